@@ -0,0 +1,375 @@
+use crate::audit::{audited, record};
+use crate::auth::{require_lesson_teacher, require_staff, require_student_reader, require_teacher};
+use crate::calendar::{get_term_record, holidays_between, Date};
+use crate::{migrations, require_max_size, student_lessons, teacher_lessons};
+use crate::{Entity, Error, IdCell, Lesson, Memory, LESSON_MAP, MAX_NAME_SIZE, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::thread::LocalKey;
+use std::{borrow::Cow, cell::RefCell};
+
+// Longest note that can be kept with a mark
+const MAX_NOTE_SIZE: usize = 4 * MAX_NAME_SIZE;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AttendanceStatus {
+    Present,
+    Absent,
+    Late,
+    Excused,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AttendanceMark {
+    student_id: u64,
+    status: AttendanceStatus,
+    note: String,
+}
+
+// The roll of one dated session of a lesson
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AttendanceSession {
+    pub(crate) id: u64,
+    pub(crate) lesson_id: u64,
+    pub(crate) schedule_id: u64,
+    pub(crate) date: Date,
+    // one mark per student, in the order they were first marked
+    pub(crate) marks: Vec<AttendanceMark>,
+}
+
+impl AttendanceSession {
+    // "lesson 3, slot 5 on 2024-09-02, 20 present, 1 late, 2 absent, 0 excused"
+    pub(crate) fn describe(&self) -> String {
+        let mut summary = AttendanceSummary::default();
+        for mark in &self.marks {
+            summary.count(mark.status);
+        }
+        format!(
+            "lesson {}, slot {} on {}, {} present, {} late, {} absent, {} excused",
+            self.lesson_id, self.schedule_id, self.date, summary.present, summary.late, summary.absent, summary.excused
+        )
+    }
+}
+
+impl Storable for AttendanceSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_attendance_session(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_attendance_session(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// (owner id, session id) pairs, the owner being a lesson or a student
+type SessionIndex = StableBTreeMap<(u64, u64), (), Memory>;
+
+thread_local! {
+    pub(crate) static ATTENDANCE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))), 0)
+            .expect("Cannot create a counter")
+    );
+    pub(crate) static ATTENDANCE_MAP: RefCell<StableBTreeMap<u64, AttendanceSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
+    // (lesson_id, session_id) of every session
+    static LESSON_ATTENDANCE_INDEX: RefCell<SessionIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))))
+    );
+    // (student_id, session_id) of every session the student is marked in
+    static STUDENT_ATTENDANCE_INDEX: RefCell<SessionIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))))
+    );
+}
+
+// Marks counted over a set of sessions. The rate is the share of present or
+// late marks among all marks that are not excused, None without such marks.
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct AttendanceSummary {
+    sessions: u64,
+    present: u64,
+    late: u64,
+    absent: u64,
+    excused: u64,
+    rate: Option<f64>,
+}
+
+impl AttendanceSummary {
+    fn count(&mut self, status: AttendanceStatus) {
+        match status {
+            AttendanceStatus::Present => self.present += 1,
+            AttendanceStatus::Late => self.late += 1,
+            AttendanceStatus::Absent => self.absent += 1,
+            AttendanceStatus::Excused => self.excused += 1,
+        }
+    }
+
+    fn finish(mut self) -> Self {
+        let attended = self.present + self.late;
+        let expected = attended + self.absent;
+        self.rate = (expected > 0).then(|| attended as f64 / expected as f64);
+        self
+    }
+}
+
+pub(crate) fn get_attendance_record(session_id: u64) -> Result<AttendanceSession, Error> {
+    let session = ATTENDANCE_MAP.with(|service| service.borrow().get(&session_id));
+    if let Some(session) = session {
+        Ok(session)
+    } else {
+        Err(Error::NotFound { entity: Entity::AttendanceSession, id: session_id })
+    }
+}
+
+fn get_lesson_record(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+// store a session and keep both indexes in step with its marks
+fn do_insert_session(previous: Option<&AttendanceSession>, session: &AttendanceSession) {
+    if let Some(previous) = previous {
+        STUDENT_ATTENDANCE_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for mark in &previous.marks {
+                index.remove(&(mark.student_id, previous.id));
+            }
+        });
+    }
+    STUDENT_ATTENDANCE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for mark in &session.marks {
+            index.insert((mark.student_id, session.id), ());
+        }
+    });
+    LESSON_ATTENDANCE_INDEX.with(|index| index.borrow_mut().insert((session.lesson_id, session.id), ()));
+    ATTENDANCE_MAP.with(|service| service.borrow_mut().insert(session.id, session.clone()));
+}
+
+fn remove_session(session: &AttendanceSession) {
+    STUDENT_ATTENDANCE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for mark in &session.marks {
+            index.remove(&(mark.student_id, session.id));
+        }
+    });
+    LESSON_ATTENDANCE_INDEX.with(|index| index.borrow_mut().remove(&(session.lesson_id, session.id)));
+    ATTENDANCE_MAP.with(|service| service.borrow_mut().remove(&session.id));
+}
+
+fn sessions_in(index: &'static LocalKey<RefCell<SessionIndex>>, id: u64) -> Vec<AttendanceSession> {
+    let session_ids: Vec<u64> = index.with(|index| {
+        index
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|((_, session_id), _)| session_id)
+            .collect()
+    });
+    ATTENDANCE_MAP.with(|service| {
+        let map = service.borrow();
+        session_ids.iter().filter_map(|session_id| map.get(session_id)).collect()
+    })
+}
+
+fn lesson_attendance(lesson_id: u64) -> Vec<AttendanceSession> {
+    sessions_in(&LESSON_ATTENDANCE_INDEX, lesson_id)
+}
+
+// drop the attendance of a deleted lesson
+pub(crate) fn remove_lesson_attendance(lesson_id: u64) {
+    for session in lesson_attendance(lesson_id) {
+        remove_session(&session);
+    }
+}
+
+// drop the marks of a deleted student; sessions stay with the other marks
+pub(crate) fn remove_student_attendance(student_id: u64) {
+    for session in sessions_in(&STUDENT_ATTENDANCE_INDEX, student_id) {
+        let mut updated = session.clone();
+        updated.marks.retain(|mark| mark.student_id != student_id);
+        do_insert_session(Some(&session), &updated);
+    }
+}
+
+// rebuild both indexes from ATTENDANCE_MAP, after a restore
+pub(crate) fn rebuild_attendance_indexes() {
+    LESSON_ATTENDANCE_INDEX.with(|index| index.borrow_mut().clear_new());
+    STUDENT_ATTENDANCE_INDEX.with(|index| index.borrow_mut().clear_new());
+    let sessions: Vec<AttendanceSession> =
+        ATTENDANCE_MAP.with(|service| service.borrow().iter().map(|(_, session)| session).collect());
+    for session in &sessions {
+        do_insert_session(None, session);
+    }
+}
+
+// the lesson must meet in the slot on `date`: the weekday matches, the date
+// is within the lesson's term and not on a holiday
+fn require_session_date(lesson: &Lesson, schedule_id: u64, date: Date) -> Result<(), Error> {
+    let invalid = |reason: String| Error::InvalidInput {
+        field: "date".to_string(),
+        reason,
+    };
+    if !date.is_valid() {
+        return Err(invalid(format!("{} is not a valid date", date)));
+    }
+    let slot = lesson.schedule.iter().find(|entry| entry.id == schedule_id).ok_or_else(|| Error::InvalidInput {
+        field: "schedule_id".to_string(),
+        reason: format!("lesson {} is not scheduled in slot {}", lesson.id, schedule_id),
+    })?;
+    if date.weekday() != slot.day {
+        return Err(invalid(format!("{} is not a {}", date, slot.day.name())));
+    }
+    if let Some(term_id) = lesson.term_id {
+        let term = get_term_record(term_id)?;
+        if date < term.start || date > term.end {
+            return Err(invalid(format!("{} is outside term {:?}", date, term.name)));
+        }
+    }
+    if let Some(holiday) = holidays_between(date, date).first() {
+        return Err(invalid(format!("{} is during holiday {:?}", date, holiday.name)));
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_attendance_session(session_id: u64) -> Result<AttendanceSession, Error> {
+    let session = get_attendance_record(session_id)?;
+    require_lesson_teacher(session.lesson_id, "read the attendance of this lesson")?;
+    Ok(session)
+}
+
+// sessions of a lesson by date
+#[ic_cdk::query]
+fn get_lesson_attendance(lesson_id: u64) -> Result<Vec<AttendanceSession>, Error> {
+    get_lesson_record(lesson_id)?;
+    require_lesson_teacher(lesson_id, "read the attendance of this lesson")?;
+    let mut sessions = lesson_attendance(lesson_id);
+    sessions.sort_by_key(|session| (session.date, session.id));
+    Ok(sessions)
+}
+
+// Start the roll for the session of a lesson in `schedule_id` on `date`.
+// Each session can only be opened once.
+#[ic_cdk::update]
+fn open_attendance_session(lesson_id: u64, schedule_id: u64, date: Date) -> Result<AttendanceSession, Error> {
+    audited("open_attendance_session", &[record(Entity::Lesson, lesson_id)], || {
+        let lesson = get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "take attendance for this lesson")?;
+        require_session_date(&lesson, schedule_id, date)?;
+        if let Some(open) = lesson_attendance(lesson_id)
+            .iter()
+            .find(|session| session.schedule_id == schedule_id && session.date == date)
+        {
+            return Err(Error::Conflict {
+                reason: format!("attendance for this session is already open with id={}", open.id),
+            });
+        }
+
+        let id = ATTENDANCE_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment id counter");
+        let session = AttendanceSession {
+            id,
+            lesson_id,
+            schedule_id,
+            date,
+            marks: Vec::new(),
+        };
+        do_insert_session(None, &session);
+        Ok(session)
+    })
+}
+
+// Mark students enrolled in the lesson, replacing earlier marks of the same
+// students
+#[ic_cdk::update]
+fn mark_attendance(session_id: u64, marks: Vec<AttendanceMark>) -> Result<AttendanceSession, Error> {
+    audited("mark_attendance", &[record(Entity::AttendanceSession, session_id)], || {
+        let previous = get_attendance_record(session_id)?;
+        require_lesson_teacher(previous.lesson_id, "take attendance for this lesson")?;
+        let lesson = get_lesson_record(previous.lesson_id)?;
+        let mut session = previous.clone();
+        for mark in marks {
+            if !lesson.students.contains(&mark.student_id) {
+                return Err(Error::InvalidInput {
+                    field: "student_id".to_string(),
+                    reason: format!("student {} is not enrolled in lesson {}", mark.student_id, lesson.id),
+                });
+            }
+            require_max_size("note", &mark.note, MAX_NOTE_SIZE)?;
+            match session.marks.iter_mut().find(|other| other.student_id == mark.student_id) {
+                Some(other) => *other = mark,
+                None => session.marks.push(mark),
+            }
+        }
+        do_insert_session(Some(&previous), &session);
+        Ok(session)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_attendance_session(session_id: u64) -> Result<AttendanceSession, Error> {
+    audited("delete_attendance_session", &[record(Entity::AttendanceSession, session_id)], || {
+        let session = get_attendance_record(session_id)?;
+        require_lesson_teacher(session.lesson_id, "take attendance for this lesson")?;
+        remove_session(&session);
+        Ok(session)
+    })
+}
+
+// attendance over every session of the lesson
+#[ic_cdk::query]
+fn get_lesson_attendance_rate(lesson_id: u64) -> Result<AttendanceSummary, Error> {
+    get_lesson_record(lesson_id)?;
+    require_lesson_teacher(lesson_id, "read the attendance of this lesson")?;
+    let mut summary = AttendanceSummary::default();
+    for session in lesson_attendance(lesson_id) {
+        summary.sessions += 1;
+        for mark in &session.marks {
+            summary.count(mark.status);
+        }
+    }
+    Ok(summary.finish())
+}
+
+// attendance of a student over every session they were marked in
+#[ic_cdk::query]
+fn get_student_attendance_rate(student_id: u64) -> Result<AttendanceSummary, Error> {
+    student_lessons(student_id)?;
+    require_staff("read the attendance of students").or_else(|_| require_student_reader(student_id, "read this attendance"))?;
+    let mut summary = AttendanceSummary::default();
+    for session in sessions_in(&STUDENT_ATTENDANCE_INDEX, student_id) {
+        summary.sessions += 1;
+        for mark in session.marks.iter().filter(|mark| mark.student_id == student_id) {
+            summary.count(mark.status);
+        }
+    }
+    Ok(summary.finish())
+}
+
+// attendance over every session of the teacher's lessons
+#[ic_cdk::query]
+fn get_teacher_attendance_rate(teacher_id: u64) -> Result<AttendanceSummary, Error> {
+    let (_, lessons) = teacher_lessons(teacher_id)?;
+    require_teacher(teacher_id, "read the attendance of this teacher")?;
+    let mut summary = AttendanceSummary::default();
+    for lesson in &lessons {
+        for session in lesson_attendance(lesson.id) {
+            summary.sessions += 1;
+            for mark in &session.marks {
+                summary.count(mark.status);
+            }
+        }
+    }
+    Ok(summary.finish())
+}
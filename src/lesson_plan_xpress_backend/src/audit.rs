@@ -0,0 +1,484 @@
+use crate::attendance::{get_attendance_record, AttendanceSession};
+use crate::auth::{require_admin, role_of, Role};
+use crate::backup;
+use crate::calendar::{Holiday, HOLIDAY_MAP, TERM_MAP};
+use crate::eligibility::{Completions, Eligibility};
+use crate::gradebook::{get_assignment_record, Assignment, GradeWeights};
+use crate::import::{describe_upload, ImportReport};
+use crate::pagination::{page_size, Page};
+use crate::rooms::{get_room_record, LessonRooms, Room};
+use crate::schedule::{school_settings, SchoolSettings};
+use crate::templates::{LessonTemplate, TEMPLATE_MAP};
+use crate::timetable::TimetableProgress;
+use crate::{calendar, Entity, Error, Lesson, Memory, ScheduleEntry, Student, Teacher, MEMORY_MANAGER};
+use crate::{LESSON_MAP, SCHEDULE_ENTRY_MAP, STUDENT_MAP, TEACHER_MAP};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Summaries longer than this many bytes are cut off
+const MAX_SUMMARY_SIZE: usize = 1024;
+
+// Entries a single list_audit_log call looks at, so that a filter matching
+// few entries cannot exceed the instruction limit of a query
+const MAX_SCANNED_ENTRIES: usize = 5000;
+
+// Oldest entries removed per logged call while the log is over its limits
+const MAX_TRIMMED_ENTRIES: usize = 100;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+// What a logged call changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AuditTarget {
+    Record { entity: Entity, id: u64 },
+    Role(Principal),
+    SchoolSettings,
+}
+
+// The target as it was before and after the call, None where it did not exist
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditChange {
+    target: AuditTarget,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+// One successful call of an update endpoint
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    id: u64,
+    caller: Principal,
+    // nanoseconds since the epoch
+    timestamp: u64,
+    method: String,
+    changes: Vec<AuditChange>,
+    // outcome of calls that change many records at once
+    note: Option<String>,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// How much of the log is kept; older entries are removed first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditRetention {
+    max_entries: u64,
+    // entries older than this are removed, kept regardless of age when None
+    max_age_days: Option<u32>,
+}
+
+impl Default for AuditRetention {
+    fn default() -> Self {
+        AuditRetention {
+            max_entries: 100_000,
+            max_age_days: None,
+        }
+    }
+}
+
+impl Storable for AuditRetention {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // append-only, keyed by entry id in call order
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+    static AUDIT_RETENTION: RefCell<Cell<AuditRetention, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))), AuditRetention::default())
+            .expect("Cannot create audit retention")
+    );
+}
+
+// Filters of list_audit_log; entries must match all that are given
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AuditQuery {
+    caller: Option<Principal>,
+    method: Option<String>,
+    // entries that changed a record of this kind, and this id when given
+    entity: Option<Entity>,
+    id: Option<u64>,
+    // timestamps in nanoseconds, both inclusive
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+// What the value an endpoint returns adds to its log entry
+pub(crate) trait Audited {
+    // a record the call created
+    fn created(&self) -> Option<AuditTarget> {
+        None
+    }
+
+    fn note(&self) -> Option<String> {
+        None
+    }
+}
+
+fn record_target(entity: Entity, id: u64) -> Option<AuditTarget> {
+    Some(AuditTarget::Record { entity, id })
+}
+
+impl Audited for Lesson {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Lesson, self.id)
+    }
+}
+
+impl Audited for Teacher {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Teacher, self.id)
+    }
+}
+
+impl Audited for Student {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Student, self.id)
+    }
+}
+
+impl Audited for ScheduleEntry {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::ScheduleEntry, self.id)
+    }
+}
+
+impl Audited for calendar::Term {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Term, self.id)
+    }
+}
+
+impl Audited for Holiday {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Holiday, self.id)
+    }
+}
+
+impl Audited for LessonTemplate {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Template, self.id)
+    }
+}
+
+impl Audited for AttendanceSession {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::AttendanceSession, self.id)
+    }
+}
+
+impl Audited for Assignment {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Assignment, self.id)
+    }
+}
+
+impl Audited for GradeWeights {
+    fn note(&self) -> Option<String> {
+        Some(self.describe())
+    }
+}
+
+impl Audited for Room {
+    fn created(&self) -> Option<AuditTarget> {
+        record_target(Entity::Room, self.id)
+    }
+}
+
+impl Audited for LessonRooms {
+    fn note(&self) -> Option<String> {
+        Some(self.describe())
+    }
+}
+
+impl Audited for Eligibility {
+    fn note(&self) -> Option<String> {
+        Some(self.describe())
+    }
+}
+
+impl Audited for Completions {
+    fn note(&self) -> Option<String> {
+        Some(self.describe())
+    }
+}
+
+impl Audited for TimetableProgress {
+    fn note(&self) -> Option<String> {
+        Some(self.describe())
+    }
+}
+
+impl Audited for ImportReport {
+    fn note(&self) -> Option<String> {
+        Some(self.describe())
+    }
+}
+
+impl Audited for () {}
+impl Audited for u64 {}
+impl Audited for Role {}
+impl Audited for Option<Role> {}
+impl Audited for SchoolSettings {}
+impl Audited for crate::enrollment::Enrollment {}
+
+fn ids(ids: &[u64]) -> String {
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    format!("[{}]", ids.join(", "))
+}
+
+fn describe_role(role: &Role) -> String {
+    match role {
+        Role::Admin => "admin".to_string(),
+        Role::Teacher { teacher_id } => format!("teacher {}", teacher_id),
+        Role::Student { student_id } => format!("student {}", student_id),
+        Role::Guardian { student_ids } => format!("guardian of students {}", ids(student_ids)),
+    }
+}
+
+fn describe_record(entity: Entity, id: u64) -> Option<String> {
+    match entity {
+        Entity::Lesson => LESSON_MAP.with(|service| service.borrow().get(&id)).map(|lesson| {
+            let schedule: Vec<u64> = lesson.schedule.iter().map(|slot| slot.id).collect();
+            format!(
+                "{:?} ({}, grade {}), teacher {}, students {}, schedule {}, term {}",
+                lesson.title,
+                lesson.subject,
+                lesson.grade_level,
+                lesson.teacher_id,
+                ids(&lesson.students),
+                ids(&schedule),
+                lesson.term_id.map_or("none".to_string(), |term_id| term_id.to_string())
+            )
+        }),
+        Entity::Teacher => TEACHER_MAP.with(|service| service.borrow().get(&id)).map(|teacher| {
+            let availability: Vec<u64> = teacher.availability.iter().map(|slot| slot.id).collect();
+            format!(
+                "{:?} ({}), lessons {}, availability {}",
+                teacher.name,
+                teacher.subject,
+                ids(&teacher.lessons),
+                ids(&availability)
+            )
+        }),
+        Entity::Student => STUDENT_MAP.with(|service| service.borrow().get(&id)).map(|student| {
+            format!("{:?} (grade {}), lessons {}", student.name, student.grade_level, ids(&student.lessons))
+        }),
+        Entity::ScheduleEntry => SCHEDULE_ENTRY_MAP
+            .with(|service| service.borrow().get(&id))
+            .map(|entry| entry.describe()),
+        Entity::Term => TERM_MAP
+            .with(|service| service.borrow().get(&id))
+            .map(|term| format!("{:?} {} to {}", term.name, term.start, term.end)),
+        Entity::Holiday => HOLIDAY_MAP
+            .with(|service| service.borrow().get(&id))
+            .map(|holiday| format!("{:?} {} to {}", holiday.name, holiday.start, holiday.end)),
+        Entity::Template => TEMPLATE_MAP
+            .with(|service| service.borrow().get(&id))
+            .map(|template| template.describe()),
+        Entity::Upload => describe_upload(id),
+        Entity::Assignment => get_assignment_record(id).ok().map(|assignment| assignment.describe()),
+        Entity::Room => get_room_record(id).ok().map(|room| room.describe()),
+        Entity::AttendanceSession => get_attendance_record(id).ok().map(|session| session.describe()),
+        Entity::LessonRevision => None,
+    }
+}
+
+fn describe(target: &AuditTarget) -> Option<String> {
+    let mut summary = match target {
+        AuditTarget::Record { entity, id } => describe_record(*entity, *id),
+        AuditTarget::Role(principal) => role_of(*principal).map(|role| describe_role(&role)),
+        AuditTarget::SchoolSettings => {
+            let settings = school_settings();
+            Some(format!("{} (UTC offset {} minutes)", settings.time_zone, settings.utc_offset_minutes))
+        }
+    }?;
+    if summary.len() > MAX_SUMMARY_SIZE {
+        let mut end = MAX_SUMMARY_SIZE;
+        while !summary.is_char_boundary(end) {
+            end -= 1;
+        }
+        summary.truncate(end);
+        summary.push('…');
+    }
+    Some(summary)
+}
+
+fn retention() -> AuditRetention {
+    AUDIT_RETENTION.with(|retention| retention.borrow().get().clone())
+}
+
+// remove the oldest entries that are over the retention limits
+fn trim(map: &mut StableBTreeMap<u64, AuditEntry, Memory>, now: u64) {
+    let retention = retention();
+    let cutoff = retention
+        .max_age_days
+        .map(|days| now.saturating_sub(days as u64 * NANOS_PER_DAY));
+    for _ in 0..MAX_TRIMMED_ENTRIES {
+        let expired = match map.first_key_value() {
+            Some((_, entry)) => {
+                map.len() > retention.max_entries || cutoff.is_some_and(|cutoff| entry.timestamp < cutoff)
+            }
+            None => false,
+        };
+        if !expired {
+            break;
+        }
+        map.pop_first();
+    }
+}
+
+// Run the body of the update endpoint `method` and log it when it succeeds,
+// with each of `targets` summarized before and after the call. A record the
+// call creates is added to the targets.
+pub(crate) fn audited<T: Audited>(
+    method: &str,
+    targets: &[AuditTarget],
+    body: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    backup::require_no_restore(method)?;
+    let before: Vec<Option<String>> = targets.iter().map(describe).collect();
+    let value = body()?;
+
+    let mut changes: Vec<AuditChange> = targets
+        .iter()
+        .zip(before)
+        .map(|(target, before)| AuditChange {
+            target: target.clone(),
+            before,
+            after: describe(target),
+        })
+        .collect();
+    if let Some(target) = value.created().filter(|created| !targets.contains(created)) {
+        changes.push(AuditChange {
+            after: describe(&target),
+            target,
+            before: None,
+        });
+    }
+    let timestamp = ic_cdk::api::time();
+    AUDIT_LOG.with(|service| {
+        let mut map = service.borrow_mut();
+        let id = map.last_key_value().map_or(0, |(id, _)| id + 1);
+        map.insert(
+            id,
+            AuditEntry {
+                id,
+                caller: ic_cdk::caller(),
+                timestamp,
+                method: method.to_string(),
+                changes,
+                note: value.note(),
+            },
+        );
+        trim(&mut map, timestamp);
+    });
+    Ok(value)
+}
+
+// shorthand for the target of a stored record
+pub(crate) fn record(entity: Entity, id: u64) -> AuditTarget {
+    AuditTarget::Record { entity, id }
+}
+
+fn matches(query: &AuditQuery, entry: &AuditEntry) -> bool {
+    query.caller.is_none_or(|caller| caller == entry.caller)
+        && query.method.as_ref().is_none_or(|method| *method == entry.method)
+        && query.since.is_none_or(|since| entry.timestamp >= since)
+        && query.until.is_none_or(|until| entry.timestamp <= until)
+        && (query.entity.is_none() && query.id.is_none()
+            || entry.changes.iter().any(|change| match change.target {
+                AuditTarget::Record { entity, id } => {
+                    query.entity.is_none_or(|wanted| wanted == entity) && query.id.is_none_or(|wanted| wanted == id)
+                }
+                _ => false,
+            }))
+}
+
+// Entries matching `query`, oldest first. A page can hold fewer than `limit`
+// entries when many did not match; keep following next_cursor until it is
+// None. `total` counts all entries kept in the log.
+#[ic_cdk::query]
+fn list_audit_log(query: AuditQuery, start_after: Option<u64>, limit: u32) -> Result<Page<AuditEntry>, Error> {
+    require_admin("read the audit log")?;
+    let limit = page_size(limit)?;
+    let from = match start_after {
+        Some(id) => id.saturating_add(1),
+        None => 0,
+    };
+    AUDIT_LOG.with(|service| {
+        let map = service.borrow();
+        let mut items = Vec::new();
+        let mut next_cursor = None;
+        for (scanned, (id, entry)) in map.range(from..).enumerate() {
+            if items.len() == limit || scanned == MAX_SCANNED_ENTRIES {
+                break;
+            }
+            next_cursor = Some(id);
+            if matches(&query, &entry) {
+                items.push(entry);
+            }
+        }
+        // no cursor when the scan reached the end of the log
+        if next_cursor.is_some_and(|id| map.range(id + 1..).next().is_none()) {
+            next_cursor = None;
+        }
+        Ok(Page {
+            items,
+            next_cursor,
+            total: map.len(),
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_audit_retention() -> Result<AuditRetention, Error> {
+    require_admin("read the audit log")?;
+    Ok(retention())
+}
+
+// Entries over the new limits are removed gradually as calls are logged
+#[ic_cdk::update]
+fn set_audit_retention(retention: AuditRetention) -> Result<AuditRetention, Error> {
+    audited("set_audit_retention", &[], || {
+        require_admin("change the audit log retention")?;
+        if retention.max_entries == 0 {
+            return Err(Error::InvalidInput {
+                field: "max_entries".to_string(),
+                reason: "must be greater than 0".to_string(),
+            });
+        }
+        if retention.max_age_days == Some(0) {
+            return Err(Error::InvalidInput {
+                field: "max_age_days".to_string(),
+                reason: "must be greater than 0".to_string(),
+            });
+        }
+        AUDIT_RETENTION
+            .with(|cell| cell.borrow_mut().set(retention.clone()))
+            .expect("cannot store audit retention");
+        Ok(())
+    })?;
+    Ok(retention)
+}
@@ -0,0 +1,179 @@
+use crate::audit::{audited, AuditTarget};
+use crate::links::{require_student_exists, require_teacher_exists};
+use crate::{Error, Memory, LESSON_MAP, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Role granted to a principal. Teacher, student and guardian roles are tied
+// to the records they are allowed to act on.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum Role {
+    Admin,
+    Teacher { teacher_id: u64 },
+    Student { student_id: u64 },
+    Guardian { student_ids: Vec<u64> },
+}
+
+impl Storable for Role {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    // a guardian may be linked to any number of students
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Principal wrapper so it can be used as a stable map key.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct StorablePrincipal(pub(crate) Principal);
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+
+    // Principals are at most 29 bytes long.
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
+}
+
+thread_local! {
+    static ROLE_MAP: RefCell<StableBTreeMap<StorablePrincipal, Role, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))))
+    );
+}
+
+// Role of the current caller. Canister controllers are always admins so the
+// school can bootstrap the first role assignments.
+pub(crate) fn caller_role() -> Option<Role> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return None;
+    }
+    if ic_cdk::api::is_controller(&caller) {
+        return Some(Role::Admin);
+    }
+    role_of(caller)
+}
+
+pub(crate) fn role_of(principal: Principal) -> Option<Role> {
+    ROLE_MAP.with(|service| service.borrow().get(&StorablePrincipal(principal)))
+}
+
+fn unauthorized(action: &str) -> Error {
+    Error::Unauthorized {
+        action: action.to_string(),
+    }
+}
+
+// Only admins may perform the action.
+pub(crate) fn require_admin(action: &str) -> Result<(), Error> {
+    match caller_role() {
+        Some(Role::Admin) => Ok(()),
+        _ => Err(unauthorized(action)),
+    }
+}
+
+// Admins, or the teacher identified by `teacher_id`.
+pub(crate) fn require_teacher(teacher_id: u64, action: &str) -> Result<(), Error> {
+    match caller_role() {
+        Some(Role::Admin) => Ok(()),
+        Some(Role::Teacher { teacher_id: own_id }) if own_id == teacher_id => Ok(()),
+        _ => Err(unauthorized(action)),
+    }
+}
+
+// Admins, or the teacher who owns the lesson identified by `lesson_id`.
+pub(crate) fn require_lesson_teacher(lesson_id: u64, action: &str) -> Result<(), Error> {
+    match LESSON_MAP.with(|service| service.borrow().get(&lesson_id)) {
+        Some(lesson) => require_teacher(lesson.teacher_id, action),
+        None => require_admin(action),
+    }
+}
+
+// Admins, or any teacher.
+pub(crate) fn require_staff(action: &str) -> Result<(), Error> {
+    match caller_role() {
+        Some(Role::Admin) | Some(Role::Teacher { .. }) => Ok(()),
+        _ => Err(unauthorized(action)),
+    }
+}
+
+// Admins, or the student identified by `student_id`.
+pub(crate) fn require_student(student_id: u64, action: &str) -> Result<(), Error> {
+    match caller_role() {
+        Some(Role::Admin) => Ok(()),
+        Some(Role::Student { student_id: own_id }) if own_id == student_id => Ok(()),
+        _ => Err(unauthorized(action)),
+    }
+}
+
+// Admins, the student identified by `student_id`, or one of their
+// guardians. Guardians may only read, so use this for queries.
+pub(crate) fn require_student_reader(student_id: u64, action: &str) -> Result<(), Error> {
+    match caller_role() {
+        Some(Role::Guardian { student_ids }) if student_ids.contains(&student_id) => Ok(()),
+        _ => require_student(student_id, action),
+    }
+}
+
+// make sure the records a role points at exist
+fn validate_role(role: &Role) -> Result<(), Error> {
+    match role {
+        Role::Admin => Ok(()),
+        Role::Teacher { teacher_id } => require_teacher_exists(*teacher_id),
+        Role::Student { student_id } => require_student_exists(*student_id),
+        Role::Guardian { student_ids } => student_ids
+            .iter()
+            .try_for_each(|student_id| require_student_exists(*student_id)),
+    }
+}
+
+// assign a role to a principal, replacing any previous role
+#[ic_cdk::update]
+fn assign_role(principal: Principal, role: Role) -> Result<Role, Error> {
+    audited("assign_role", &[AuditTarget::Role(principal)], || {
+        require_admin("assign roles")?;
+        validate_role(&role)?;
+        ROLE_MAP.with(|service| {
+            service
+                .borrow_mut()
+                .insert(StorablePrincipal(principal), role.clone())
+        });
+        Ok(role)
+    })
+}
+
+// revoke the role of a principal, returning the role it had
+#[ic_cdk::update]
+fn revoke_role(principal: Principal) -> Result<Option<Role>, Error> {
+    audited("revoke_role", &[AuditTarget::Role(principal)], || {
+        require_admin("revoke roles")?;
+        Ok(ROLE_MAP.with(|service| service.borrow_mut().remove(&StorablePrincipal(principal))))
+    })
+}
+
+#[ic_cdk::query]
+fn get_role(principal: Principal) -> Result<Option<Role>, Error> {
+    require_admin("read roles")?;
+    Ok(ROLE_MAP.with(|service| service.borrow().get(&StorablePrincipal(principal))))
+}
+
+// role of the caller, so the frontend can decide what to show
+#[ic_cdk::query]
+fn get_my_role() -> Option<Role> {
+    caller_role()
+}
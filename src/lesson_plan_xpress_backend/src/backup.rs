@@ -0,0 +1,445 @@
+use crate::audit::audited;
+use crate::attendance::{rebuild_attendance_indexes, AttendanceSession, ATTENDANCE_ID_COUNTER, ATTENDANCE_MAP};
+use crate::auth::require_admin;
+use crate::eligibility::{Completions, Eligibility, COMPLETION_MAP, ELIGIBILITY_MAP};
+use crate::gradebook::{rebuild_assignment_index, Assignment, GradeWeights};
+use crate::gradebook::{ASSIGNMENT_ID_COUNTER, ASSIGNMENT_MAP, GRADE_WEIGHTS_MAP};
+use crate::calendar::{Holiday, Term, HOLIDAY_ID_COUNTER, HOLIDAY_MAP, TERM_ID_COUNTER, TERM_MAP};
+use crate::rooms::{LessonRooms, Room, LESSON_ROOMS_MAP, ROOM_ID_COUNTER, ROOM_MAP};
+use crate::schedule::{SchoolSettings, SCHOOL_SETTINGS};
+use crate::templates::{LessonTemplate, TEMPLATE_ID_COUNTER, TEMPLATE_MAP};
+use crate::{revisions, search, Error, IdCell, Lesson, Memory, ScheduleEntry, Student, Teacher};
+use crate::{LESSON_ID_COUNTER, LESSON_MAP, SCHEDULE_ENTRY_MAP, SCHEDULE_ID_COUNTER, STUDENT_ID_COUNTER};
+use crate::{STUDENT_MAP, TEACHER_ID_COUNTER, TEACHER_MAP};
+use crate::MEMORY_MANAGER;
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+// Layout of the snapshot records. Bump it when SnapshotRecord changes in a
+// way older snapshots cannot be decoded into.
+//   2: counters of attendance sessions, assignments and rooms
+const SNAPSHOT_VERSION: u32 = 2;
+
+// Encoded records per chunk stop after this many bytes, well below the
+// size limit of a reply
+const MAX_CHUNK_BYTES: usize = 1024 * 1024;
+
+// A restore between start_restore and finish_restore. Kept in stable
+// memory, since start_restore has already removed the data when an upgrade
+// happens before the restore is finished.
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct RestoreState {
+    in_progress: bool,
+    // chunks stored so far
+    chunks: u64,
+}
+
+impl Storable for RestoreState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static RESTORE_STATE: RefCell<Cell<RestoreState, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))), RestoreState::default())
+            .expect("Cannot create the restore state")
+    );
+}
+
+fn set_restore_state(state: RestoreState) {
+    RESTORE_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("cannot store the restore state");
+}
+
+// Chunks stored by the restore in progress, None when there is none
+pub(crate) fn restore_progress() -> Option<u64> {
+    let state = RESTORE_STATE.with(|cell| cell.borrow().get().clone());
+    state.in_progress.then_some(state.chunks)
+}
+
+// Next id of each counter
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Counters {
+    student: u64,
+    teacher: u64,
+    lesson: u64,
+    schedule_entry: u64,
+    template: u64,
+    term: u64,
+    holiday: u64,
+    attendance_session: u64,
+    assignment: u64,
+    room: u64,
+}
+
+// One stored value. Revisions, roles, uploads and the audit log are not
+// part of a snapshot; a restore starts each lesson's history afresh.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum SnapshotRecord {
+    Counters(Counters),
+    Settings(SchoolSettings),
+    Teacher(Teacher),
+    Student(Student),
+    ScheduleEntry(ScheduleEntry),
+    Term(Term),
+    Holiday(Holiday),
+    Template(LessonTemplate),
+    Lesson(Lesson),
+    AttendanceSession(AttendanceSession),
+    Assignment(Assignment),
+    GradeWeights(GradeWeights),
+    Room(Room),
+    LessonRooms(LessonRooms),
+    Eligibility(Eligibility),
+    Completions(Completions),
+}
+
+// Parts of a snapshot in the order they are exported
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub(crate) enum Section {
+    Settings,
+    Teachers,
+    Students,
+    ScheduleEntries,
+    Terms,
+    Holidays,
+    Templates,
+    Lessons,
+    Attendance,
+    Assignments,
+    GradeWeights,
+    Rooms,
+    LessonRooms,
+    Eligibility,
+    Completions,
+}
+
+impl Section {
+    const ALL: [Section; 15] = [
+        Section::Settings,
+        Section::Teachers,
+        Section::Students,
+        Section::ScheduleEntries,
+        Section::Terms,
+        Section::Holidays,
+        Section::Templates,
+        Section::Lessons,
+        Section::Attendance,
+        Section::Assignments,
+        Section::GradeWeights,
+        Section::Rooms,
+        Section::LessonRooms,
+        Section::Eligibility,
+        Section::Completions,
+    ];
+}
+
+// Where the next chunk of an export starts
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct ExportCursor {
+    section: Section,
+    start_after: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SnapshotChunk {
+    version: u32,
+    records: Vec<SnapshotRecord>,
+    // None on the last chunk
+    next_cursor: Option<ExportCursor>,
+}
+
+fn counter_value(counter: &'static LocalKey<RefCell<IdCell>>) -> u64 {
+    counter.with(|counter| *counter.borrow().get())
+}
+
+fn set_counter(counter: &'static LocalKey<RefCell<IdCell>>, value: u64) {
+    counter
+        .with(|counter| counter.borrow_mut().set(value))
+        .expect("cannot set id counter");
+}
+
+// Append records of `map` after `start_after` until the chunk is full.
+// Returns the last key added when the chunk filled up first.
+fn export_map<V: Storable>(
+    map: &StableBTreeMap<u64, V, Memory>,
+    start_after: Option<u64>,
+    record: fn(V) -> SnapshotRecord,
+    records: &mut Vec<SnapshotRecord>,
+    size: &mut usize,
+) -> Option<u64> {
+    let from = match start_after {
+        Some(id) => id.checked_add(1)?,
+        None => 0,
+    };
+    for (id, value) in map.range(from..) {
+        let value = record(value);
+        *size += candid::encode_one(&value).map(|bytes| bytes.len()).unwrap_or_default();
+        records.push(value);
+        if *size >= MAX_CHUNK_BYTES {
+            return Some(id);
+        }
+    }
+    None
+}
+
+fn export_section(section: Section, start_after: Option<u64>, records: &mut Vec<SnapshotRecord>, size: &mut usize) -> Option<u64> {
+    match section {
+        Section::Settings => {
+            records.push(SnapshotRecord::Counters(Counters {
+                student: counter_value(&STUDENT_ID_COUNTER),
+                teacher: counter_value(&TEACHER_ID_COUNTER),
+                lesson: counter_value(&LESSON_ID_COUNTER),
+                schedule_entry: counter_value(&SCHEDULE_ID_COUNTER),
+                template: counter_value(&TEMPLATE_ID_COUNTER),
+                term: counter_value(&TERM_ID_COUNTER),
+                holiday: counter_value(&HOLIDAY_ID_COUNTER),
+                attendance_session: counter_value(&ATTENDANCE_ID_COUNTER),
+                assignment: counter_value(&ASSIGNMENT_ID_COUNTER),
+                room: counter_value(&ROOM_ID_COUNTER),
+            }));
+            let settings = SCHOOL_SETTINGS.with(|settings| settings.borrow().get().clone());
+            records.push(SnapshotRecord::Settings(settings));
+            None
+        }
+        Section::Teachers => TEACHER_MAP.with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Teacher, records, size)),
+        Section::Students => STUDENT_MAP.with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Student, records, size)),
+        Section::ScheduleEntries => SCHEDULE_ENTRY_MAP
+            .with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::ScheduleEntry, records, size)),
+        Section::Terms => TERM_MAP.with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Term, records, size)),
+        Section::Holidays => HOLIDAY_MAP.with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Holiday, records, size)),
+        Section::Templates => TEMPLATE_MAP.with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Template, records, size)),
+        Section::Lessons => LESSON_MAP.with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Lesson, records, size)),
+        Section::Attendance => ATTENDANCE_MAP
+            .with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::AttendanceSession, records, size)),
+        Section::Assignments => ASSIGNMENT_MAP
+            .with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Assignment, records, size)),
+        Section::GradeWeights => GRADE_WEIGHTS_MAP
+            .with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::GradeWeights, records, size)),
+        Section::Rooms => ROOM_MAP.with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Room, records, size)),
+        Section::LessonRooms => LESSON_ROOMS_MAP
+            .with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::LessonRooms, records, size)),
+        Section::Eligibility => ELIGIBILITY_MAP
+            .with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Eligibility, records, size)),
+        Section::Completions => COMPLETION_MAP
+            .with(|map| export_map(&map.borrow(), start_after, SnapshotRecord::Completions, records, size)),
+    }
+}
+
+// Read the data of the canister as a versioned snapshot, one chunk per call.
+// Start without a cursor and pass each `next_cursor` back until it is None.
+#[ic_cdk::query]
+fn export_snapshot(cursor: Option<ExportCursor>) -> Result<SnapshotChunk, Error> {
+    require_admin("export data")?;
+    let cursor = cursor.unwrap_or(ExportCursor { section: Section::Settings, start_after: None });
+    let mut records = Vec::new();
+    let mut size = 0;
+    for section in Section::ALL.into_iter().filter(|section| *section >= cursor.section) {
+        let start_after = if section == cursor.section { cursor.start_after } else { None };
+        if let Some(last_id) = export_section(section, start_after, &mut records, &mut size) {
+            return Ok(SnapshotChunk {
+                version: SNAPSHOT_VERSION,
+                records,
+                next_cursor: Some(ExportCursor { section, start_after: Some(last_id) }),
+            });
+        }
+    }
+    Ok(SnapshotChunk { version: SNAPSHOT_VERSION, records, next_cursor: None })
+}
+
+fn require_restore_in_progress() -> Result<(), Error> {
+    if restore_progress().is_some() {
+        Ok(())
+    } else {
+        Err(Error::Conflict {
+            reason: "no restore in progress, call start_restore first".to_string(),
+        })
+    }
+}
+
+// Calls that restore a snapshot, the only changes accepted while one runs
+const RESTORE_METHODS: [&str; 3] = ["start_restore", "restore_snapshot_chunk", "finish_restore"];
+
+// Refuse other changes between start_restore and finish_restore, so they
+// are not mixed into the restored data. Checked by audited for every
+// update call.
+pub(crate) fn require_no_restore(method: &str) -> Result<(), Error> {
+    if restore_progress().is_some() && !RESTORE_METHODS.contains(&method) {
+        Err(Error::Conflict {
+            reason: "a restore is in progress, changes are accepted again after finish_restore".to_string(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// Remove all data a snapshot holds, along with the lesson revisions and
+// indexes derived from it, and accept restore_snapshot_chunk calls.
+#[ic_cdk::update]
+fn start_restore() -> Result<(), Error> {
+    audited("start_restore", &[], || {
+        require_admin("restore data")?;
+        TEACHER_MAP.with(|map| map.borrow_mut().clear_new());
+        STUDENT_MAP.with(|map| map.borrow_mut().clear_new());
+        SCHEDULE_ENTRY_MAP.with(|map| map.borrow_mut().clear_new());
+        TERM_MAP.with(|map| map.borrow_mut().clear_new());
+        HOLIDAY_MAP.with(|map| map.borrow_mut().clear_new());
+        TEMPLATE_MAP.with(|map| map.borrow_mut().clear_new());
+        LESSON_MAP.with(|map| map.borrow_mut().clear_new());
+        ATTENDANCE_MAP.with(|map| map.borrow_mut().clear_new());
+        ASSIGNMENT_MAP.with(|map| map.borrow_mut().clear_new());
+        GRADE_WEIGHTS_MAP.with(|map| map.borrow_mut().clear_new());
+        ROOM_MAP.with(|map| map.borrow_mut().clear_new());
+        LESSON_ROOMS_MAP.with(|map| map.borrow_mut().clear_new());
+        ELIGIBILITY_MAP.with(|map| map.borrow_mut().clear_new());
+        COMPLETION_MAP.with(|map| map.borrow_mut().clear_new());
+        revisions::clear_revisions();
+        search::rebuild_lesson_indexes();
+        rebuild_attendance_indexes();
+        rebuild_assignment_index();
+        set_restore_state(RestoreState { in_progress: true, chunks: 0 });
+        Ok(())
+    })
+}
+
+// Store the records of one exported chunk, returns how many were stored
+#[ic_cdk::update]
+fn restore_snapshot_chunk(chunk: SnapshotChunk) -> Result<u64, Error> {
+    audited("restore_snapshot_chunk", &[], || {
+        require_admin("restore data")?;
+        require_restore_in_progress()?;
+        if chunk.version != SNAPSHOT_VERSION {
+            return Err(Error::InvalidInput {
+                field: "version".to_string(),
+                reason: format!("snapshot version {} is not supported, expected {}", chunk.version, SNAPSHOT_VERSION),
+            });
+        }
+        let count = chunk.records.len() as u64;
+        for record in chunk.records {
+            match record {
+                SnapshotRecord::Counters(counters) => {
+                    set_counter(&STUDENT_ID_COUNTER, counters.student);
+                    set_counter(&TEACHER_ID_COUNTER, counters.teacher);
+                    set_counter(&LESSON_ID_COUNTER, counters.lesson);
+                    set_counter(&SCHEDULE_ID_COUNTER, counters.schedule_entry);
+                    set_counter(&TEMPLATE_ID_COUNTER, counters.template);
+                    set_counter(&TERM_ID_COUNTER, counters.term);
+                    set_counter(&HOLIDAY_ID_COUNTER, counters.holiday);
+                    set_counter(&ATTENDANCE_ID_COUNTER, counters.attendance_session);
+                    set_counter(&ASSIGNMENT_ID_COUNTER, counters.assignment);
+                    set_counter(&ROOM_ID_COUNTER, counters.room);
+                }
+                SnapshotRecord::Settings(settings) => {
+                    SCHOOL_SETTINGS
+                        .with(|cell| cell.borrow_mut().set(settings))
+                        .expect("cannot store school settings");
+                }
+                SnapshotRecord::Teacher(teacher) => {
+                    TEACHER_MAP.with(|map| map.borrow_mut().insert(teacher.id, teacher));
+                }
+                SnapshotRecord::Student(student) => {
+                    STUDENT_MAP.with(|map| map.borrow_mut().insert(student.id, student));
+                }
+                SnapshotRecord::ScheduleEntry(entry) => {
+                    SCHEDULE_ENTRY_MAP.with(|map| map.borrow_mut().insert(entry.id, entry));
+                }
+                SnapshotRecord::Term(term) => {
+                    TERM_MAP.with(|map| map.borrow_mut().insert(term.id, term));
+                }
+                SnapshotRecord::Holiday(holiday) => {
+                    HOLIDAY_MAP.with(|map| map.borrow_mut().insert(holiday.id, holiday));
+                }
+                SnapshotRecord::Template(template) => {
+                    TEMPLATE_MAP.with(|map| map.borrow_mut().insert(template.id, template));
+                }
+                SnapshotRecord::Lesson(lesson) => {
+                    LESSON_MAP.with(|map| map.borrow_mut().insert(lesson.id, lesson));
+                }
+                SnapshotRecord::AttendanceSession(session) => {
+                    ATTENDANCE_MAP.with(|map| map.borrow_mut().insert(session.id, session));
+                }
+                SnapshotRecord::Assignment(assignment) => {
+                    ASSIGNMENT_MAP.with(|map| map.borrow_mut().insert(assignment.id, assignment));
+                }
+                SnapshotRecord::GradeWeights(weights) => {
+                    GRADE_WEIGHTS_MAP.with(|map| map.borrow_mut().insert(weights.lesson_id, weights));
+                }
+                SnapshotRecord::Room(room) => {
+                    ROOM_MAP.with(|map| map.borrow_mut().insert(room.id, room));
+                }
+                SnapshotRecord::LessonRooms(rooms) => {
+                    LESSON_ROOMS_MAP.with(|map| map.borrow_mut().insert(rooms.lesson_id, rooms));
+                }
+                SnapshotRecord::Eligibility(rules) => {
+                    ELIGIBILITY_MAP.with(|map| map.borrow_mut().insert(rules.lesson_id, rules));
+                }
+                SnapshotRecord::Completions(completions) => {
+                    COMPLETION_MAP.with(|map| map.borrow_mut().insert(completions.student_id, completions));
+                }
+            }
+        }
+        let chunks = restore_progress().unwrap_or_default() + 1;
+        set_restore_state(RestoreState { in_progress: true, chunks });
+        Ok(count)
+    })
+}
+
+// Chunks stored since start_restore, so a restore interrupted by an
+// upgrade can resume with the next one; None when no restore is running
+#[ic_cdk::query]
+fn get_restore_progress() -> Result<Option<u64>, Error> {
+    require_admin("read the restore progress")?;
+    Ok(restore_progress())
+}
+
+// keep a counter ahead of the ids restored into `map`
+fn advance_counter<V: Storable>(
+    counter: &'static LocalKey<RefCell<IdCell>>,
+    map: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+) {
+    let last_id = map.with(|map| map.borrow().last_key_value().map(|(id, _)| id));
+    if let Some(last_id) = last_id {
+        if counter_value(counter) <= last_id {
+            set_counter(counter, last_id + 1);
+        }
+    }
+}
+
+// Rebuild the lesson indexes and start the revision history of every
+// restored lesson
+#[ic_cdk::update]
+fn finish_restore() -> Result<(), Error> {
+    audited("finish_restore", &[], || {
+        require_admin("restore data")?;
+        require_restore_in_progress()?;
+        advance_counter(&STUDENT_ID_COUNTER, &STUDENT_MAP);
+        advance_counter(&TEACHER_ID_COUNTER, &TEACHER_MAP);
+        advance_counter(&LESSON_ID_COUNTER, &LESSON_MAP);
+        advance_counter(&SCHEDULE_ID_COUNTER, &SCHEDULE_ENTRY_MAP);
+        advance_counter(&TEMPLATE_ID_COUNTER, &TEMPLATE_MAP);
+        advance_counter(&TERM_ID_COUNTER, &TERM_MAP);
+        advance_counter(&HOLIDAY_ID_COUNTER, &HOLIDAY_MAP);
+        advance_counter(&ATTENDANCE_ID_COUNTER, &ATTENDANCE_MAP);
+        advance_counter(&ASSIGNMENT_ID_COUNTER, &ASSIGNMENT_MAP);
+        advance_counter(&ROOM_ID_COUNTER, &ROOM_MAP);
+        search::rebuild_lesson_indexes();
+        rebuild_attendance_indexes();
+        rebuild_assignment_index();
+        revisions::record_initial_revisions();
+        set_restore_state(RestoreState::default());
+        Ok(())
+    })
+}
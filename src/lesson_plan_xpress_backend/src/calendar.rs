@@ -0,0 +1,619 @@
+use crate::audit::{audited, record};
+use crate::auth::{require_admin, require_lesson_teacher};
+use crate::schedule::{self, Weekday};
+use crate::{do_insert_lesson, rooms, migrations, require_not_empty, revisions, Entity, Error, IdCell, Lesson, Memory};
+use crate::{LESSON_MAP, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, fmt};
+
+// A calendar day. Fields are ordered so the derived Ord is chronological.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Date {
+    pub(crate) year: u16,
+    pub(crate) month: u8,
+    pub(crate) day: u8,
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => 0,
+    }
+}
+
+impl Date {
+    pub(crate) fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month) && self.day >= 1 && self.day <= days_in_month(self.year as i64, self.month)
+    }
+
+    // days since 1970-01-01, from Howard Hinnant's days_from_civil
+    pub(crate) fn to_days(self) -> i64 {
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    // inverse of to_days
+    pub(crate) fn from_days(days: i64) -> Date {
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        Date {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+        }
+    }
+
+    pub(crate) fn weekday(self) -> Weekday {
+        // 1970-01-01 was a Thursday
+        Weekday::ALL[(self.to_days() + 3).rem_euclid(7) as usize]
+    }
+}
+
+// "2024-09-02"
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+// An academic term or semester, both dates inclusive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Term {
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    pub(crate) start: Date,
+    pub(crate) end: Date,
+}
+
+// A holiday or closure during which no lessons take place, both dates inclusive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Holiday {
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    pub(crate) start: Date,
+    pub(crate) end: Date,
+}
+
+impl Holiday {
+    pub(crate) fn covers(&self, date: Date) -> bool {
+        self.start <= date && date <= self.end
+    }
+}
+
+// payload for terms and holidays
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PeriodPayload {
+    name: String,
+    start: Date,
+    end: Date,
+}
+
+impl Storable for Term {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_term(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_term(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Holiday {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_holiday(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_holiday(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    pub(crate) static TERM_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))), 0)
+            .expect("Cannot create a counter")
+    );
+    pub(crate) static TERM_MAP: RefCell<StableBTreeMap<u64, Term, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
+    pub(crate) static HOLIDAY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))), 0)
+            .expect("Cannot create a counter")
+    );
+    pub(crate) static HOLIDAY_MAP: RefCell<StableBTreeMap<u64, Holiday, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))))
+    );
+}
+
+fn next_id(counter: &'static std::thread::LocalKey<RefCell<IdCell>>) -> u64 {
+    counter
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter")
+}
+
+// Longest term or holiday, in days. Sessions are expanded day by day over
+// a term, so this also bounds the work and the reply of get_lesson_sessions.
+const MAX_PERIOD_DAYS: i64 = 366;
+
+fn validate_period(payload: &PeriodPayload) -> Result<(), Error> {
+    require_not_empty("name", &payload.name)?;
+    for (field, date) in [("start", payload.start), ("end", payload.end)] {
+        if !date.is_valid() {
+            return Err(Error::InvalidInput {
+                field: field.to_string(),
+                reason: format!("{} is not a valid date", date),
+            });
+        }
+    }
+    if payload.start > payload.end {
+        return Err(Error::InvalidInput {
+            field: "end".to_string(),
+            reason: format!("must not be before start {}", payload.start),
+        });
+    }
+    if payload.end.to_days() - payload.start.to_days() >= MAX_PERIOD_DAYS {
+        return Err(Error::InvalidInput {
+            field: "end".to_string(),
+            reason: format!("must be less than {} days after start {}", MAX_PERIOD_DAYS, payload.start),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn get_term_record(term_id: u64) -> Result<Term, Error> {
+    let term = TERM_MAP.with(|service| service.borrow().get(&term_id));
+    if let Some(term) = term {
+        Ok(term)
+    } else {
+        Err(Error::NotFound { entity: Entity::Term, id: term_id })
+    }
+}
+
+pub(crate) fn require_term_exists(term_id: u64) -> Result<(), Error> {
+    get_term_record(term_id).map(|_| ())
+}
+
+// Lessons in terms that share no day cannot clash. Lessons without a term,
+// or with a term that no longer exists, run all year.
+pub(crate) fn terms_overlap(a: Option<u64>, b: Option<u64>) -> bool {
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) if a != b => (a, b),
+        _ => return true,
+    };
+    match (get_term_record(a), get_term_record(b)) {
+        (Ok(a), Ok(b)) => a.start <= b.end && b.start <= a.end,
+        _ => true,
+    }
+}
+
+// every holiday that overlaps the given days
+pub(crate) fn holidays_between(start: Date, end: Date) -> Vec<Holiday> {
+    HOLIDAY_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, holiday)| holiday)
+            .filter(|holiday| holiday.start <= end && start <= holiday.end)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_term(id: u64) -> Result<Term, Error> {
+    get_term_record(id)
+}
+
+// terms in the order they start
+#[ic_cdk::query]
+fn get_all_terms() -> Result<Vec<Term>, Error> {
+    let mut terms: Vec<Term> = TERM_MAP.with(|service| service.borrow().iter().map(|(_, term)| term).collect());
+    terms.sort_by_key(|term| (term.start, term.id));
+    Ok(terms)
+}
+
+#[ic_cdk::update]
+fn add_term(payload: PeriodPayload) -> Result<Term, Error> {
+    audited("add_term", &[], || {
+        require_admin("add terms")?;
+        validate_period(&payload)?;
+        let term = Term {
+            id: next_id(&TERM_ID_COUNTER),
+            name: payload.name,
+            start: payload.start,
+            end: payload.end,
+        };
+        TERM_MAP.with(|service| service.borrow_mut().insert(term.id, term.clone()));
+        Ok(term)
+    })
+}
+
+#[ic_cdk::update]
+fn update_term(id: u64, payload: PeriodPayload) -> Result<Term, Error> {
+    audited("update_term", &[record(Entity::Term, id)], || {
+        require_admin("update terms")?;
+        let old_term = get_term_record(id)?;
+        validate_period(&payload)?;
+        let term = Term {
+            id,
+            name: payload.name,
+            start: payload.start,
+            end: payload.end,
+        };
+        // new dates change which terms overlap, so the lessons bound to the
+        // term are checked against them before the change is kept
+        TERM_MAP.with(|service| service.borrow_mut().insert(id, term.clone()));
+        if let Err(error) = check_term_lessons(id) {
+            TERM_MAP.with(|service| service.borrow_mut().insert(id, old_term));
+            return Err(error);
+        }
+        Ok(term)
+    })
+}
+
+// the slots and rooms of every lesson bound to the term must not clash with
+// lessons of the terms it overlaps
+fn check_term_lessons(term_id: u64) -> Result<(), Error> {
+    let lessons: Vec<Lesson> = LESSON_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, lesson)| lesson)
+            .filter(|lesson| lesson.term_id == Some(term_id))
+            .collect()
+    });
+    for lesson in lessons {
+        schedule::check_lesson_slots(&lesson, false)?;
+        rooms::check_lesson_rooms(&lesson)?;
+    }
+    Ok(())
+}
+
+// a term can only be deleted once no lesson is bound to it
+#[ic_cdk::update]
+fn delete_term(id: u64) -> Result<Term, Error> {
+    audited("delete_term", &[record(Entity::Term, id)], || {
+        require_admin("delete terms")?;
+        let term = get_term_record(id)?;
+        let bound = LESSON_MAP.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, lesson)| lesson.term_id == Some(id))
+                .count()
+        });
+        if bound > 0 {
+            return Err(Error::Conflict {
+                reason: format!("Term with id={} is still used by {} lesson(s)", id, bound),
+            });
+        }
+        TERM_MAP.with(|service| service.borrow_mut().remove(&id));
+        Ok(term)
+    })
+}
+
+// holidays in the order they start; only those within the term when given
+#[ic_cdk::query]
+fn get_holidays(term_id: Option<u64>) -> Result<Vec<Holiday>, Error> {
+    let mut holidays = match term_id {
+        Some(term_id) => {
+            let term = get_term_record(term_id)?;
+            holidays_between(term.start, term.end)
+        }
+        None => HOLIDAY_MAP.with(|service| service.borrow().iter().map(|(_, holiday)| holiday).collect()),
+    };
+    holidays.sort_by_key(|holiday| (holiday.start, holiday.id));
+    Ok(holidays)
+}
+
+#[ic_cdk::update]
+fn add_holiday(payload: PeriodPayload) -> Result<Holiday, Error> {
+    audited("add_holiday", &[], || {
+        require_admin("add holidays")?;
+        validate_period(&payload)?;
+        let holiday = Holiday {
+            id: next_id(&HOLIDAY_ID_COUNTER),
+            name: payload.name,
+            start: payload.start,
+            end: payload.end,
+        };
+        HOLIDAY_MAP.with(|service| service.borrow_mut().insert(holiday.id, holiday.clone()));
+        Ok(holiday)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_holiday(id: u64) -> Result<Holiday, Error> {
+    audited("delete_holiday", &[record(Entity::Holiday, id)], || {
+        require_admin("delete holidays")?;
+        let holiday = HOLIDAY_MAP.with(|service| service.borrow_mut().remove(&id));
+        if let Some(holiday) = holiday {
+            Ok(holiday)
+        } else {
+            Err(Error::NotFound { entity: Entity::Holiday, id })
+        }
+    })
+}
+
+// Bind a lesson to a term, or unbind it with None. The lesson's slots are
+// checked against the lessons of the terms it now overlaps; admins may
+// `force` past schedule conflicts, but not past a room that is taken.
+#[ic_cdk::update]
+fn set_lesson_term(lesson_id: u64, term_id: Option<u64>, force: bool) -> Result<Lesson, Error> {
+    audited("set_lesson_term", &[record(Entity::Lesson, lesson_id)], || {
+        require_lesson_teacher(lesson_id, "change the term of this lesson")?;
+        let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+        let mut lesson = if let Some(lesson) = lesson {
+            lesson
+        } else {
+            return Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id });
+        };
+        if let Some(term_id) = term_id {
+            require_term_exists(term_id)?;
+        }
+        lesson.term_id = term_id;
+        schedule::check_lesson_slots(&lesson, force)?;
+        rooms::check_lesson_rooms(&lesson)?;
+        do_insert_lesson(&lesson)?;
+        revisions::record_revision(&lesson);
+        Ok(lesson)
+    })
+}
+
+// One dated occurrence of a lesson
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Session {
+    pub(crate) lesson_id: u64,
+    pub(crate) schedule_id: u64,
+    pub(crate) date: Date,
+    pub(crate) start_minute: u16,
+    pub(crate) end_minute: u16,
+}
+
+// every session of a lesson between two dates, skipping holidays
+pub(crate) fn expand_sessions(lesson: &Lesson, start: Date, end: Date) -> Vec<Session> {
+    // terms stored before their length was limited are cut short
+    let end = end.min(Date::from_days(start.to_days() + MAX_PERIOD_DAYS - 1));
+    let holidays = holidays_between(start, end);
+    let mut sessions = Vec::new();
+    for days in start.to_days()..=end.to_days() {
+        let date = Date::from_days(days);
+        if holidays.iter().any(|holiday| holiday.covers(date)) {
+            continue;
+        }
+        let weekday = date.weekday();
+        let mut slots: Vec<_> = lesson.schedule.iter().filter(|entry| entry.day == weekday).collect();
+        slots.sort_by_key(|entry| entry.start_minute);
+        for entry in slots {
+            sessions.push(Session {
+                lesson_id: lesson.id,
+                schedule_id: entry.id,
+                date,
+                start_minute: entry.start_minute,
+                end_minute: entry.end_minute,
+            });
+        }
+    }
+    sessions
+}
+
+// The weekly schedule of a lesson as dated sessions within a term, the
+// lesson's own term when none is given
+pub(crate) fn lesson_sessions(lesson_id: u64, term_id: Option<u64>) -> Result<Vec<Session>, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    let lesson = if let Some(lesson) = lesson {
+        lesson
+    } else {
+        return Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id });
+    };
+    let term_id = match term_id.or(lesson.term_id) {
+        Some(term_id) => term_id,
+        None => {
+            return Err(Error::InvalidInput {
+                field: "term_id".to_string(),
+                reason: format!("lesson {} is not bound to a term", lesson_id),
+            })
+        }
+    };
+    let term = get_term_record(term_id)?;
+    Ok(expand_sessions(&lesson, term.start, term.end))
+}
+
+#[ic_cdk::query]
+fn get_lesson_sessions(lesson_id: u64, term_id: Option<u64>) -> Result<Vec<Session>, Error> {
+    lesson_sessions(lesson_id, term_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScheduleEntry;
+
+    fn date(year: u16, month: u8, day: u8) -> Date {
+        Date { year, month, day }
+    }
+
+    fn period(start: Date, end: Date) -> PeriodPayload {
+        PeriodPayload {
+            name: "Autumn".to_string(),
+            start,
+            end,
+        }
+    }
+
+    fn invalid_field(result: Result<(), Error>) -> Option<String> {
+        match result {
+            Err(Error::InvalidInput { field, .. }) => Some(field),
+            _ => None,
+        }
+    }
+
+    fn slot(id: u64, day: Weekday, start_minute: u16) -> ScheduleEntry {
+        ScheduleEntry {
+            id,
+            day,
+            start_minute,
+            end_minute: start_minute + 45,
+        }
+    }
+
+    fn lesson(schedule: Vec<ScheduleEntry>) -> Lesson {
+        Lesson {
+            id: 3,
+            title: "Fractions".to_string(),
+            description: "Adding fractions".to_string(),
+            grade_level: "3".to_string(),
+            subject: "Math".to_string(),
+            teacher_id: 1,
+            students: Vec::new(),
+            schedule,
+            plan: Default::default(),
+            cloned_from: None,
+            term_id: None,
+            capacity: None,
+            waitlist: Vec::new(),
+        }
+    }
+
+    fn session_days(sessions: &[Session]) -> Vec<(String, u64)> {
+        sessions
+            .iter()
+            .map(|session| (session.date.to_string(), session.schedule_id))
+            .collect()
+    }
+
+    #[test]
+    fn leap_years_follow_the_gregorian_rules() {
+        assert!(is_leap_year(2024));
+        assert!(is_leap_year(2000));
+        assert!(!is_leap_year(1900));
+        assert!(!is_leap_year(2023));
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2023, 2), 28);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2024, 4), 30);
+        assert_eq!(days_in_month(2024, 12), 31);
+    }
+
+    #[test]
+    fn dates_are_checked_against_their_month() {
+        assert!(date(2024, 2, 29).is_valid());
+        assert!(!date(2023, 2, 29).is_valid());
+        assert!(!date(2024, 4, 31).is_valid());
+        assert!(!date(2024, 13, 1).is_valid());
+        assert!(!date(2024, 0, 1).is_valid());
+        assert!(!date(2024, 1, 0).is_valid());
+    }
+
+    #[test]
+    fn days_count_from_the_unix_epoch() {
+        assert_eq!(date(1970, 1, 1).to_days(), 0);
+        assert_eq!(date(1969, 12, 31).to_days(), -1);
+        assert_eq!(date(2000, 3, 1).to_days(), 11_017);
+        assert_eq!(date(2024, 3, 1).to_days() - date(2024, 2, 28).to_days(), 2);
+        assert_eq!(date(2023, 3, 1).to_days() - date(2023, 2, 28).to_days(), 1);
+        assert_eq!(Date::from_days(date(2024, 2, 28).to_days() + 1).to_string(), "2024-02-29");
+        for days in -800..40_000 {
+            assert_eq!(Date::from_days(days).to_days(), days);
+        }
+    }
+
+    #[test]
+    fn weekdays_follow_the_calendar() {
+        assert_eq!(date(1970, 1, 1).weekday(), Weekday::Thursday);
+        assert_eq!(date(2000, 1, 1).weekday(), Weekday::Saturday);
+        assert_eq!(date(2024, 2, 29).weekday(), Weekday::Thursday);
+        assert_eq!(date(2024, 9, 2).weekday(), Weekday::Monday);
+        assert_eq!(date(2024, 9, 8).weekday(), Weekday::Sunday);
+    }
+
+    #[test]
+    fn periods_are_at_most_366_days_long() {
+        assert!(validate_period(&period(date(2024, 1, 1), date(2024, 1, 1))).is_ok());
+        // 2024 is a leap year, so this is 366 days
+        assert!(validate_period(&period(date(2024, 1, 1), date(2024, 12, 31))).is_ok());
+        assert_eq!(invalid_field(validate_period(&period(date(2024, 1, 1), date(2025, 1, 1)))).as_deref(), Some("end"));
+        assert_eq!(invalid_field(validate_period(&period(date(2024, 9, 2), date(2024, 9, 1)))).as_deref(), Some("end"));
+        assert_eq!(invalid_field(validate_period(&period(date(2023, 2, 29), date(2023, 3, 1)))).as_deref(), Some("start"));
+        let mut unnamed = period(date(2024, 1, 1), date(2024, 1, 2));
+        unnamed.name = " ".to_string();
+        assert_eq!(invalid_field(validate_period(&unnamed)).as_deref(), Some("name"));
+    }
+
+    #[test]
+    fn sessions_fall_on_the_slot_weekdays_in_start_order() {
+        let lesson = lesson(vec![
+            slot(12, Weekday::Wednesday, 13 * 60),
+            slot(11, Weekday::Wednesday, 9 * 60),
+            slot(10, Weekday::Monday, 9 * 60),
+        ]);
+        let sessions = expand_sessions(&lesson, date(2024, 9, 2), date(2024, 9, 11));
+        assert_eq!(
+            session_days(&sessions),
+            vec![
+                ("2024-09-02".to_string(), 10),
+                ("2024-09-04".to_string(), 11),
+                ("2024-09-04".to_string(), 12),
+                ("2024-09-09".to_string(), 10),
+                ("2024-09-11".to_string(), 11),
+                ("2024-09-11".to_string(), 12),
+            ]
+        );
+        assert!(sessions.iter().all(|session| session.lesson_id == 3));
+        assert_eq!((sessions[2].start_minute, sessions[2].end_minute), (13 * 60, 13 * 60 + 45));
+    }
+
+    #[test]
+    fn sessions_skip_holidays() {
+        let holiday = Holiday {
+            id: 1,
+            name: "Autumn break".to_string(),
+            start: date(2024, 9, 9),
+            end: date(2024, 9, 13),
+        };
+        HOLIDAY_MAP.with(|service| service.borrow_mut().insert(holiday.id, holiday));
+        let lesson = lesson(vec![slot(10, Weekday::Monday, 9 * 60)]);
+        let sessions = expand_sessions(&lesson, date(2024, 9, 1), date(2024, 9, 30));
+        HOLIDAY_MAP.with(|service| service.borrow_mut().remove(&1));
+        let dates: Vec<String> = sessions.iter().map(|session| session.date.to_string()).collect();
+        assert_eq!(dates, vec!["2024-09-02", "2024-09-16", "2024-09-23", "2024-09-30"]);
+    }
+
+    #[test]
+    fn sessions_stop_after_366_days() {
+        // 2024-01-01 is a Monday; a term stored before the limit runs on
+        // for three years, but only its first 366 days are expanded
+        let lesson = lesson(vec![slot(10, Weekday::Monday, 9 * 60)]);
+        let sessions = expand_sessions(&lesson, date(2024, 1, 1), date(2026, 12, 31));
+        assert_eq!(sessions.len(), 53);
+        assert_eq!(sessions[0].date.to_string(), "2024-01-01");
+        assert_eq!(sessions[52].date.to_string(), "2024-12-30");
+    }
+}
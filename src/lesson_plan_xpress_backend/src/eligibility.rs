@@ -0,0 +1,430 @@
+use crate::audit::{audited, record};
+use crate::auth::{require_lesson_teacher, require_staff, require_student_reader};
+use crate::links::{require_lesson_exists, require_student_exists};
+use crate::migrations;
+use crate::{Entity, Error, Lesson, Memory, LESSON_MAP, MEMORY_MANAGER, STUDENT_MAP};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::{borrow::Cow, cell::RefCell};
+
+// Most prerequisite lessons a lesson can have
+const MAX_PREREQUISITES: usize = 16;
+
+// Highest grade after kindergarten
+const MAX_GRADE: u8 = 12;
+
+// A school grade, kindergarten first. Grade levels entered as text are
+// stored in the form Display gives ("K", "1" to "12").
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum GradeLevel {
+    Kindergarten,
+    Grade(u8),
+}
+
+impl GradeLevel {
+    // Read "K", "kindergarten", "3", "3rd", "grade 3" or "3rd grade"
+    pub(crate) fn parse(text: &str) -> Option<GradeLevel> {
+        let text = text.trim().to_lowercase();
+        if matches!(text.as_str(), "k" | "kg" | "kindergarten") {
+            return Some(GradeLevel::Kindergarten);
+        }
+        let number = text
+            .strip_prefix("grade")
+            .or_else(|| text.strip_suffix("grade"))
+            .unwrap_or(&text)
+            .trim();
+        let number = ["st", "nd", "rd", "th"]
+            .iter()
+            .find_map(|suffix| number.strip_suffix(suffix))
+            .unwrap_or(number);
+        match number.parse::<u8>() {
+            Ok(grade) => GradeLevel::Grade(grade).valid(),
+            Err(_) => None,
+        }
+    }
+
+    fn valid(self) -> Option<GradeLevel> {
+        match self {
+            GradeLevel::Grade(grade) if grade == 0 || grade > MAX_GRADE => None,
+            grade => Some(grade),
+        }
+    }
+}
+
+impl fmt::Display for GradeLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GradeLevel::Kindergarten => write!(f, "K"),
+            GradeLevel::Grade(grade) => write!(f, "{}", grade),
+        }
+    }
+}
+
+fn invalid_grade(field: &str) -> Error {
+    Error::InvalidInput {
+        field: field.to_string(),
+        reason: format!("must be K or a grade from 1 to {}", MAX_GRADE),
+    }
+}
+
+// the stored form of a grade level given as text
+pub(crate) fn require_grade_level(field: &str, text: &str) -> Result<String, Error> {
+    match GradeLevel::parse(text) {
+        Some(grade) => Ok(grade.to_string()),
+        None => Err(invalid_grade(field)),
+    }
+}
+
+// the stored form of a grade level, or the text itself for records written
+// before grade levels were checked
+pub(crate) fn canonical_grade_level(text: &str) -> String {
+    GradeLevel::parse(text).map_or_else(|| text.to_string(), |grade| grade.to_string())
+}
+
+// Who may enroll in a lesson. Grade bounds are inclusive, None leaves that
+// end open.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Eligibility {
+    pub(crate) lesson_id: u64,
+    min_grade: Option<GradeLevel>,
+    max_grade: Option<GradeLevel>,
+    // lessons the student must have completed first
+    prerequisites: Vec<u64>,
+}
+
+impl Storable for Eligibility {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_eligibility(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_eligibility(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Eligibility {
+    // "lesson 4: grades 3 to 5, after lessons [1, 2]"
+    pub(crate) fn describe(&self) -> String {
+        let bound = |grade: Option<GradeLevel>| grade.map_or("any".to_string(), |grade| grade.to_string());
+        let prerequisites: Vec<String> = self.prerequisites.iter().map(u64::to_string).collect();
+        format!(
+            "lesson {}: grades {} to {}, after lessons [{}]",
+            self.lesson_id,
+            bound(self.min_grade),
+            bound(self.max_grade),
+            prerequisites.join(", ")
+        )
+    }
+}
+
+// The rules a teacher sets for a lesson
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct EligibilityPayload {
+    min_grade: Option<GradeLevel>,
+    max_grade: Option<GradeLevel>,
+    prerequisites: Vec<u64>,
+}
+
+// Lessons a student has completed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Completions {
+    pub(crate) student_id: u64,
+    lessons: Vec<u64>,
+}
+
+impl Storable for Completions {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_completions(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_completions(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Completions {
+    // "student 3 completed lessons [1, 2]"
+    pub(crate) fn describe(&self) -> String {
+        let lessons: Vec<String> = self.lessons.iter().map(u64::to_string).collect();
+        format!("student {} completed lessons [{}]", self.student_id, lessons.join(", "))
+    }
+}
+
+thread_local! {
+    // lesson_id -> rules set by the lesson's teacher
+    pub(crate) static ELIGIBILITY_MAP: RefCell<StableBTreeMap<u64, Eligibility, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))))
+    );
+    // student_id -> lessons they completed
+    pub(crate) static COMPLETION_MAP: RefCell<StableBTreeMap<u64, Completions, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))))
+    );
+}
+
+fn get_lesson_record(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+// The lesson's rules. Lessons without rules take students of their own
+// grade level, or any student when that level is not recognized.
+fn lesson_eligibility(lesson: &Lesson) -> Eligibility {
+    ELIGIBILITY_MAP
+        .with(|service| service.borrow().get(&lesson.id))
+        .unwrap_or_else(|| {
+            let grade = GradeLevel::parse(&lesson.grade_level);
+            Eligibility { lesson_id: lesson.id, min_grade: grade, max_grade: grade, prerequisites: Vec::new() }
+        })
+}
+
+fn completions(student_id: u64) -> Completions {
+    COMPLETION_MAP
+        .with(|service| service.borrow().get(&student_id))
+        .unwrap_or(Completions { student_id, lessons: Vec::new() })
+}
+
+fn store_completions(completions: &Completions) {
+    COMPLETION_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        if completions.lessons.is_empty() {
+            map.remove(&completions.student_id);
+        } else {
+            map.insert(completions.student_id, completions.clone());
+        }
+    });
+}
+
+fn not_eligible(lesson_id: u64, student_id: u64, reason: String) -> Error {
+    Error::NotEligible { lesson_id, student_id, reason }
+}
+
+// Reject a student who is outside the lesson's grade range or has not
+// completed all its prerequisites
+pub(crate) fn require_eligible(lesson: &Lesson, student_id: u64) -> Result<(), Error> {
+    let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
+    let student = if let Some(student) = student {
+        student
+    } else {
+        return Err(Error::NotFound { entity: Entity::Student, id: student_id });
+    };
+    let rules = lesson_eligibility(lesson);
+    if rules.min_grade.is_some() || rules.max_grade.is_some() {
+        let grade = match GradeLevel::parse(&student.grade_level) {
+            Some(grade) => grade,
+            None => {
+                return Err(not_eligible(
+                    lesson.id,
+                    student_id,
+                    format!("grade level {:?} is not recognized", student.grade_level),
+                ))
+            }
+        };
+        if let Some(min_grade) = rules.min_grade.filter(|min_grade| grade < *min_grade) {
+            return Err(not_eligible(
+                lesson.id,
+                student_id,
+                format!("grade {} is below the lesson's lowest grade {}", grade, min_grade),
+            ));
+        }
+        if let Some(max_grade) = rules.max_grade.filter(|max_grade| grade > *max_grade) {
+            return Err(not_eligible(
+                lesson.id,
+                student_id,
+                format!("grade {} is above the lesson's highest grade {}", grade, max_grade),
+            ));
+        }
+    }
+    let completed = completions(student_id).lessons;
+    let missing: Vec<String> = rules
+        .prerequisites
+        .iter()
+        .filter(|lesson_id| !completed.contains(lesson_id))
+        .map(u64::to_string)
+        .collect();
+    if !missing.is_empty() {
+        return Err(not_eligible(
+            lesson.id,
+            student_id,
+            format!("prerequisite lessons [{}] are not completed", missing.join(", ")),
+        ));
+    }
+    Ok(())
+}
+
+// Refuse prerequisites that lead back to the lesson itself, which would
+// leave no student able to enroll in either lesson
+fn require_no_cycle(lesson_id: u64, prerequisites: &[u64]) -> Result<(), Error> {
+    let mut seen = BTreeSet::new();
+    let mut pending: Vec<u64> = prerequisites.to_vec();
+    while let Some(id) = pending.pop() {
+        if id == lesson_id {
+            return Err(Error::Conflict {
+                reason: format!("lesson {} would become its own prerequisite", lesson_id),
+            });
+        }
+        if seen.insert(id) {
+            if let Some(rules) = ELIGIBILITY_MAP.with(|service| service.borrow().get(&id)) {
+                pending.extend(rules.prerequisites);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_eligibility(lesson_id: u64, payload: &EligibilityPayload) -> Result<(), Error> {
+    if payload.min_grade.is_some_and(|grade| grade.valid().is_none()) {
+        return Err(invalid_grade("min_grade"));
+    }
+    if payload.max_grade.is_some_and(|grade| grade.valid().is_none()) {
+        return Err(invalid_grade("max_grade"));
+    }
+    if let (Some(min_grade), Some(max_grade)) = (payload.min_grade, payload.max_grade) {
+        if min_grade > max_grade {
+            return Err(Error::InvalidInput {
+                field: "max_grade".to_string(),
+                reason: "must not be below min_grade".to_string(),
+            });
+        }
+    }
+    if payload.prerequisites.len() > MAX_PREREQUISITES {
+        return Err(Error::InvalidInput {
+            field: "prerequisites".to_string(),
+            reason: format!("at most {} lessons", MAX_PREREQUISITES),
+        });
+    }
+    let mut seen = BTreeSet::new();
+    for &prerequisite in &payload.prerequisites {
+        require_lesson_exists(prerequisite)?;
+        if !seen.insert(prerequisite) {
+            return Err(Error::InvalidInput {
+                field: "prerequisites".to_string(),
+                reason: format!("lesson {} is given more than once", prerequisite),
+            });
+        }
+    }
+    require_no_cycle(lesson_id, &payload.prerequisites)
+}
+
+// drop the rules of a deleted lesson and every reference to it
+pub(crate) fn remove_lesson_eligibility(lesson_id: u64) {
+    ELIGIBILITY_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        map.remove(&lesson_id);
+        let dependent: Vec<Eligibility> = map
+            .iter()
+            .map(|(_, rules)| rules)
+            .filter(|rules| rules.prerequisites.contains(&lesson_id))
+            .collect();
+        for mut rules in dependent {
+            rules.prerequisites.retain(|id| id != &lesson_id);
+            map.insert(rules.lesson_id, rules);
+        }
+    });
+    let holders: Vec<Completions> = COMPLETION_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, completions)| completions)
+            .filter(|completions| completions.lessons.contains(&lesson_id))
+            .collect()
+    });
+    for mut completions in holders {
+        completions.lessons.retain(|id| id != &lesson_id);
+        store_completions(&completions);
+    }
+}
+
+// drop the completions of a deleted student
+pub(crate) fn remove_student_completions(student_id: u64) {
+    COMPLETION_MAP.with(|service| service.borrow_mut().remove(&student_id));
+}
+
+// rules that decide who may enroll in the lesson
+#[ic_cdk::query]
+fn get_lesson_eligibility(lesson_id: u64) -> Result<Eligibility, Error> {
+    Ok(lesson_eligibility(&get_lesson_record(lesson_id)?))
+}
+
+// Replace the lesson's rules. Students already enrolled keep their place.
+#[ic_cdk::update]
+fn set_lesson_eligibility(lesson_id: u64, payload: EligibilityPayload) -> Result<Eligibility, Error> {
+    audited("set_lesson_eligibility", &[record(Entity::Lesson, lesson_id)], || {
+        get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "set who may enroll in this lesson")?;
+        validate_eligibility(lesson_id, &payload)?;
+        let rules = Eligibility {
+            lesson_id,
+            min_grade: payload.min_grade,
+            max_grade: payload.max_grade,
+            prerequisites: payload.prerequisites,
+        };
+        ELIGIBILITY_MAP.with(|service| service.borrow_mut().insert(lesson_id, rules.clone()));
+        Ok(rules)
+    })
+}
+
+// Ok when the student may enroll in the lesson, otherwise the reason why not
+#[ic_cdk::query]
+fn check_eligibility(lesson_id: u64, student_id: u64) -> Result<(), Error> {
+    let lesson = get_lesson_record(lesson_id)?;
+    require_eligible(&lesson, student_id)
+}
+
+// lessons the student has completed
+#[ic_cdk::query]
+fn get_completed_lessons(student_id: u64) -> Result<Completions, Error> {
+    require_student_exists(student_id)?;
+    require_staff("read the completions of students").or_else(|_| require_student_reader(student_id, "read these completions"))?;
+    Ok(completions(student_id))
+}
+
+// mark the lesson as completed by the student, which counts towards the
+// prerequisites of other lessons
+#[ic_cdk::update]
+fn record_completion(lesson_id: u64, student_id: u64) -> Result<Completions, Error> {
+    audited("record_completion", &[record(Entity::Lesson, lesson_id), record(Entity::Student, student_id)], || {
+        get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "record completions of this lesson")?;
+        require_student_exists(student_id)?;
+        let mut completions = completions(student_id);
+        if completions.lessons.contains(&lesson_id) {
+            return Err(Error::Conflict {
+                reason: format!("Student with id={} already completed lesson with id={}", student_id, lesson_id),
+            });
+        }
+        completions.lessons.push(lesson_id);
+        store_completions(&completions);
+        Ok(completions)
+    })
+}
+
+// take back a completion recorded by mistake
+#[ic_cdk::update]
+fn revoke_completion(lesson_id: u64, student_id: u64) -> Result<Completions, Error> {
+    audited("revoke_completion", &[record(Entity::Lesson, lesson_id), record(Entity::Student, student_id)], || {
+        get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "record completions of this lesson")?;
+        require_student_exists(student_id)?;
+        let mut completions = completions(student_id);
+        if !completions.lessons.contains(&lesson_id) {
+            return Err(Error::InvalidInput {
+                field: "student_id".to_string(),
+                reason: format!("has not completed lesson {}", lesson_id),
+            });
+        }
+        completions.lessons.retain(|id| id != &lesson_id);
+        store_completions(&completions);
+        Ok(completions)
+    })
+}
@@ -0,0 +1,108 @@
+use crate::audit::{audited, record};
+use crate::auth::require_teacher;
+use crate::eligibility;
+use crate::waitlist::{self, Placement};
+use crate::{do_insert_lesson, do_insert_student, Entity, Error, Lesson, Student};
+use crate::{LESSON_MAP, STUDENT_MAP};
+
+// Both sides of an enrollment after it was changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Enrollment {
+    pub(crate) lesson: Lesson,
+    pub(crate) student: Student,
+}
+
+fn get_lesson_and_student(lesson_id: u64, student_id: u64) -> Result<(Lesson, Student), Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    let lesson = if let Some(lesson) = lesson {
+        lesson
+    } else {
+        return Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id });
+    };
+    let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
+    if let Some(student) = student {
+        Ok((lesson, student))
+    } else {
+        Err(Error::NotFound { entity: Entity::Student, id: student_id })
+    }
+}
+
+// enroll a student in a lesson, updating Lesson.students and Student.lessons
+// together. A link that exists on only one side is completed. When the
+// lesson is full the student joins its waitlist instead. New enrollments must
+// meet the lesson's eligibility rules.
+#[ic_cdk::update]
+fn enroll(lesson_id: u64, student_id: u64) -> Result<Enrollment, Error> {
+    audited("enroll", &[record(Entity::Lesson, lesson_id), record(Entity::Student, student_id)], || {
+        enroll_student(lesson_id, student_id)
+    })
+}
+
+// the work of enroll, shared with insert_lesson_to_student and
+// insert_student_to_lesson
+pub(crate) fn enroll_student(lesson_id: u64, student_id: u64) -> Result<Enrollment, Error> {
+    let (mut lesson, mut student) = get_lesson_and_student(lesson_id, student_id)?;
+    require_teacher(lesson.teacher_id, "enroll students in this lesson")?;
+
+    let in_lesson = lesson.students.contains(&student_id);
+    let in_student = student.lessons.contains(&lesson_id);
+    if in_lesson && in_student {
+        return Err(Error::Conflict {
+            reason: format!(
+                "Student with id={} is already enrolled in lesson with id={}",
+                student_id, lesson_id
+            ),
+        });
+    }
+
+    if !in_lesson {
+        eligibility::require_eligible(&lesson, student_id)?;
+        let placement = waitlist::place_student(&mut lesson, student_id)?;
+        do_insert_lesson(&lesson)?;
+        // a waitlisted student is linked from their side once promoted
+        if placement == Placement::Waitlisted {
+            if in_student {
+                student.lessons.retain(|id| id != &lesson_id);
+                do_insert_student(&student);
+            }
+            return Ok(Enrollment { lesson, student });
+        }
+    }
+    if !in_student {
+        student.lessons.push(lesson_id);
+        do_insert_student(&student);
+    }
+    Ok(Enrollment { lesson, student })
+}
+
+// remove a student from a lesson, or from its waitlist, on both sides.
+// The freed place goes to the first student on the waitlist.
+#[ic_cdk::update]
+fn unenroll(lesson_id: u64, student_id: u64) -> Result<Enrollment, Error> {
+    audited("unenroll", &[record(Entity::Lesson, lesson_id), record(Entity::Student, student_id)], || {
+        unenroll_student(lesson_id, student_id)
+    })
+}
+
+// the work of unenroll, shared with delete_student_from_lesson and
+// delete_lesson_from_student
+pub(crate) fn unenroll_student(lesson_id: u64, student_id: u64) -> Result<Enrollment, Error> {
+    let (mut lesson, mut student) = get_lesson_and_student(lesson_id, student_id)?;
+    require_teacher(lesson.teacher_id, "unenroll students from this lesson")?;
+
+    if !lesson.students.contains(&student_id)
+        && !lesson.waitlist.contains(&student_id)
+        && !student.lessons.contains(&lesson_id)
+    {
+        return Err(Error::InvalidInput {
+            field: "student_id".to_string(),
+            reason: format!("not enrolled in lesson {}", lesson_id),
+        });
+    }
+
+    // also stores the student without the lesson
+    waitlist::release_student(&mut lesson, student_id);
+    student.lessons.retain(|id| id != &lesson_id);
+    do_insert_lesson(&lesson)?;
+    Ok(Enrollment { lesson, student })
+}
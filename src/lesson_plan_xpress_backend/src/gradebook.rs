@@ -0,0 +1,515 @@
+use crate::audit::{audited, record};
+use crate::auth::{require_lesson_teacher, require_staff, require_student_reader};
+use crate::calendar::Date;
+use crate::{migrations, require_max_size, require_not_empty, student_lessons};
+use crate::{Entity, Error, IdCell, Lesson, Memory, LESSON_MAP, MAX_NAME_SIZE, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum GradeCategory {
+    Homework,
+    Quiz,
+    Exam,
+}
+
+impl GradeCategory {
+    const ALL: [GradeCategory; 3] = [GradeCategory::Homework, GradeCategory::Quiz, GradeCategory::Exam];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            GradeCategory::Homework => "Homework",
+            GradeCategory::Quiz => "Quiz",
+            GradeCategory::Exam => "Exam",
+        }
+    }
+}
+
+// Points a student scored on an assignment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Score {
+    student_id: u64,
+    points: f64,
+}
+
+// A graded piece of work in a lesson, with the scores given so far
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Assignment {
+    pub(crate) id: u64,
+    pub(crate) lesson_id: u64,
+    pub(crate) title: String,
+    pub(crate) category: GradeCategory,
+    pub(crate) max_points: u32,
+    pub(crate) due: Option<Date>,
+    pub(crate) scores: Vec<Score>,
+}
+
+impl Assignment {
+    // "Quiz 1" (Quiz, 20 points), lesson 3, 12 scores
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "{:?} ({}, {} points), lesson {}, {} scores",
+            self.title,
+            self.category.name(),
+            self.max_points,
+            self.lesson_id,
+            self.scores.len()
+        )
+    }
+
+    fn score_of(&self, student_id: u64) -> Option<f64> {
+        self.scores
+            .iter()
+            .find(|score| score.student_id == student_id)
+            .map(|score| score.points)
+    }
+}
+
+impl Storable for Assignment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_assignment(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_assignment(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AssignmentPayload {
+    title: String,
+    category: GradeCategory,
+    max_points: u32,
+    due: Option<Date>,
+}
+
+// Relative weight of each category in a lesson's average. A lesson that has
+// none set weighs all categories equally.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GradeWeights {
+    pub(crate) lesson_id: u64,
+    homework: u32,
+    quiz: u32,
+    exam: u32,
+}
+
+impl GradeWeights {
+    fn equal(lesson_id: u64) -> Self {
+        GradeWeights {
+            lesson_id,
+            homework: 1,
+            quiz: 1,
+            exam: 1,
+        }
+    }
+
+    // "weights homework 1, quiz 2, exam 3"
+    pub(crate) fn describe(&self) -> String {
+        format!("weights homework {}, quiz {}, exam {}", self.homework, self.quiz, self.exam)
+    }
+
+    fn of(&self, category: GradeCategory) -> u32 {
+        match category {
+            GradeCategory::Homework => self.homework,
+            GradeCategory::Quiz => self.quiz,
+            GradeCategory::Exam => self.exam,
+        }
+    }
+}
+
+impl Storable for GradeWeights {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_grade_weights(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_grade_weights(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    pub(crate) static ASSIGNMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), 0)
+            .expect("Cannot create a counter")
+    );
+    pub(crate) static ASSIGNMENT_MAP: RefCell<StableBTreeMap<u64, Assignment, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))))
+    );
+    // (lesson_id, assignment_id) of every assignment
+    static LESSON_ASSIGNMENT_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))))
+    );
+    // lesson_id -> weights, only for lessons that set their own
+    pub(crate) static GRADE_WEIGHTS_MAP: RefCell<StableBTreeMap<u64, GradeWeights, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))))
+    );
+}
+
+// Points over the assignments of one category that have a score. The
+// percentage is None until one of them is scored.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CategoryGrade {
+    category: GradeCategory,
+    points: f64,
+    max_points: u64,
+    percentage: Option<f64>,
+}
+
+// A student's running grade in one lesson
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StudentGrade {
+    student_id: u64,
+    categories: Vec<CategoryGrade>,
+    // weighted over the categories that have a percentage
+    average: Option<f64>,
+}
+
+// Every assignment of a lesson with its scores, and the grade of each
+// enrolled student
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GradeSheet {
+    lesson_id: u64,
+    weights: GradeWeights,
+    assignments: Vec<Assignment>,
+    grades: Vec<StudentGrade>,
+}
+
+// One assignment as seen by a student
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AssignmentResult {
+    assignment_id: u64,
+    title: String,
+    category: GradeCategory,
+    max_points: u32,
+    due: Option<Date>,
+    points: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LessonGrades {
+    lesson_id: u64,
+    title: String,
+    weights: GradeWeights,
+    assignments: Vec<AssignmentResult>,
+    grade: StudentGrade,
+}
+
+pub(crate) fn get_assignment_record(assignment_id: u64) -> Result<Assignment, Error> {
+    let assignment = ASSIGNMENT_MAP.with(|service| service.borrow().get(&assignment_id));
+    if let Some(assignment) = assignment {
+        Ok(assignment)
+    } else {
+        Err(Error::NotFound { entity: Entity::Assignment, id: assignment_id })
+    }
+}
+
+fn get_lesson_record(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+fn do_insert_assignment(assignment: &Assignment) {
+    LESSON_ASSIGNMENT_INDEX.with(|index| index.borrow_mut().insert((assignment.lesson_id, assignment.id), ()));
+    ASSIGNMENT_MAP.with(|service| service.borrow_mut().insert(assignment.id, assignment.clone()));
+}
+
+fn remove_assignment(assignment: &Assignment) {
+    LESSON_ASSIGNMENT_INDEX.with(|index| index.borrow_mut().remove(&(assignment.lesson_id, assignment.id)));
+    ASSIGNMENT_MAP.with(|service| service.borrow_mut().remove(&assignment.id));
+}
+
+// assignments of a lesson in the order they were added
+fn lesson_assignments(lesson_id: u64) -> Vec<Assignment> {
+    let ids: Vec<u64> = LESSON_ASSIGNMENT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((lesson_id, 0)..=(lesson_id, u64::MAX))
+            .map(|((_, assignment_id), _)| assignment_id)
+            .collect()
+    });
+    ASSIGNMENT_MAP.with(|service| {
+        let map = service.borrow();
+        ids.iter().filter_map(|id| map.get(id)).collect()
+    })
+}
+
+fn lesson_weights(lesson_id: u64) -> GradeWeights {
+    GRADE_WEIGHTS_MAP
+        .with(|service| service.borrow().get(&lesson_id))
+        .unwrap_or_else(|| GradeWeights::equal(lesson_id))
+}
+
+// drop the assignments and weights of a deleted lesson
+pub(crate) fn remove_lesson_grades(lesson_id: u64) {
+    for assignment in lesson_assignments(lesson_id) {
+        remove_assignment(&assignment);
+    }
+    GRADE_WEIGHTS_MAP.with(|service| service.borrow_mut().remove(&lesson_id));
+}
+
+// drop the scores of a deleted student from every assignment
+pub(crate) fn remove_student_scores(student_id: u64) {
+    let assignments: Vec<Assignment> = ASSIGNMENT_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, assignment)| assignment)
+            .filter(|assignment| assignment.score_of(student_id).is_some())
+            .collect()
+    });
+    for mut assignment in assignments {
+        assignment.scores.retain(|score| score.student_id != student_id);
+        do_insert_assignment(&assignment);
+    }
+}
+
+// rebuild LESSON_ASSIGNMENT_INDEX from ASSIGNMENT_MAP, after a restore
+pub(crate) fn rebuild_assignment_index() {
+    LESSON_ASSIGNMENT_INDEX.with(|index| index.borrow_mut().clear_new());
+    let assignments: Vec<Assignment> =
+        ASSIGNMENT_MAP.with(|service| service.borrow().iter().map(|(_, assignment)| assignment).collect());
+    for assignment in &assignments {
+        do_insert_assignment(assignment);
+    }
+}
+
+fn as_percentage(points: f64, max_points: u64) -> Option<f64> {
+    (max_points > 0).then(|| points * 100.0 / max_points as f64)
+}
+
+fn student_grade(student_id: u64, assignments: &[Assignment], weights: &GradeWeights) -> StudentGrade {
+    let categories: Vec<CategoryGrade> = GradeCategory::ALL
+        .iter()
+        .map(|category| {
+            let (points, max_points) = assignments
+                .iter()
+                .filter(|assignment| assignment.category == *category)
+                .filter_map(|assignment| Some((assignment.score_of(student_id)?, assignment.max_points as u64)))
+                .fold((0.0, 0), |(points, max_points), (score, max)| (points + score, max_points + max));
+            CategoryGrade {
+                category: *category,
+                points,
+                max_points,
+                percentage: as_percentage(points, max_points),
+            }
+        })
+        .collect();
+    let (weighted, total_weight) = categories
+        .iter()
+        .filter_map(|grade| Some((grade.percentage?, weights.of(grade.category) as f64)))
+        .filter(|(_, weight)| *weight > 0.0)
+        .fold((0.0, 0.0), |(weighted, total), (percentage, weight)| {
+            (weighted + percentage * weight, total + weight)
+        });
+    StudentGrade {
+        student_id,
+        categories,
+        average: (total_weight > 0.0).then(|| weighted / total_weight),
+    }
+}
+
+fn validate_assignment(payload: &AssignmentPayload) -> Result<(), Error> {
+    require_not_empty("title", &payload.title)?;
+    require_max_size("title", &payload.title, MAX_NAME_SIZE)?;
+    if payload.max_points == 0 {
+        return Err(Error::InvalidInput {
+            field: "max_points".to_string(),
+            reason: "must be greater than 0".to_string(),
+        });
+    }
+    if let Some(due) = payload.due {
+        if !due.is_valid() {
+            return Err(Error::InvalidInput {
+                field: "due".to_string(),
+                reason: format!("{} is not a valid date", due),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_assignment(assignment_id: u64) -> Result<Assignment, Error> {
+    let assignment = get_assignment_record(assignment_id)?;
+    require_lesson_teacher(assignment.lesson_id, "read the grades of this lesson")?;
+    Ok(assignment)
+}
+
+#[ic_cdk::update]
+fn add_assignment(lesson_id: u64, payload: AssignmentPayload) -> Result<Assignment, Error> {
+    audited("add_assignment", &[], || {
+        get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "grade this lesson")?;
+        validate_assignment(&payload)?;
+        let id = ASSIGNMENT_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment id counter");
+        let assignment = Assignment {
+            id,
+            lesson_id,
+            title: payload.title,
+            category: payload.category,
+            max_points: payload.max_points,
+            due: payload.due,
+            scores: Vec::new(),
+        };
+        do_insert_assignment(&assignment);
+        Ok(assignment)
+    })
+}
+
+// change an assignment, keeping its scores; lowering max_points below a
+// score that was given is rejected
+#[ic_cdk::update]
+fn update_assignment(assignment_id: u64, payload: AssignmentPayload) -> Result<Assignment, Error> {
+    audited("update_assignment", &[record(Entity::Assignment, assignment_id)], || {
+        let mut assignment = get_assignment_record(assignment_id)?;
+        require_lesson_teacher(assignment.lesson_id, "grade this lesson")?;
+        validate_assignment(&payload)?;
+        if let Some(score) = assignment
+            .scores
+            .iter()
+            .find(|score| score.points > payload.max_points as f64)
+        {
+            return Err(Error::InvalidInput {
+                field: "max_points".to_string(),
+                reason: format!("student {} already scored {} points", score.student_id, score.points),
+            });
+        }
+        assignment.title = payload.title;
+        assignment.category = payload.category;
+        assignment.max_points = payload.max_points;
+        assignment.due = payload.due;
+        do_insert_assignment(&assignment);
+        Ok(assignment)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_assignment(assignment_id: u64) -> Result<Assignment, Error> {
+    audited("delete_assignment", &[record(Entity::Assignment, assignment_id)], || {
+        let assignment = get_assignment_record(assignment_id)?;
+        require_lesson_teacher(assignment.lesson_id, "grade this lesson")?;
+        remove_assignment(&assignment);
+        Ok(assignment)
+    })
+}
+
+// Score students enrolled in the lesson, between 0 and max_points. A score
+// of None removes the student's score.
+#[ic_cdk::update]
+fn set_scores(assignment_id: u64, scores: Vec<(u64, Option<f64>)>) -> Result<Assignment, Error> {
+    audited("set_scores", &[record(Entity::Assignment, assignment_id)], || {
+        let mut assignment = get_assignment_record(assignment_id)?;
+        require_lesson_teacher(assignment.lesson_id, "grade this lesson")?;
+        let lesson = get_lesson_record(assignment.lesson_id)?;
+        for (student_id, points) in scores {
+            assignment.scores.retain(|score| score.student_id != student_id);
+            let points = match points {
+                Some(points) => points,
+                None => continue,
+            };
+            if !lesson.students.contains(&student_id) {
+                return Err(Error::InvalidInput {
+                    field: "student_id".to_string(),
+                    reason: format!("student {} is not enrolled in lesson {}", student_id, lesson.id),
+                });
+            }
+            if !points.is_finite() || points < 0.0 || points > assignment.max_points as f64 {
+                return Err(Error::InvalidInput {
+                    field: "points".to_string(),
+                    reason: format!("must be between 0 and {}", assignment.max_points),
+                });
+            }
+            assignment.scores.push(Score { student_id, points });
+        }
+        do_insert_assignment(&assignment);
+        Ok(assignment)
+    })
+}
+
+#[ic_cdk::update]
+fn set_grade_weights(lesson_id: u64, homework: u32, quiz: u32, exam: u32) -> Result<GradeWeights, Error> {
+    audited("set_grade_weights", &[record(Entity::Lesson, lesson_id)], || {
+        get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "grade this lesson")?;
+        if homework == 0 && quiz == 0 && exam == 0 {
+            return Err(Error::InvalidInput {
+                field: "weights".to_string(),
+                reason: "at least one weight must be greater than 0".to_string(),
+            });
+        }
+        let weights = GradeWeights {
+            lesson_id,
+            homework,
+            quiz,
+            exam,
+        };
+        GRADE_WEIGHTS_MAP.with(|service| service.borrow_mut().insert(lesson_id, weights.clone()));
+        Ok(weights)
+    })
+}
+
+#[ic_cdk::query]
+fn get_lesson_grade_sheet(lesson_id: u64) -> Result<GradeSheet, Error> {
+    let lesson = get_lesson_record(lesson_id)?;
+    require_lesson_teacher(lesson_id, "read the grades of this lesson")?;
+    let weights = lesson_weights(lesson_id);
+    let assignments = lesson_assignments(lesson_id);
+    let grades = lesson
+        .students
+        .iter()
+        .map(|student_id| student_grade(*student_id, &assignments, &weights))
+        .collect();
+    Ok(GradeSheet {
+        lesson_id,
+        weights,
+        assignments,
+        grades,
+    })
+}
+
+// the student's scores and running grade in each lesson of Student.lessons
+#[ic_cdk::query]
+fn get_student_grades(student_id: u64) -> Result<Vec<LessonGrades>, Error> {
+    let (_, lessons) = student_lessons(student_id)?;
+    require_staff("read the grades of students").or_else(|_| require_student_reader(student_id, "read these grades"))?;
+    Ok(lessons
+        .into_iter()
+        .map(|lesson| {
+            let weights = lesson_weights(lesson.id);
+            let assignments = lesson_assignments(lesson.id);
+            let grade = student_grade(student_id, &assignments, &weights);
+            LessonGrades {
+                lesson_id: lesson.id,
+                title: lesson.title,
+                weights,
+                assignments: assignments
+                    .iter()
+                    .map(|assignment| AssignmentResult {
+                        assignment_id: assignment.id,
+                        title: assignment.title.clone(),
+                        category: assignment.category,
+                        max_points: assignment.max_points,
+                        due: assignment.due,
+                        points: assignment.score_of(student_id),
+                    })
+                    .collect(),
+                grade,
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,132 @@
+use crate::ics::{student_timetable, teacher_timetable};
+use crate::{get_all_lessons_for_student, get_all_lessons_for_teacher, get_lesson, get_student, get_teacher};
+use crate::{calendar, Error};
+use serde::Serialize;
+
+// Request and response of the HTTP gateway interface. Query responses are
+// not certified, so they are only served on the raw domain. The request's
+// headers and body are not needed and left out of the decoded record.
+#[derive(candid::CandidType, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+}
+
+#[derive(candid::CandidType, Serialize)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+const JSON: &str = "application/json; charset=utf-8";
+const ICS: &str = "text/calendar; charset=utf-8";
+
+fn response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+    }
+}
+
+fn json<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    response(status_code, JSON, serde_json::to_vec(value).unwrap())
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    error: &'a str,
+}
+
+fn not_found() -> HttpResponse {
+    json(404, &Message { error: "not found" })
+}
+
+// Errors keep the shape they have in Candid; missing records are a 404
+fn error(error: Error) -> HttpResponse {
+    let status_code = match error {
+        Error::NotFound { .. } => 404,
+        Error::Unauthorized { .. } => 403,
+        _ => 400,
+    };
+    json(status_code, &error)
+}
+
+fn json_result<T: Serialize>(result: Result<T, Error>) -> HttpResponse {
+    match result {
+        Ok(value) => json(200, &value),
+        Err(err) => error(err),
+    }
+}
+
+fn ics_result(result: Result<String, Error>) -> HttpResponse {
+    match result {
+        Ok(document) => response(200, ICS, document.into_bytes()),
+        Err(err) => error(err),
+    }
+}
+
+fn parse_id(segment: &str) -> Option<u64> {
+    segment.parse().ok()
+}
+
+// Route a GET request to the getter behind it
+fn route(path: &str) -> HttpResponse {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["lessons", file] => match file.strip_suffix(".json").and_then(parse_id) {
+            Some(id) => json_result(get_lesson(id)),
+            None => not_found(),
+        },
+        ["lessons", id, "sessions.json"] => match parse_id(id) {
+            Some(id) => json_result(calendar::lesson_sessions(id, None)),
+            None => not_found(),
+        },
+        ["teachers", file] => match file.strip_suffix(".json").and_then(parse_id) {
+            Some(id) => json_result(get_teacher(id)),
+            None => not_found(),
+        },
+        ["teachers", id, "lessons"] => match parse_id(id) {
+            Some(id) => json_result(get_all_lessons_for_teacher(id)),
+            None => not_found(),
+        },
+        ["teachers", id, "timetable.ics"] => match parse_id(id) {
+            Some(id) => ics_result(teacher_timetable(id)),
+            None => not_found(),
+        },
+        ["students", file] => match file.strip_suffix(".json").and_then(parse_id) {
+            Some(id) => json_result(get_student(id)),
+            None => not_found(),
+        },
+        ["students", id, "lessons"] => match parse_id(id) {
+            Some(id) => json_result(get_all_lessons_for_student(id)),
+            None => not_found(),
+        },
+        ["students", id, "timetable.ics"] => match parse_id(id) {
+            Some(id) => ics_result(student_timetable(id)),
+            None => not_found(),
+        },
+        _ => not_found(),
+    }
+}
+
+// Read-only HTTP access for browsers, scripts and calendar clients:
+//   /lessons/{id}.json, /lessons/{id}/sessions.json
+//   /teachers/{id}.json, /teachers/{id}/lessons, /teachers/{id}/timetable.ics
+//   /students/{id}.json, /students/{id}/lessons, /students/{id}/timetable.ics
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" && request.method != "HEAD" {
+        return json(405, &Message { error: "method not allowed" });
+    }
+    let path = request.url.split(['?', '#']).next().unwrap_or_default();
+    let mut response = route(path);
+    if request.method == "HEAD" {
+        response.body.clear();
+    }
+    response
+}
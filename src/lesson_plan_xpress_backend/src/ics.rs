@@ -0,0 +1,403 @@
+use crate::calendar::{get_term_record, holidays_between, Date};
+use crate::schedule::{school_settings, SchoolSettings, Weekday};
+use crate::{student_lessons, teacher_lessons, Error, Lesson, ScheduleEntry, TEACHER_MAP};
+
+// Lines longer than this many octets are folded (RFC 5545 section 3.1)
+const MAX_LINE_OCTETS: usize = 75;
+
+// Lessons without a term repeat for this many days from the first session
+const OPEN_ENDED_DAYS: i64 = 365;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+fn byday(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Monday => "MO",
+        Weekday::Tuesday => "TU",
+        Weekday::Wednesday => "WE",
+        Weekday::Thursday => "TH",
+        Weekday::Friday => "FR",
+        Weekday::Saturday => "SA",
+        Weekday::Sunday => "SU",
+    }
+}
+
+// escape a TEXT value (RFC 5545 section 3.3.11)
+fn escape_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Append a content line, folded into 75 octet pieces without splitting a
+// UTF-8 character. Continuation lines start with a space.
+fn push_line(document: &mut String, line: &str) {
+    let mut rest = line;
+    let mut limit = MAX_LINE_OCTETS;
+    while rest.len() > limit {
+        let mut end = limit;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        document.push_str(&rest[..end]);
+        document.push_str("\r\n ");
+        rest = &rest[end..];
+        // the leading space counts towards the next line
+        limit = MAX_LINE_OCTETS - 1;
+    }
+    document.push_str(rest);
+    document.push_str("\r\n");
+}
+
+// local wall clock time, "20240902T093000"
+fn format_local(date: Date, minute: u16) -> String {
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}00",
+        date.year,
+        date.month,
+        date.day,
+        minute / 60,
+        minute % 60
+    )
+}
+
+// UTC time from seconds since the epoch, "20240902T063000Z"
+fn format_utc(seconds: i64) -> String {
+    let date = Date::from_days(seconds.div_euclid(86_400));
+    let second_of_day = seconds.rem_euclid(86_400);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        date.year,
+        date.month,
+        date.day,
+        second_of_day / 3600,
+        second_of_day / 60 % 60,
+        second_of_day % 60
+    )
+}
+
+fn format_offset(minutes: i16) -> String {
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let minutes = minutes.unsigned_abs();
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+// the first day on or after `date` that falls on `day`
+fn first_weekday_from(date: Date, day: Weekday) -> Date {
+    let position = |day: Weekday| Weekday::ALL.iter().position(|other| *other == day).unwrap() as i64;
+    let ahead = (position(day) - position(date.weekday())).rem_euclid(7);
+    Date::from_days(date.to_days() + ahead)
+}
+
+// The school's zone with its fixed offset. Daylight saving rules are not
+// known to the canister, so the zone has a single STANDARD observance.
+fn push_timezone(document: &mut String, settings: &SchoolSettings) {
+    let offset = format_offset(settings.utc_offset_minutes);
+    push_line(document, "BEGIN:VTIMEZONE");
+    push_line(document, &format!("TZID:{}", settings.time_zone));
+    push_line(document, "BEGIN:STANDARD");
+    push_line(document, "DTSTART:19700101T000000");
+    push_line(document, &format!("TZOFFSETFROM:{}", offset));
+    push_line(document, &format!("TZOFFSETTO:{}", offset));
+    push_line(document, &format!("TZNAME:{}", escape_text(&settings.time_zone)));
+    push_line(document, "END:STANDARD");
+    push_line(document, "END:VTIMEZONE");
+}
+
+// one recurring event for a weekly slot of a lesson
+fn push_event(document: &mut String, settings: &SchoolSettings, lesson: &Lesson, slot: &ScheduleEntry, now: i64) {
+    let today = Date::from_days(now.div_euclid(86_400));
+    let term = lesson.term_id.and_then(|term_id| get_term_record(term_id).ok());
+    let (first_day, last_day) = match &term {
+        Some(term) => (term.start, term.end),
+        None => (today, Date::from_days(today.to_days() + OPEN_ENDED_DAYS)),
+    };
+    let first = first_weekday_from(first_day, slot.day);
+    if first > last_day {
+        return;
+    }
+    // UNTIL is given in UTC for the end of the last local day
+    let until = (last_day.to_days() + 1) * 86_400 - settings.utc_offset_minutes as i64 * 60 - 1;
+    let teacher_name = TEACHER_MAP
+        .with(|service| service.borrow().get(&lesson.teacher_id))
+        .map(|teacher| teacher.name)
+        .unwrap_or_default();
+    let tzid = format!("TZID={}", settings.time_zone);
+
+    push_line(document, "BEGIN:VEVENT");
+    push_line(document, &format!("UID:lesson-{}-slot-{}@lesson_plan_xpress", lesson.id, slot.id));
+    push_line(document, &format!("DTSTAMP:{}", format_utc(now)));
+    push_line(document, &format!("DTSTART;{}:{}", tzid, format_local(first, slot.start_minute)));
+    push_line(document, &format!("DURATION:PT{}M", slot.end_minute - slot.start_minute));
+    push_line(
+        document,
+        &format!("RRULE:FREQ=WEEKLY;BYDAY={};UNTIL={}", byday(slot.day), format_utc(until)),
+    );
+    for holiday in holidays_between(first, last_day) {
+        let mut day = first_weekday_from(holiday.start.max(first), slot.day);
+        while day <= holiday.end && day <= last_day {
+            push_line(document, &format!("EXDATE;{}:{}", tzid, format_local(day, slot.start_minute)));
+            day = Date::from_days(day.to_days() + 7);
+        }
+    }
+    push_line(
+        document,
+        &format!("SUMMARY:{}", escape_text(&format!("{} ({})", lesson.title, lesson.subject))),
+    );
+    push_line(
+        document,
+        &format!(
+            "DESCRIPTION:{}",
+            escape_text(&format!(
+                "Subject: {}\nTeacher: {}\nGrade level: {}\n\n{}",
+                lesson.subject, teacher_name, lesson.grade_level, lesson.description
+            ))
+        ),
+    );
+    push_line(document, &format!("CATEGORIES:{}", escape_text(&lesson.subject)));
+    push_line(document, "END:VEVENT");
+}
+
+// An iCalendar document with a weekly recurring event per schedule entry
+pub(crate) fn timetable(name: &str, lessons: &[Lesson]) -> String {
+    let settings = school_settings();
+    let now = (ic_cdk::api::time() / NANOS_PER_SECOND) as i64;
+    let mut document = String::new();
+    push_line(&mut document, "BEGIN:VCALENDAR");
+    push_line(&mut document, "VERSION:2.0");
+    push_line(&mut document, "PRODID:-//lesson_plan_xpress//Timetable//EN");
+    push_line(&mut document, "CALSCALE:GREGORIAN");
+    push_line(&mut document, "METHOD:PUBLISH");
+    push_line(&mut document, &format!("X-WR-CALNAME:{}", escape_text(name)));
+    push_line(&mut document, &format!("X-WR-TIMEZONE:{}", settings.time_zone));
+    push_timezone(&mut document, &settings);
+    for lesson in lessons {
+        for slot in &lesson.schedule {
+            push_event(&mut document, &settings, lesson, slot, now);
+        }
+    }
+    push_line(&mut document, "END:VCALENDAR");
+    document
+}
+
+pub(crate) fn teacher_timetable(teacher_id: u64) -> Result<String, Error> {
+    let (teacher, lessons) = teacher_lessons(teacher_id)?;
+    Ok(timetable(&format!("{} timetable", teacher.name), &lessons))
+}
+
+pub(crate) fn student_timetable(student_id: u64) -> Result<String, Error> {
+    let (student, lessons) = student_lessons(student_id)?;
+    Ok(timetable(&format!("{} timetable", student.name), &lessons))
+}
+
+// the lessons of get_all_lessons_for_teacher as an .ics document
+#[ic_cdk::query]
+fn get_teacher_timetable_ics(teacher_id: u64) -> Result<String, Error> {
+    teacher_timetable(teacher_id)
+}
+
+// the lessons of get_all_lessons_for_student as an .ics document
+#[ic_cdk::query]
+fn get_student_timetable_ics(student_id: u64) -> Result<String, Error> {
+    student_timetable(student_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::{Holiday, Term, HOLIDAY_MAP, TERM_MAP};
+    use crate::Teacher;
+
+    fn date(year: u16, month: u8, day: u8) -> Date {
+        Date { year, month, day }
+    }
+
+    fn document(lines: &[&str]) -> String {
+        lines.iter().map(|line| format!("{}\r\n", line)).collect()
+    }
+
+    fn folded(line: &str) -> String {
+        let mut document = String::new();
+        push_line(&mut document, line);
+        document
+    }
+
+    fn nairobi() -> SchoolSettings {
+        SchoolSettings {
+            time_zone: "Africa/Nairobi".to_string(),
+            utc_offset_minutes: 180,
+        }
+    }
+
+    fn lesson(term_id: Option<u64>) -> Lesson {
+        Lesson {
+            id: 3,
+            title: "Fractions, part 1".to_string(),
+            description: "Adding; simple\\cases".to_string(),
+            grade_level: "3".to_string(),
+            subject: "Math".to_string(),
+            teacher_id: 1,
+            students: Vec::new(),
+            schedule: Vec::new(),
+            plan: Default::default(),
+            cloned_from: None,
+            term_id,
+            capacity: None,
+            waitlist: Vec::new(),
+        }
+    }
+
+    fn wednesday_slot() -> ScheduleEntry {
+        ScheduleEntry {
+            id: 10,
+            day: Weekday::Wednesday,
+            start_minute: 9 * 60,
+            end_minute: 9 * 60 + 45,
+        }
+    }
+
+    // 2024-09-01T12:00:00Z
+    fn now() -> i64 {
+        date(2024, 9, 1).to_days() * 86_400 + 12 * 3600
+    }
+
+    fn store_calendar() {
+        let term = Term {
+            id: 1,
+            name: "Autumn".to_string(),
+            start: date(2024, 9, 2),
+            end: date(2024, 9, 30),
+        };
+        TERM_MAP.with(|service| service.borrow_mut().insert(term.id, term));
+        let holiday = Holiday {
+            id: 1,
+            name: "Autumn break".to_string(),
+            start: date(2024, 9, 9),
+            end: date(2024, 9, 13),
+        };
+        HOLIDAY_MAP.with(|service| service.borrow_mut().insert(holiday.id, holiday));
+        let teacher = Teacher {
+            id: 1,
+            name: "Ada".to_string(),
+            subject: "Math".to_string(),
+            lessons: vec![3],
+            availability: Vec::new(),
+        };
+        TEACHER_MAP.with(|service| service.borrow_mut().insert(teacher.id, teacher));
+    }
+
+    #[test]
+    fn text_values_escape_separators_and_line_breaks() {
+        assert_eq!(escape_text("plain 12:00 text"), "plain 12:00 text");
+        assert_eq!(escape_text("a\\b;c,d\r\ne"), "a\\\\b\\;c\\,d\\ne");
+    }
+
+    #[test]
+    fn short_lines_are_not_folded() {
+        let line = "a".repeat(MAX_LINE_OCTETS);
+        assert_eq!(folded(&line), format!("{}\r\n", line));
+    }
+
+    #[test]
+    fn long_lines_fold_at_75_octets() {
+        let line = "a".repeat(200);
+        // continuation lines hold 74 octets after their leading space
+        assert_eq!(
+            folded(&line),
+            format!("{}\r\n {}\r\n {}\r\n", "a".repeat(75), "a".repeat(74), "a".repeat(51))
+        );
+    }
+
+    #[test]
+    fn folding_keeps_utf8_characters_whole() {
+        // "é" takes octets 75 and 76, so the first line stops before it
+        let line = format!("{}éb", "a".repeat(74));
+        assert_eq!(folded(&line), format!("{}\r\n éb\r\n", "a".repeat(74)));
+
+        let line = "€".repeat(100);
+        let document = folded(&line);
+        for physical in document.split("\r\n").filter(|physical| !physical.is_empty()) {
+            assert!(physical.len() <= MAX_LINE_OCTETS, "{} octets", physical.len());
+        }
+        assert_eq!(document.replace("\r\n ", ""), format!("{}\r\n", line));
+    }
+
+    #[test]
+    fn timezone_has_a_fixed_offset() {
+        let mut output = String::new();
+        push_timezone(&mut output, &nairobi());
+        assert_eq!(
+            output,
+            document(&[
+                "BEGIN:VTIMEZONE",
+                "TZID:Africa/Nairobi",
+                "BEGIN:STANDARD",
+                "DTSTART:19700101T000000",
+                "TZOFFSETFROM:+0300",
+                "TZOFFSETTO:+0300",
+                "TZNAME:Africa/Nairobi",
+                "END:STANDARD",
+                "END:VTIMEZONE",
+            ])
+        );
+        assert_eq!(format_offset(-330), "-0530");
+    }
+
+    #[test]
+    fn events_repeat_weekly_over_the_term_without_holidays() {
+        store_calendar();
+        let mut output = String::new();
+        push_event(&mut output, &nairobi(), &lesson(Some(1)), &wednesday_slot(), now());
+        assert_eq!(
+            output,
+            document(&[
+                "BEGIN:VEVENT",
+                "UID:lesson-3-slot-10@lesson_plan_xpress",
+                "DTSTAMP:20240901T120000Z",
+                "DTSTART;TZID=Africa/Nairobi:20240904T090000",
+                "DURATION:PT45M",
+                // midnight after the last day in Nairobi, one second earlier
+                "RRULE:FREQ=WEEKLY;BYDAY=WE;UNTIL=20240930T205959Z",
+                "EXDATE;TZID=Africa/Nairobi:20240911T090000",
+                "SUMMARY:Fractions\\, part 1 (Math)",
+                "DESCRIPTION:Subject: Math\\nTeacher: Ada\\nGrade level: 3\\n\\nAdding\\; simple\\",
+                " \\cases",
+                "CATEGORIES:Math",
+                "END:VEVENT",
+            ])
+        );
+    }
+
+    #[test]
+    fn events_without_a_term_repeat_for_a_year_from_today() {
+        store_calendar();
+        let mut output = String::new();
+        push_event(&mut output, &SchoolSettings::default(), &lesson(None), &wednesday_slot(), now());
+        assert!(output.contains("\r\nDTSTART;TZID=UTC:20240904T090000\r\n"));
+        assert!(output.contains("\r\nRRULE:FREQ=WEEKLY;BYDAY=WE;UNTIL=20250901T235959Z\r\n"));
+        assert!(output.contains("\r\nEXDATE;TZID=UTC:20240911T090000\r\n"));
+    }
+
+    #[test]
+    fn slots_on_no_day_of_the_term_have_no_event() {
+        let term = Term {
+            id: 2,
+            name: "Exam day".to_string(),
+            start: date(2024, 9, 2),
+            end: date(2024, 9, 2),
+        };
+        TERM_MAP.with(|service| service.borrow_mut().insert(term.id, term));
+        let mut output = String::new();
+        push_event(&mut output, &nairobi(), &lesson(Some(2)), &wednesday_slot(), now());
+        assert!(output.is_empty());
+    }
+}
@@ -0,0 +1,832 @@
+use crate::audit::{audited, record};
+use crate::auth::{require_admin, require_staff, require_teacher, StorablePrincipal};
+use crate::{create_lesson, create_student, create_teacher, validate_new_lesson, validate_new_student, validate_new_teacher};
+use crate::{Entity, Error, IdCell, LessonPayload, Memory, StudentPayload, TeacherPayload, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::{BTreeMap, HashMap};
+use std::{borrow::Cow, cell::RefCell};
+
+// Most data rows one import may hold. The rows are checked over as many
+// calls as the instruction budget requires.
+const MAX_IMPORT_ROWS: usize = 50_000;
+
+// Most data rows a Commit may hold. They are all stored in one call, so
+// that either every row or none of them is kept.
+const MAX_COMMIT_ROWS: usize = 10_000;
+
+// Instructions a call may spend on rows before it returns and waits to be
+// continued, well below the limit of an update call
+const INSTRUCTION_BUDGET: u64 = 5_000_000_000;
+
+// Largest file that can be assembled from uploaded chunks
+const MAX_UPLOAD_SIZE: u64 = 16 * 1024 * 1024;
+
+// Nanoseconds an upload is kept after its last chunk was appended
+const UPLOAD_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Most expired uploads one create_upload call removes
+const MAX_EXPIRED_PER_CALL: usize = 10;
+
+// Size of an upload so far, kept so appending does not read earlier chunks
+#[derive(candid::CandidType, Deserialize)]
+struct UploadInfo {
+    size: u64,
+    chunks: u64,
+    // nanoseconds since the epoch
+    expires_at: u64,
+}
+
+impl Storable for UploadInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static UPLOAD_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))), 0)
+            .expect("Cannot create a counter")
+    );
+    // principal that created each upload
+    static UPLOAD_OWNER_MAP: RefCell<StableBTreeMap<u64, StorablePrincipal, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
+    // (upload_id, chunk index) -> bytes, in the order they were appended
+    static UPLOAD_CHUNK_MAP: RefCell<StableBTreeMap<(u64, u64), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))))
+    );
+    // uploads without an entry predate expiry and count as expired
+    static UPLOAD_INFO_MAP: RefCell<StableBTreeMap<u64, UploadInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))))
+    );
+
+    // the last import of each principal; lost on upgrade like any heap state,
+    // which never leaves a Commit half stored since it stores in one call
+    static IMPORT_JOBS: RefCell<BTreeMap<Principal, ImportJob>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Where the CSV text of an import comes from
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum CsvSource {
+    Text(String),
+    // a file assembled with create_upload and append_upload_chunk
+    Upload(u64),
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ImportMode {
+    // validate every row and report the errors without storing anything
+    DryRun,
+    // store all rows, or none of them if any row is invalid
+    Commit,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ImportStatus {
+    // rows are being checked; call continue_import for more
+    Validating,
+    // every row passed; the next call stores them all at once
+    Creating,
+    Finished,
+}
+
+// A row that failed validation; `line` is its line number in the file
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RowError {
+    line: u64,
+    error: Error,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportReport {
+    status: ImportStatus,
+    // data rows read, not counting the header
+    rows: u64,
+    // rows done in the current status
+    processed: u64,
+    errors: Vec<RowError>,
+    // ids of the created records, in row order
+    created_ids: Vec<u64>,
+    committed: bool,
+}
+
+impl ImportReport {
+    // "finished, 12 rows, 0 errors, 12 created"
+    pub(crate) fn describe(&self) -> String {
+        let status = match self.status {
+            ImportStatus::Validating => "validating",
+            ImportStatus::Creating => "creating",
+            ImportStatus::Finished => "finished",
+        };
+        format!(
+            "{}, {} rows, {} errors, {} created",
+            status,
+            self.rows,
+            self.errors.len(),
+            self.created_ids.len()
+        )
+    }
+}
+
+fn csv_error(reason: String) -> Error {
+    Error::InvalidInput {
+        field: "csv".to_string(),
+        reason,
+    }
+}
+
+// Split CSV text into records of fields (RFC 4180): fields are separated by
+// commas, may be quoted with ", and a quoted field may contain commas, line
+// breaks and "" for a quote. Blank lines are skipped. Each record comes with
+// the line it starts on.
+fn parse_csv(text: &str) -> Result<Vec<(u64, Vec<String>)>, Error> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                '\n' => {
+                    line += 1;
+                    field.push(c);
+                }
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => fields.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                fields.push(std::mem::take(&mut field));
+                let record = std::mem::take(&mut fields);
+                if !(record.len() == 1 && record[0].trim().is_empty()) {
+                    records.push((record_line, record));
+                }
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(csv_error(format!("quoted field starting on line {} is not closed", record_line)));
+    }
+    fields.push(field);
+    if !(fields.len() == 1 && fields[0].trim().is_empty()) {
+        records.push((record_line, fields));
+    }
+    Ok(records)
+}
+
+// A data row with its fields looked up by header name
+struct Row<'a> {
+    columns: &'a HashMap<String, usize>,
+    fields: &'a [String],
+}
+
+impl Row<'_> {
+    fn get(&self, column: &str) -> Option<&str> {
+        self.columns.get(column).map(|index| self.fields[*index].trim())
+    }
+
+    // required columns are checked once against the header
+    fn text(&self, column: &str) -> String {
+        self.get(column).unwrap_or_default().to_string()
+    }
+
+    fn id(&self, column: &str) -> Result<Option<u64>, Error> {
+        match self.get(column) {
+            None | Some("") => Ok(None),
+            Some(value) => value.parse().map(Some).map_err(|_| Error::InvalidInput {
+                field: column.to_string(),
+                reason: format!("{:?} is not a number", value),
+            }),
+        }
+    }
+}
+
+fn upload_owner(upload_id: u64) -> Result<StorablePrincipal, Error> {
+    let owner = UPLOAD_OWNER_MAP.with(|service| service.borrow().get(&upload_id));
+    owner.ok_or(Error::NotFound { entity: Entity::Upload, id: upload_id })
+}
+
+fn upload_expired(upload_id: u64, now: u64) -> bool {
+    UPLOAD_INFO_MAP
+        .with(|service| service.borrow().get(&upload_id))
+        .is_none_or(|info| info.expires_at <= now)
+}
+
+// Only the principal that created an upload, or an admin, may use it. An
+// expired upload is removed and reported as not found.
+fn require_upload_owner(upload_id: u64) -> Result<(), Error> {
+    let owner = upload_owner(upload_id)?;
+    if owner.0 != ic_cdk::caller() {
+        require_admin("use uploads of other principals")?;
+    }
+    if upload_expired(upload_id, ic_cdk::api::time()) {
+        remove_upload(upload_id);
+        return Err(Error::NotFound { entity: Entity::Upload, id: upload_id });
+    }
+    Ok(())
+}
+
+// "owner aaaaa-aa"
+pub(crate) fn describe_upload(upload_id: u64) -> Option<String> {
+    let owner = upload_owner(upload_id).ok()?;
+    Some(format!("owner {}", owner.0))
+}
+
+fn upload_chunks(upload_id: u64) -> Vec<Vec<u8>> {
+    UPLOAD_CHUNK_MAP.with(|service| {
+        service
+            .borrow()
+            .range((upload_id, 0)..=(upload_id, u64::MAX))
+            .map(|(_, chunk)| chunk)
+            .collect()
+    })
+}
+
+fn remove_upload(upload_id: u64) {
+    UPLOAD_CHUNK_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = map
+            .range((upload_id, 0)..=(upload_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            map.remove(&key);
+        }
+    });
+    UPLOAD_INFO_MAP.with(|service| service.borrow_mut().remove(&upload_id));
+    UPLOAD_OWNER_MAP.with(|service| service.borrow_mut().remove(&upload_id));
+}
+
+// drop a few uploads that were left to expire
+fn remove_expired_uploads(now: u64) {
+    let expired: Vec<u64> = UPLOAD_OWNER_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| upload_expired(*id, now))
+            .take(MAX_EXPIRED_PER_CALL)
+            .collect()
+    });
+    for upload_id in expired {
+        remove_upload(upload_id);
+    }
+}
+
+fn source_text(source: &CsvSource) -> Result<String, Error> {
+    match source {
+        CsvSource::Text(text) => Ok(text.clone()),
+        CsvSource::Upload(upload_id) => {
+            require_upload_owner(*upload_id)?;
+            String::from_utf8(upload_chunks(*upload_id).concat()).map_err(|_| Error::InvalidInput {
+                field: "upload".to_string(),
+                reason: "is not valid UTF-8 text".to_string(),
+            })
+        }
+    }
+}
+
+// Start a chunked upload for a CSV file too large for a single message. It
+// expires a day after its last chunk unless an import consumes it first.
+#[ic_cdk::update]
+fn create_upload() -> Result<u64, Error> {
+    require_staff("upload files")?;
+    let id = UPLOAD_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    audited("create_upload", &[record(Entity::Upload, id)], || {
+        let now = ic_cdk::api::time();
+        remove_expired_uploads(now);
+        UPLOAD_OWNER_MAP.with(|service| service.borrow_mut().insert(id, StorablePrincipal(ic_cdk::caller())));
+        let info = UploadInfo {
+            size: 0,
+            chunks: 0,
+            expires_at: now + UPLOAD_TTL,
+        };
+        UPLOAD_INFO_MAP.with(|service| service.borrow_mut().insert(id, info));
+        Ok(id)
+    })
+}
+
+// add the next piece of the file, returns the size uploaded so far
+#[ic_cdk::update]
+fn append_upload_chunk(upload_id: u64, chunk: Vec<u8>) -> Result<u64, Error> {
+    audited("append_upload_chunk", &[record(Entity::Upload, upload_id)], || {
+        require_upload_owner(upload_id)?;
+        let mut info = UPLOAD_INFO_MAP
+            .with(|service| service.borrow().get(&upload_id))
+            .ok_or(Error::NotFound { entity: Entity::Upload, id: upload_id })?;
+        let size = info.size + chunk.len() as u64;
+        if size > MAX_UPLOAD_SIZE {
+            return Err(Error::PayloadTooLarge {
+                field: "upload".to_string(),
+                size,
+                max_size: MAX_UPLOAD_SIZE,
+            });
+        }
+        UPLOAD_CHUNK_MAP.with(|service| service.borrow_mut().insert((upload_id, info.chunks), chunk));
+        info.size = size;
+        info.chunks += 1;
+        info.expires_at = ic_cdk::api::time() + UPLOAD_TTL;
+        UPLOAD_INFO_MAP.with(|service| service.borrow_mut().insert(upload_id, info));
+        Ok(size)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_upload(upload_id: u64) -> Result<(), Error> {
+    audited("delete_upload", &[record(Entity::Upload, upload_id)], || {
+        require_upload_owner(upload_id)?;
+        remove_upload(upload_id);
+        Ok(())
+    })
+}
+
+// The records an import creates
+#[derive(Clone, Copy)]
+enum ImportKind {
+    Students,
+    Teachers,
+    Lessons,
+}
+
+// columns: name, grade_level
+fn parse_student(row: &Row) -> Result<StudentPayload, Error> {
+    Ok(StudentPayload {
+        name: row.text("name"),
+        grade_level: row.text("grade_level"),
+    })
+}
+
+// columns: name, subject
+fn parse_teacher(row: &Row) -> Result<TeacherPayload, Error> {
+    Ok(TeacherPayload {
+        name: row.text("name"),
+        subject: row.text("subject"),
+    })
+}
+
+// columns: title, description, grade_level, subject, teacher_id and an
+// optional term_id
+fn parse_lesson(row: &Row) -> Result<LessonPayload, Error> {
+    let teacher_id = row.id("teacher_id")?.ok_or_else(|| Error::InvalidInput {
+        field: "teacher_id".to_string(),
+        reason: "must not be empty".to_string(),
+    })?;
+    Ok(LessonPayload {
+        title: row.text("title"),
+        description: row.text("description"),
+        grade_level: row.text("grade_level"),
+        subject: row.text("subject"),
+        teacher_id,
+        plan: None,
+        term_id: row.id("term_id")?,
+    })
+}
+
+// teachers may only import their own lessons
+fn check_lesson(payload: &LessonPayload) -> Result<(), Error> {
+    require_teacher(payload.teacher_id, "import lessons for this teacher")?;
+    validate_new_lesson(payload)
+}
+
+// Parse one row and check it with the same rules as the matching add_*
+// endpoint; with `create` also store it and return the new id
+fn process_row<P>(
+    row: &Row,
+    parse: fn(&Row) -> Result<P, Error>,
+    check: fn(&P) -> Result<(), Error>,
+    create: Option<fn(P) -> Result<u64, Error>>,
+) -> Result<Option<u64>, Error> {
+    let payload = parse(row)?;
+    check(&payload)?;
+    match create {
+        Some(create) => create(payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+impl ImportKind {
+    fn required(self) -> &'static [&'static str] {
+        match self {
+            ImportKind::Students => &["name", "grade_level"],
+            ImportKind::Teachers => &["name", "subject"],
+            ImportKind::Lessons => &["title", "description", "grade_level", "subject", "teacher_id"],
+        }
+    }
+
+    // the role needed to start or continue an import of this kind
+    fn require_role(self) -> Result<(), Error> {
+        match self {
+            ImportKind::Students => require_admin("import students"),
+            ImportKind::Teachers => require_admin("import teachers"),
+            ImportKind::Lessons => require_staff("import lessons"),
+        }
+    }
+
+    fn process(self, row: &Row, create: bool) -> Result<Option<u64>, Error> {
+        match self {
+            ImportKind::Students => process_row(
+                row,
+                parse_student,
+                validate_new_student,
+                create.then_some(|payload| Ok(create_student(payload).id)),
+            ),
+            ImportKind::Teachers => process_row(
+                row,
+                parse_teacher,
+                validate_new_teacher,
+                create.then_some(|payload| Ok(create_teacher(payload).id)),
+            ),
+            ImportKind::Lessons => process_row(
+                row,
+                parse_lesson,
+                check_lesson,
+                create.then_some(|payload| Ok(create_lesson(payload)?.id)),
+            ),
+        }
+    }
+}
+
+// An import in progress. Every row is checked first, over as many calls as
+// needed. A Commit without errors then checks every row again and stores
+// them all in a single call, since records may have changed in between.
+struct ImportJob {
+    kind: ImportKind,
+    mode: ImportMode,
+    source: CsvSource,
+    columns: HashMap<String, usize>,
+    header_len: usize,
+    records: Vec<(u64, Vec<String>)>,
+    status: ImportStatus,
+    // index in `records` of the next row to check
+    next: usize,
+    errors: Vec<RowError>,
+    created_ids: Vec<u64>,
+    committed: bool,
+}
+
+impl ImportJob {
+    fn new(kind: ImportKind, source: CsvSource, mode: ImportMode) -> Result<ImportJob, Error> {
+        let text = source_text(&source)?;
+        let mut records = parse_csv(&text)?.into_iter();
+        let (_, header) = records.next().ok_or_else(|| csv_error("has no header row".to_string()))?;
+        let columns: HashMap<String, usize> = header
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.trim().to_lowercase(), index))
+            .collect();
+        for column in kind.required() {
+            if !columns.contains_key(*column) {
+                return Err(csv_error(format!("has no {:?} column", column)));
+            }
+        }
+        let records: Vec<(u64, Vec<String>)> = records.collect();
+        let max_rows = match mode {
+            ImportMode::DryRun => MAX_IMPORT_ROWS,
+            ImportMode::Commit => MAX_COMMIT_ROWS,
+        };
+        if records.len() > max_rows {
+            return Err(csv_error(format!(
+                "has {} rows, at most {} can be imported at once",
+                records.len(),
+                max_rows
+            )));
+        }
+        Ok(ImportJob {
+            kind,
+            mode,
+            source,
+            columns,
+            header_len: header.len(),
+            records,
+            status: ImportStatus::Validating,
+            next: 0,
+            errors: Vec::new(),
+            created_ids: Vec::new(),
+            committed: false,
+        })
+    }
+
+    // check the row at `index`, with `create` also store it
+    fn process_record(&self, index: usize, create: bool) -> Result<Option<u64>, RowError> {
+        let (line, fields) = &self.records[index];
+        let result = if fields.len() != self.header_len {
+            Err(csv_error(format!("expected {} fields, found {}", self.header_len, fields.len())))
+        } else {
+            let row = Row { columns: &self.columns, fields };
+            self.kind.process(&row, create)
+        };
+        result.map_err(|error| RowError { line: *line, error })
+    }
+
+    // check the next row, or move on once all of them are checked
+    fn step(&mut self) {
+        match self.status {
+            ImportStatus::Validating if self.next < self.records.len() => {
+                if let Err(error) = self.process_record(self.next, false) {
+                    self.errors.push(error);
+                }
+                self.next += 1;
+            }
+            ImportStatus::Validating => {
+                self.status = if self.mode == ImportMode::Commit && self.errors.is_empty() {
+                    ImportStatus::Creating
+                } else {
+                    ImportStatus::Finished
+                };
+            }
+            ImportStatus::Creating => self.commit(),
+            ImportStatus::Finished => {}
+        }
+    }
+
+    // Store every row in this call. They are all checked again first, and
+    // if one no longer passes nothing is stored.
+    fn commit(&mut self) {
+        self.status = ImportStatus::Finished;
+        self.errors = (0..self.records.len())
+            .filter_map(|index| self.process_record(index, false).err())
+            .collect();
+        if !self.errors.is_empty() {
+            return;
+        }
+        for index in 0..self.records.len() {
+            match self.process_record(index, true) {
+                Ok(Some(id)) => self.created_ids.push(id),
+                Ok(None) => {}
+                // trapping discards the rows stored so far in this call
+                Err(error) => ic_cdk::trap(&format!(
+                    "row on line {} failed to store after passing its checks",
+                    error.line
+                )),
+            }
+        }
+        self.committed = true;
+        if let CsvSource::Upload(upload_id) = self.source {
+            remove_upload(upload_id);
+        }
+    }
+
+    // Work through rows until done or out of instructions for this call. A
+    // Commit is only stored by a call that starts within the budget.
+    fn run(&mut self) {
+        while self.status != ImportStatus::Finished {
+            if ic_cdk::api::instruction_counter() >= INSTRUCTION_BUDGET {
+                return;
+            }
+            self.step();
+        }
+    }
+
+    fn report(&self) -> ImportReport {
+        ImportReport {
+            status: self.status,
+            rows: self.records.len() as u64,
+            processed: self.next as u64,
+            errors: self.errors.clone(),
+            created_ids: self.created_ids.clone(),
+            committed: self.committed,
+        }
+    }
+}
+
+fn no_import() -> Error {
+    Error::Conflict {
+        reason: "no import, call one of the import_* methods first".to_string(),
+    }
+}
+
+// Start an import for the caller and work on it as long as the call's
+// instruction budget allows; continue_import resumes it.
+fn start_import(kind: ImportKind, source: CsvSource, mode: ImportMode) -> Result<ImportReport, Error> {
+    kind.require_role()?;
+    let caller = ic_cdk::caller();
+    let running = IMPORT_JOBS.with(|jobs| {
+        jobs.borrow().get(&caller).is_some_and(|job| job.status != ImportStatus::Finished)
+    });
+    if running {
+        return Err(Error::Conflict {
+            reason: "an import is still running, continue or cancel it first".to_string(),
+        });
+    }
+    let mut job = ImportJob::new(kind, source, mode)?;
+    job.run();
+    let report = job.report();
+    IMPORT_JOBS.with(|jobs| jobs.borrow_mut().insert(caller, job));
+    Ok(report)
+}
+
+#[ic_cdk::update]
+fn import_students(source: CsvSource, mode: ImportMode) -> Result<ImportReport, Error> {
+    audited("import_students", &[], || {
+        start_import(ImportKind::Students, source, mode)
+    })
+}
+
+#[ic_cdk::update]
+fn import_teachers(source: CsvSource, mode: ImportMode) -> Result<ImportReport, Error> {
+    audited("import_teachers", &[], || {
+        start_import(ImportKind::Teachers, source, mode)
+    })
+}
+
+#[ic_cdk::update]
+fn import_lessons(source: CsvSource, mode: ImportMode) -> Result<ImportReport, Error> {
+    audited("import_lessons", &[], || {
+        start_import(ImportKind::Lessons, source, mode)
+    })
+}
+
+// work on the caller's import where the last call stopped; a finished
+// import reports its result
+#[ic_cdk::update]
+fn continue_import() -> Result<ImportReport, Error> {
+    audited("continue_import", &[], || {
+        IMPORT_JOBS.with(|jobs| {
+            let mut jobs = jobs.borrow_mut();
+            let job = jobs.get_mut(&ic_cdk::caller()).ok_or_else(no_import)?;
+            // the caller's role may have been revoked since the import started
+            job.kind.require_role()?;
+            job.run();
+            Ok(job.report())
+        })
+    })
+}
+
+// Drop the caller's import. A Commit stores its rows in one call, so an
+// import that is not finished has stored nothing.
+#[ic_cdk::update]
+fn cancel_import() -> Result<(), Error> {
+    audited("cancel_import", &[], || {
+        IMPORT_JOBS.with(|jobs| jobs.borrow_mut().remove(&ic_cdk::caller())).ok_or_else(no_import)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::STUDENT_MAP;
+
+    fn records(text: &str) -> Vec<(u64, Vec<String>)> {
+        match parse_csv(text) {
+            Ok(records) => records,
+            Err(_) => panic!("{:?} did not parse", text),
+        }
+    }
+
+    fn csv_record(line: u64, fields: &[&str]) -> (u64, Vec<String>) {
+        (line, fields.iter().map(|field| field.to_string()).collect())
+    }
+
+    fn job(text: &str, mode: ImportMode) -> Result<ImportJob, Error> {
+        ImportJob::new(ImportKind::Students, CsvSource::Text(text.to_string()), mode)
+    }
+
+    fn finish(mut job: ImportJob) -> ImportReport {
+        while job.status != ImportStatus::Finished {
+            job.step();
+        }
+        job.report()
+    }
+
+    fn csv_reason(error: Option<Error>) -> String {
+        match error {
+            Some(Error::InvalidInput { field, reason }) if field == "csv" => reason,
+            _ => panic!("expected a csv error"),
+        }
+    }
+
+    #[test]
+    fn fields_split_on_commas_outside_quotes() {
+        assert_eq!(
+            records("name,grade_level\nAda,3\n\"Lovelace, Ada\",\"4\"\n"),
+            vec![
+                csv_record(1, &["name", "grade_level"]),
+                csv_record(2, &["Ada", "3"]),
+                csv_record(3, &["Lovelace, Ada", "4"]),
+            ]
+        );
+        assert_eq!(records("a,,c"), vec![csv_record(1, &["a", "", "c"])]);
+    }
+
+    #[test]
+    fn doubled_quotes_inside_quotes_are_one_quote() {
+        assert_eq!(
+            records("\"say \"\"hi\"\"\",\"\"\"\""),
+            vec![csv_record(1, &["say \"hi\"", "\""])]
+        );
+    }
+
+    #[test]
+    fn crlf_and_cr_end_records() {
+        assert_eq!(
+            records("a,b\r\nc,d\r\ne,f\rg,h\r\n"),
+            vec![csv_record(1, &["a", "b"]), csv_record(2, &["c", "d"]), csv_record(3, &["e", "f"]), csv_record(4, &["g", "h"])]
+        );
+    }
+
+    #[test]
+    fn quoted_line_breaks_stay_in_the_field() {
+        // records keep the line they start on
+        assert_eq!(
+            records("a,b\n\"first\nsecond\",z\nc,d"),
+            vec![csv_record(1, &["a", "b"]), csv_record(2, &["first\nsecond", "z"]), csv_record(4, &["c", "d"])]
+        );
+    }
+
+    #[test]
+    fn blank_lines_and_byte_order_mark_are_skipped() {
+        assert_eq!(
+            records("\u{feff}name\n\n  \nAda\n\n"),
+            vec![csv_record(1, &["name"]), csv_record(4, &["Ada"])]
+        );
+        assert!(records("").is_empty());
+    }
+
+    #[test]
+    fn unclosed_quotes_are_an_error() {
+        let reason = csv_reason(parse_csv("a,b\n\"open,c\nd").err());
+        assert!(reason.contains("line 2"), "{}", reason);
+    }
+
+    #[test]
+    fn headers_must_name_every_required_column() {
+        let reason = csv_reason(job("name,grade\nAda,3", ImportMode::DryRun).err());
+        assert!(reason.contains("\"grade_level\""), "{}", reason);
+        csv_reason(job("", ImportMode::DryRun).err());
+        // names are matched without case and surrounding spaces
+        assert!(job(" Name , GRADE_LEVEL \nAda,3", ImportMode::DryRun).is_ok());
+    }
+
+    #[test]
+    fn rows_must_have_as_many_fields_as_the_header() {
+        let job = match job("name,grade_level\nAda,3\nGrace\nLinus,4,extra", ImportMode::DryRun) {
+            Ok(job) => job,
+            Err(_) => panic!("the header is valid"),
+        };
+        let report = finish(job);
+        assert_eq!(report.rows, 3);
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![3, 4]);
+        assert!(report.created_ids.is_empty());
+        assert!(!report.committed);
+    }
+
+    #[test]
+    fn commits_with_an_invalid_row_store_nothing() {
+        let job = match job("name,grade_level\nAda,3\n,4", ImportMode::Commit) {
+            Ok(job) => job,
+            Err(_) => panic!("the header is valid"),
+        };
+        let students = STUDENT_MAP.with(|service| service.borrow().len());
+        let report = finish(job);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 3);
+        assert!(!report.committed);
+        assert_eq!(STUDENT_MAP.with(|service| service.borrow().len()), students);
+    }
+
+    #[test]
+    fn commits_store_every_row() {
+        let job = match job("name,grade_level\nAda,3\n\"Hopper, Grace\",4", ImportMode::Commit) {
+            Ok(job) => job,
+            Err(_) => panic!("the header is valid"),
+        };
+        let report = finish(job);
+        assert!(report.errors.is_empty());
+        assert!(report.committed);
+        assert_eq!(report.created_ids.len(), 2);
+        let name = STUDENT_MAP.with(|service| service.borrow().get(&report.created_ids[1])).map(|student| student.name);
+        assert_eq!(name.as_deref(), Some("Hopper, Grace"));
+    }
+}
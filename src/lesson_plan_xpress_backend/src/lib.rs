@@ -1,10 +1,54 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::Principal;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
 use std::{borrow::Cow, cell::RefCell};
 
+mod attendance;
+mod audit;
+mod auth;
+mod backup;
+mod calendar;
+mod eligibility;
+mod enrollment;
+mod gradebook;
+mod http;
+mod ics;
+mod import;
+mod links;
+mod migrations;
+mod pagination;
+mod plan;
+mod revisions;
+mod rooms;
+mod schedule;
+mod search;
+mod templates;
+mod timetable;
+mod waitlist;
+
+use attendance::{AttendanceMark, AttendanceSession, AttendanceSummary};
+use audit::{audited, record, AuditEntry, AuditQuery, AuditRetention};
+use auth::{require_admin, require_lesson_teacher, require_staff, require_student, require_teacher, Role};
+use backup::{ExportCursor, SnapshotChunk};
+use calendar::{Date, Holiday, PeriodPayload, Session, Term};
+use eligibility::{Completions, Eligibility, EligibilityPayload};
+use enrollment::Enrollment;
+use gradebook::{Assignment, AssignmentPayload, GradeSheet, GradeWeights, LessonGrades};
+use http::{HttpRequest, HttpResponse};
+use import::{CsvSource, ImportMode, ImportReport};
+use links::{require_teacher_exists, DeleteMode};
+use migrations::LegacyScheduleEntry;
+use pagination::Page;
+use plan::{Activity, LessonPlan};
+use rooms::{FreeRoomQuery, LessonRooms, Room, RoomPayload};
+use revisions::{FieldChange, Revision, RevisionSummary};
+use schedule::{ConflictReport, SchoolSettings, Weekday};
+use search::LessonQuery;
+use templates::{CloneLessonPayload, LessonSource, LessonTemplate};
+use timetable::{TimetableProgress, TimetableRequest};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>; 
 type IdCell = Cell<u64, Memory>;
@@ -21,6 +65,11 @@ struct Lesson {
     teacher_id: u64,
     students: Vec<u64>, // Connect lessons to students
     schedule: Vec<ScheduleEntry>, // Integrate scheduling
+    plan: LessonPlan, // objectives, materials, activities and assessments
+    cloned_from: Option<LessonSource>, // lesson or template this one was copied from
+    term_id: Option<u64>, // academic term the lesson runs in, all year when None
+    capacity: Option<u32>, // most students enrolled at once, unlimited when None
+    waitlist: Vec<u64>, // students waiting for a place, first in line first
 }
 
 #[derive (candid::CandidType, Clone,Serialize, Deserialize)]
@@ -46,80 +95,83 @@ struct Student {
 
 #[derive (candid::CandidType, Clone,Serialize, Deserialize)]
 // Supporting struct schedule Entry 
+// times are minutes since midnight in the school's time zone
 struct ScheduleEntry {
     id: u64,
-    day: String,
-    start_time: String,
-    end_time: String,
+    day: Weekday,
+    start_minute: u16,
+    end_minute: u16,
 }
 
 
-// Implement the Storable and BoundedStorable traits for the Lesson struct
+// Implement the Storable trait for the Lesson struct
+// records are wrapped in a versioned envelope, see migrations.rs
 impl Storable for Lesson {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-      Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+      Cow::Owned(migrations::encode_lesson(self))
   }
 
   fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-      Decode!(bytes.as_ref(), Self).unwrap()
+      migrations::decode_lesson(bytes.as_ref())
   }
-}
 
-impl  BoundedStorable for Lesson {
-   const MAX_SIZE: u32 = 1024;
-  const IS_FIXED_SIZE: bool = false;
+  // lesson plans and enrollment lists have no fixed size
+  const BOUND: Bound = Bound::Unbounded;
 }
 
 
-// Implement the Storable and BoundedStorable traits for the Teacher struct
+
+// Implement the Storable trait for the Teacher struct
+// records are wrapped in a versioned envelope, see migrations.rs
 impl Storable for Teacher {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-      Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+      Cow::Owned(migrations::encode_teacher(self))
   }
 
   fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-      Decode!(bytes.as_ref(), Self).unwrap()
+      migrations::decode_teacher(bytes.as_ref())
   }
-}
 
-impl  BoundedStorable for Teacher {
-   const MAX_SIZE: u32 = 1024;
-  const IS_FIXED_SIZE: bool = false;
+  // lesson plans and enrollment lists have no fixed size
+  const BOUND: Bound = Bound::Unbounded;
 }
 
-// Implement the Storable and BoundedStorable traits for the Student struct
+
+// Implement the Storable trait for the Student struct
+// records are wrapped in a versioned envelope, see migrations.rs
 impl Storable for Student {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-      Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+      Cow::Owned(migrations::encode_student(self))
   }
 
   fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-      Decode!(bytes.as_ref(), Self).unwrap()
+      migrations::decode_student(bytes.as_ref())
   }
-}
 
-impl  BoundedStorable for Student {
-   const MAX_SIZE: u32 = 1024;
-  const IS_FIXED_SIZE: bool = false;
+  // lesson plans and enrollment lists have no fixed size
+  const BOUND: Bound = Bound::Unbounded;
 }
 
-// Implement the Storable and BoundedStorable traits for the ScheduleEntry struct
+
+// Implement the Storable trait for the ScheduleEntry struct
+// records are wrapped in a versioned envelope, see migrations.rs
 impl Storable for ScheduleEntry {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-      Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+      Cow::Owned(migrations::encode_schedule_entry(self))
   }
 
   fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-      Decode!(bytes.as_ref(), Self).unwrap()
+      migrations::decode_schedule_entry(bytes.as_ref())
   }
-}
 
-impl  BoundedStorable for ScheduleEntry {
-   const MAX_SIZE: u32 = 1024;
-  const IS_FIXED_SIZE: bool = false;
+  const BOUND: Bound = Bound::Bounded {
+      max_size: 1024,
+      is_fixed_size: false,
+  };
 }
 
 
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -167,6 +219,10 @@ struct LessonPayload {
     grade_level: String,
     subject: String,
     teacher_id: u64,
+    // replaces the whole plan when given; sections can also be set one by one
+    plan: Option<LessonPlan>,
+    // binds the lesson to a term when given, see set_lesson_term to unbind
+    term_id: Option<u64>,
 }
 
 // struct for Teacher payload
@@ -185,8 +241,9 @@ struct StudentPayload {
 
 //struct for Schedule Entry Payload
 #[derive(candid::CandidType,Serialize, Deserialize)]
+// times are given as "HH:MM" (24 hour clock)
 struct SchedulePayload {
-    day: String,
+    day: Weekday,
     start_time: String,
     end_time: String,
 }
@@ -204,13 +261,7 @@ fn get_all_lessons() -> Result<Vec<Lesson>, Error> {
         .map(|(_, lesson)| lesson)
         .collect();
 
-    if !lessons.is_empty() {
-        Ok(lessons)
-    } else {
-        Err(Error::NotFound {
-            msg: "No Lessons found ".to_string(),
-        })
-    }
+    Ok(lessons)
 }
 
 
@@ -220,61 +271,180 @@ fn get_lesson(id: u64) -> Result<Lesson, Error> {
     if let Some(lesson) = lesson {
         Ok(lesson)
     } else {
-        Err(Error::NotFound {
-            msg: format!("Lesson with id={} not found", id),
-        })
+        Err(Error::NotFound { entity: Entity::Lesson, id })
     }
 }
 
 #[ic_cdk::update]
-fn add_lesson(lesson_payload: LessonPayload) -> Result<Lesson, String> {
-    if lesson_payload.title.trim().is_empty() ||
-        lesson_payload.subject.trim().is_empty() ||
-       lesson_payload.description.trim().is_empty() ||
-       lesson_payload.grade_level.trim().is_empty() {
-        return Err("Invalid Lesson data Check for valid data ".to_string());
+fn add_lesson(lesson_payload: LessonPayload) -> Result<Lesson, Error> {
+    audited("add_lesson", &[], || {
+        require_teacher(lesson_payload.teacher_id, "add lessons for this teacher")?;
+        validate_new_lesson(&lesson_payload)?;
+        create_lesson(lesson_payload)
+    })
+}
+
+// helper function with the checks a new lesson has to pass, shared with the
+// CSV import
+fn validate_new_lesson(lesson_payload: &LessonPayload) -> Result<(), Error> {
+    require_teacher_exists(lesson_payload.teacher_id)?;
+    require_not_empty("title", &lesson_payload.title)?;
+    require_not_empty("description", &lesson_payload.description)?;
+    require_not_empty("grade_level", &lesson_payload.grade_level)?;
+    require_not_empty("subject", &lesson_payload.subject)?;
+    check_lesson_payload_size(lesson_payload)?;
+    eligibility::require_grade_level("grade_level", &lesson_payload.grade_level)?;
+    if let Some(plan) = &lesson_payload.plan {
+        plan::validate_plan(plan)?;
+    }
+    if let Some(term_id) = lesson_payload.term_id {
+        calendar::require_term_exists(term_id)?;
     }
+    Ok(())
+}
 
-    let id = LESSON_ID_COUNTER
-    .with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1)
-    })
-    .expect("cannot increment id counter");
+// helper method to store a validated lesson payload
+fn create_lesson(lesson_payload: LessonPayload) -> Result<Lesson, Error> {
+    let id = next_lesson_id();
     let lesson = Lesson {
         id,
         title: lesson_payload.title ,
         description: lesson_payload.description,
-        grade_level: lesson_payload.grade_level,
+        grade_level: eligibility::canonical_grade_level(&lesson_payload.grade_level),
         subject: lesson_payload.subject,
         teacher_id: lesson_payload.teacher_id,
         students: Vec::new(),
         schedule: Vec::new(),
+        plan: lesson_payload.plan.unwrap_or_default(),
+        cloned_from: None,
+        term_id: lesson_payload.term_id,
+        capacity: None,
+        waitlist: Vec::new(),
     };
-    do_insert_lesson(&lesson);
+    do_insert_lesson(&lesson)?;
+    revisions::record_revision(&lesson);
     Ok(lesson)
 }
 
+// helper method to allocate the id of a new lesson
+fn next_lesson_id() -> u64 {
+    LESSON_ID_COUNTER
+    .with(|counter| {
+        let current_value = *counter.borrow().get();
+        counter.borrow_mut().set(current_value + 1)
+    })
+    .expect("cannot increment id counter")
+}
+
 // update lesson
 #[ic_cdk::update]
 fn update_lesson(lesson_id: u64, lesson_payload: LessonPayload) -> Result<Lesson, Error> {
-    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
-    if let Some(mut lesson) = lesson {
-        update_if_not_empty(&mut lesson.title, lesson_payload.title);
-        update_if_not_empty(&mut lesson.description, lesson_payload.description);
-        update_if_not_empty(&mut lesson.grade_level, lesson_payload.grade_level);
-        update_if_not_empty(&mut lesson.subject, lesson_payload.subject);
-        lesson.teacher_id = lesson_payload.teacher_id;
-        do_insert_lesson(&lesson);
-        Ok(lesson)
+    audited("update_lesson", &[record(Entity::Lesson, lesson_id)], || {
+        let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+        if let Some(mut lesson) = lesson {
+            require_teacher(lesson.teacher_id, "update this lesson")?;
+            require_teacher(lesson_payload.teacher_id, "assign lessons to this teacher")?;
+            require_teacher_exists(lesson_payload.teacher_id)?;
+            check_lesson_payload_size(&lesson_payload)?;
+            if let Some(plan) = &lesson_payload.plan {
+                plan::validate_plan(plan)?;
+            }
+            if let Some(term_id) = lesson_payload.term_id {
+                calendar::require_term_exists(term_id)?;
+                lesson.term_id = Some(term_id);
+            }
+            update_if_not_empty(&mut lesson.title, lesson_payload.title);
+            update_if_not_empty(&mut lesson.description, lesson_payload.description);
+            if !lesson_payload.grade_level.trim().is_empty() {
+                lesson.grade_level = eligibility::require_grade_level("grade_level", &lesson_payload.grade_level)?;
+            }
+            update_if_not_empty(&mut lesson.subject, lesson_payload.subject);
+            let old_teacher_id = lesson.teacher_id;
+            lesson.teacher_id = lesson_payload.teacher_id;
+            if let Some(plan) = lesson_payload.plan {
+                lesson.plan = plan;
+            }
+            // the new teacher takes over the lesson's slots
+            if lesson.teacher_id != old_teacher_id {
+                schedule::check_lesson_slots(&lesson, false)?;
+            }
+            do_insert_lesson(&lesson)?;
+            if lesson.teacher_id != old_teacher_id {
+                links::move_lesson_to_teacher(lesson.id, old_teacher_id, lesson.teacher_id);
+            }
+            revisions::record_revision(&lesson);
+            Ok(lesson)
+        } else {
+            Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id } )
+        }
+
+    })
+}
+
+// helper function to reject blank required fields
+fn require_not_empty(field: &str, value: &str) -> Result<(), Error> {
+    if value.trim().is_empty() {
+        Err(Error::InvalidInput {
+            field: field.to_string(),
+            reason: "must not be empty".to_string(),
+        })
     } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Update Lesson  with id={}. not found",
-                lesson_id
-        )} )
+        Ok(())
     }
+}
 
+// Largest accepted sizes in bytes for text fields. Records are stored
+// unbounded, these only keep single requests within reasonable limits.
+const MAX_NAME_SIZE: usize = 256;
+const MAX_DESCRIPTION_SIZE: usize = 64 * 1024;
+
+// helper function to reject oversized fields
+fn require_max_size(field: &str, value: &str, max_size: usize) -> Result<(), Error> {
+    if value.len() > max_size {
+        Err(Error::PayloadTooLarge {
+            field: field.to_string(),
+            size: value.len() as u64,
+            max_size: max_size as u64,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// Largest encoded lesson. Replies are limited to about 2MB, and some carry
+// a lesson together with other records.
+const MAX_LESSON_SIZE: usize = 1024 * 1024;
+
+// the fields are checked one by one on input, this bounds their sum and
+// the lists that grow with enrollments
+fn require_lesson_size(lesson: &Lesson) -> Result<(), Error> {
+    let size = migrations::encode_lesson(lesson).len();
+    if size > MAX_LESSON_SIZE {
+        Err(Error::PayloadTooLarge {
+            field: "lesson".to_string(),
+            size: size as u64,
+            max_size: MAX_LESSON_SIZE as u64,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn check_lesson_payload_size(payload: &LessonPayload) -> Result<(), Error> {
+    require_max_size("title", &payload.title, MAX_NAME_SIZE)?;
+    require_max_size("description", &payload.description, MAX_DESCRIPTION_SIZE)?;
+    require_max_size("grade_level", &payload.grade_level, MAX_NAME_SIZE)?;
+    require_max_size("subject", &payload.subject, MAX_NAME_SIZE)
+}
+
+fn check_teacher_payload_size(payload: &TeacherPayload) -> Result<(), Error> {
+    require_max_size("name", &payload.name, MAX_NAME_SIZE)?;
+    require_max_size("subject", &payload.subject, MAX_NAME_SIZE)
+}
+
+fn check_student_payload_size(payload: &StudentPayload) -> Result<(), Error> {
+    require_max_size("name", &payload.name, MAX_NAME_SIZE)?;
+    require_max_size("grade_level", &payload.grade_level, MAX_NAME_SIZE)
 }
 
 // helper function 
@@ -286,25 +456,24 @@ fn update_if_not_empty(field: &mut String, new_value: String) {
 
 
 // helper method to perform insert.
-fn do_insert_lesson(lesson: &Lesson) {
-    LESSON_MAP.with(|service| {
+fn do_insert_lesson(lesson: &Lesson) -> Result<(), Error> {
+    require_lesson_size(lesson)?;
+    let previous = LESSON_MAP.with(|service| {
         service
             .borrow_mut()
             .insert(lesson.id, lesson.clone())
     });
+    search::index_lesson(previous.as_ref(), Some(lesson));
+    Ok(())
 }
 
 // delete a Lesson 
 #[ic_cdk::update]
-fn delete_lesson(id: u64) -> Result<Lesson, Error> {
-    let lesson = LESSON_MAP.with(|service| service.borrow_mut().remove(&id));
-    if let Some(lesson) = lesson {
-        Ok(lesson)
-    } else {
-        Err(Error::NotFound {
-            msg: format!("Lesson with id={} not found", id),
-        })
-    }
+fn delete_lesson(id: u64, mode: DeleteMode) -> Result<Lesson, Error> {
+    audited("delete_lesson", &[record(Entity::Lesson, id)], || {
+        require_lesson_teacher(id, "delete this lesson")?;
+        links::remove_lesson(id, mode)
+    })
 }
 
 // CRUD operations for the Teacher Struct
@@ -317,13 +486,7 @@ fn get_all_teachers() -> Result<Vec<Teacher>, Error> {
         .map(|(_, teacher)| teacher)
         .collect();
 
-    if !teachers.is_empty() {
-        Ok(teachers)
-    } else {
-        Err(Error::NotFound {
-            msg: "No Teachers found ".to_string(),
-        })
-    }
+    Ok(teachers)
 }
 
 
@@ -333,19 +496,28 @@ fn get_teacher(id: u64) -> Result<Teacher, Error> {
     if let Some(teacher) = teacher {
         Ok(teacher)
     } else {
-        Err(Error::NotFound {
-            msg: format!("Teacher with id={} not found", id),
-        })
+        Err(Error::NotFound { entity: Entity::Teacher, id })
     }
 }
 
 #[ic_cdk::update]
-fn add_teacher(teacher_payload: TeacherPayload) -> Result<Teacher, String> {
-    if teacher_payload.name.trim().is_empty() ||
-        teacher_payload.subject.trim().is_empty() {
-        return Err("Invalid Teacher data Check for valid data ".to_string());
-    }
+fn add_teacher(teacher_payload: TeacherPayload) -> Result<Teacher, Error> {
+    audited("add_teacher", &[], || {
+        require_admin("add teachers")?;
+        validate_new_teacher(&teacher_payload)?;
+        Ok(create_teacher(teacher_payload))
+    })
+}
 
+// helper function with the checks a new teacher has to pass
+fn validate_new_teacher(teacher_payload: &TeacherPayload) -> Result<(), Error> {
+    require_not_empty("name", &teacher_payload.name)?;
+    require_not_empty("subject", &teacher_payload.subject)?;
+    check_teacher_payload_size(teacher_payload)
+}
+
+// helper method to store a validated teacher payload
+fn create_teacher(teacher_payload: TeacherPayload) -> Teacher {
     let id = TEACHER_ID_COUNTER
     .with(|counter| {
         let current_value = *counter.borrow().get();
@@ -360,7 +532,7 @@ fn add_teacher(teacher_payload: TeacherPayload) -> Result<Teacher, String> {
         availability: Vec::new(),
     };
     do_insert_teacher(&teacher);
-    Ok(teacher)
+    teacher
 }
 
 // update teacher
@@ -368,20 +540,19 @@ fn add_teacher(teacher_payload: TeacherPayload) -> Result<Teacher, String> {
 #[ic_cdk::update]
 
 fn update_teacher(teacher_id: u64, teacher_payload: TeacherPayload) -> Result<Teacher, Error> {
-    let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
-    if let Some(mut teacher) = teacher {
-        update_if_not_empty(&mut teacher.name, teacher_payload.name);
-        update_if_not_empty(&mut teacher.subject, teacher_payload.subject);
-        do_insert_teacher(&teacher);
-        Ok(teacher)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Update Teacher  with id={}. not found",
-                teacher_id
-        )} )
-    }
-
+    audited("update_teacher", &[record(Entity::Teacher, teacher_id)], || {
+        require_teacher(teacher_id, "update this teacher")?;
+        let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
+        if let Some(mut teacher) = teacher {
+            check_teacher_payload_size(&teacher_payload)?;
+            update_if_not_empty(&mut teacher.name, teacher_payload.name);
+            update_if_not_empty(&mut teacher.subject, teacher_payload.subject);
+            do_insert_teacher(&teacher);
+            Ok(teacher)
+        } else {
+            Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id } )
+        }
+    })
 }
 
 // helper method to perform insert.
@@ -396,15 +567,11 @@ fn do_insert_teacher(teacher: &Teacher) {
 // delete a Teacher
 
 #[ic_cdk::update]
-fn delete_teacher(id: u64) -> Result<Teacher, Error> {
-    let teacher = TEACHER_MAP.with(|service| service.borrow_mut().remove(&id));
-    if let Some(teacher) = teacher {
-        Ok(teacher)
-    } else {
-        Err(Error::NotFound {
-            msg: format!("Teacher with id={} not found", id),
-        })
-    }
+fn delete_teacher(id: u64, mode: DeleteMode) -> Result<Teacher, Error> {
+    audited("delete_teacher", &[record(Entity::Teacher, id)], || {
+        require_admin("delete teachers")?;
+        links::remove_teacher(id, mode)
+    })
 }
 
 // CRUD operations for the Student Struct
@@ -417,13 +584,7 @@ fn get_all_students() -> Result<Vec<Student>, Error> {
         .map(|(_, student)| student)
         .collect();
 
-    if !students.is_empty() {
-        Ok(students)
-    } else {
-        Err(Error::NotFound {
-            msg: "No Students found ".to_string(),
-        })
-    }
+    Ok(students)
 }
 
 
@@ -433,19 +594,30 @@ fn get_student(id: u64) -> Result<Student, Error> {
     if let Some(student) = student {
         Ok(student)
     } else {
-        Err(Error::NotFound {
-            msg: format!("Student with id={} not found", id),
-        })
+        Err(Error::NotFound { entity: Entity::Student, id })
     }
 }
 
 #[ic_cdk::update]
-fn add_student(student_payload: StudentPayload) -> Result<Student, String> {
-    if student_payload.name.trim().is_empty() ||
-        student_payload.grade_level.trim().is_empty() {
-        return Err("Invalid Student data Check for valid data ".to_string());
-    }
+fn add_student(student_payload: StudentPayload) -> Result<Student, Error> {
+    audited("add_student", &[], || {
+        require_admin("add students")?;
+        validate_new_student(&student_payload)?;
+        Ok(create_student(student_payload))
+    })
+}
 
+// helper function with the checks a new student has to pass
+fn validate_new_student(student_payload: &StudentPayload) -> Result<(), Error> {
+    require_not_empty("name", &student_payload.name)?;
+    require_not_empty("grade_level", &student_payload.grade_level)?;
+    check_student_payload_size(student_payload)?;
+    eligibility::require_grade_level("grade_level", &student_payload.grade_level)?;
+    Ok(())
+}
+
+// helper method to store a validated student payload
+fn create_student(student_payload: StudentPayload) -> Student {
     let id = STUDENT_ID_COUNTER
     .with(|counter| {
         let current_value = *counter.borrow().get();
@@ -455,31 +627,33 @@ fn add_student(student_payload: StudentPayload) -> Result<Student, String> {
     let student = Student {
         id,
         name: student_payload.name ,
-        grade_level: student_payload.grade_level,
+        grade_level: eligibility::canonical_grade_level(&student_payload.grade_level),
         lessons: Vec::new(),
     };
     do_insert_student(&student);
-    Ok(student)
+    student
 }
 
 // update student
 
 #[ic_cdk::update]
 fn update_student(student_id: u64, student_payload: StudentPayload) -> Result<Student, Error> {
-    let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
-    if let Some(mut student) = student {
-        update_if_not_empty(&mut student.name, student_payload.name);
-        update_if_not_empty(&mut student.grade_level, student_payload.grade_level);
-        do_insert_student(&student);
-        Ok(student)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Update Student  with id={}. not found",
-                student_id
-        )} )
-    }
+    audited("update_student", &[record(Entity::Student, student_id)], || {
+        require_student(student_id, "update this student")?;
+        let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
+        if let Some(mut student) = student {
+            check_student_payload_size(&student_payload)?;
+            update_if_not_empty(&mut student.name, student_payload.name);
+            if !student_payload.grade_level.trim().is_empty() {
+                student.grade_level = eligibility::require_grade_level("grade_level", &student_payload.grade_level)?;
+            }
+            do_insert_student(&student);
+            Ok(student)
+        } else {
+            Err(Error::NotFound { entity: Entity::Student, id: student_id } )
+        }
 
+    })
 }
 
 // helper method to perform insert.
@@ -494,15 +668,11 @@ fn do_insert_student(student: &Student) {
 // delete a Student
 
 #[ic_cdk::update]
-fn delete_student(id: u64) -> Result<Student, Error> {
-    let student = STUDENT_MAP.with(|service| service.borrow_mut().remove(&id));
-    if let Some(student) = student {
-        Ok(student)
-    } else {
-        Err(Error::NotFound {
-            msg: format!("Student with id={} not found", id),
-        })
-    }
+fn delete_student(id: u64, mode: DeleteMode) -> Result<Student, Error> {
+    audited("delete_student", &[record(Entity::Student, id)], || {
+        require_admin("delete students")?;
+        links::remove_student(id, mode)
+    })
 }
 
 // CRUD operations for the ScheduleEntry Struct
@@ -515,13 +685,7 @@ fn get_all_schedule_entries() -> Result<Vec<ScheduleEntry>, Error> {
         .map(|(_, schedule_entry)| schedule_entry)
         .collect();
 
-    if !schedule_entries.is_empty() {
-        Ok(schedule_entries)
-    } else {
-        Err(Error::NotFound {
-            msg: "No Schedule Entries found ".to_string(),
-        })
-    }
+    Ok(schedule_entries)
 }
 
 
@@ -531,55 +695,58 @@ fn get_schedule_entry(id: u64) -> Result<ScheduleEntry, Error> {
     if let Some(schedule_entry) = schedule_entry {
         Ok(schedule_entry)
     } else {
-        Err(Error::NotFound {
-            msg: format!("Schedule Entry with id={} not found", id),
-        })
+        Err(Error::NotFound { entity: Entity::ScheduleEntry, id })
     }
 }
 
 #[ic_cdk::update]
-fn add_schedule_entry(schedule_payload: SchedulePayload) -> Result<ScheduleEntry, String> {
-    if schedule_payload.day.trim().is_empty() ||
-        schedule_payload.start_time.trim().is_empty() ||
-        schedule_payload.end_time.trim().is_empty() {
-        return Err("Invalid Schedule Entry data Check for valid data ".to_string());
-    }
-
-    let id = SCHEDULE_ID_COUNTER
-    .with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1)
+fn add_schedule_entry(schedule_payload: SchedulePayload) -> Result<ScheduleEntry, Error> {
+    audited("add_schedule_entry", &[], || {
+        require_staff("add schedule entries")?;
+        let (start_minute, end_minute) =
+            schedule::parse_slot(&schedule_payload.start_time, &schedule_payload.end_time)?;
+
+        let id = SCHEDULE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+        let schedule_entry = ScheduleEntry {
+            id,
+            day: schedule_payload.day,
+            start_minute,
+            end_minute,
+        };
+        do_insert_schedule_entry(&schedule_entry);
+        Ok(schedule_entry)
     })
-    .expect("cannot increment id counter");
-    let schedule_entry = ScheduleEntry {
-        id,
-        day: schedule_payload.day ,
-        start_time: schedule_payload.start_time,
-        end_time: schedule_payload.end_time,
-    };
-    do_insert_schedule_entry(&schedule_entry);
-    Ok(schedule_entry)
 }
 
 // update schedule entry
 
 #[ic_cdk::update]
 fn update_schedule_entry(schedule_id: u64, schedule_payload: SchedulePayload) -> Result<ScheduleEntry, Error> {
-    let schedule_entry = SCHEDULE_ENTRY_MAP.with(|service| service.borrow().get(&schedule_id));
-    if let Some(mut schedule_entry) = schedule_entry {
-        update_if_not_empty(&mut schedule_entry.day, schedule_payload.day);
-        update_if_not_empty(&mut schedule_entry.start_time, schedule_payload.start_time);
-        update_if_not_empty(&mut schedule_entry.end_time, schedule_payload.end_time);
-        do_insert_schedule_entry(&schedule_entry);
-        Ok(schedule_entry)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Update Schedule Entry  with id={}. not found",
-                schedule_id
-        )} )
-    }
+    audited("update_schedule_entry", &[record(Entity::ScheduleEntry, schedule_id)], || {
+        require_admin("update schedule entries")?;
+        let schedule_entry = SCHEDULE_ENTRY_MAP.with(|service| service.borrow().get(&schedule_id));
+        if let Some(mut schedule_entry) = schedule_entry {
+            // empty times keep the current value
+            let mut start_time = schedule::format_time(schedule_entry.start_minute);
+            let mut end_time = schedule::format_time(schedule_entry.end_minute);
+            update_if_not_empty(&mut start_time, schedule_payload.start_time);
+            update_if_not_empty(&mut end_time, schedule_payload.end_time);
+            let (start_minute, end_minute) = schedule::parse_slot(&start_time, &end_time)?;
+            schedule_entry.day = schedule_payload.day;
+            schedule_entry.start_minute = start_minute;
+            schedule_entry.end_minute = end_minute;
+            do_insert_schedule_entry(&schedule_entry);
+            Ok(schedule_entry)
+        } else {
+            Err(Error::NotFound { entity: Entity::ScheduleEntry, id: schedule_id } )
+        }
 
+    })
 }
 
 // helper method to perform insert.
@@ -595,172 +762,174 @@ fn do_insert_schedule_entry(schedule_entry: &ScheduleEntry) {
 
 #[ic_cdk::update]
 fn delete_schedule_entry(id: u64) -> Result<ScheduleEntry, Error> {
-    let schedule_entry = SCHEDULE_ENTRY_MAP.with(|service| service.borrow_mut().remove(&id));
-    if let Some(schedule_entry) = schedule_entry {
-        Ok(schedule_entry)
-    } else {
-        Err(Error::NotFound {
-            msg: format!("Schedule Entry with id={} not found", id),
-        })
-    }
+    audited("delete_schedule_entry", &[record(Entity::ScheduleEntry, id)], || {
+        require_admin("delete schedule entries")?;
+        let schedule_entry = SCHEDULE_ENTRY_MAP.with(|service| service.borrow_mut().remove(&id));
+        if let Some(schedule_entry) = schedule_entry {
+            Ok(schedule_entry)
+        } else {
+            Err(Error::NotFound { entity: Entity::ScheduleEntry, id })
+        }
+    })
 }
 
 
-// add a student to a lesson
+// add a student to a lesson, or to its waitlist when the lesson is full;
+// the same as enroll, so both sides are linked
 #[ic_cdk::update]
 fn insert_student_to_lesson(lesson_id: u64, student_id: u64) -> Result<Lesson, Error> {
-    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
-    if let Some(mut lesson) = lesson {
-        lesson.students.push(student_id);
-        do_insert_lesson(&lesson);
-        Ok(lesson)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Lesson  with id={}. not found",
-                lesson_id
-        )} )
-    }
-
+    audited("insert_student_to_lesson", &[record(Entity::Lesson, lesson_id), record(Entity::Student, student_id)], || {
+        Ok(enrollment::enroll_student(lesson_id, student_id)?.lesson)
+    })
 }
 
 // add a schedule to a lesson
 #[ic_cdk::update]
-fn insert_schedule_to_lesson(lesson_id: u64, schedule_id: u64) -> Result<Lesson, Error> {
-    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
-    if let Some(mut lesson) = lesson {
-        let schedule = SCHEDULE_ENTRY_MAP.with(|service| service.borrow().get(&schedule_id));
-        if let Some(schedule) = schedule {
-            lesson.schedule.push(schedule.clone());
-            do_insert_lesson(&lesson);
-            Ok(lesson)
+// the slot is checked against the teacher's other lessons and availability
+// and the enrolled students' lessons; admins may `force` it through anyway
+fn insert_schedule_to_lesson(lesson_id: u64, schedule_id: u64, force: bool) -> Result<Lesson, Error> {
+    audited("insert_schedule_to_lesson", &[record(Entity::Lesson, lesson_id), record(Entity::ScheduleEntry, schedule_id)], || {
+        let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+        if let Some(mut lesson) = lesson {
+            require_teacher(lesson.teacher_id, "schedule this lesson")?;
+            let schedule = SCHEDULE_ENTRY_MAP.with(|service| service.borrow().get(&schedule_id));
+            if let Some(schedule) = schedule {
+                if lesson.schedule.iter().any(|entry| entry.id == schedule_id) {
+                    return Err(Error::Conflict {
+                        reason: format!("Lesson with id={} already has schedule id={}", lesson_id, schedule_id),
+                    });
+                }
+                schedule::check_conflicts(schedule::lesson_slot_conflicts(&lesson, &schedule), force)?;
+                lesson.schedule.push(schedule.clone());
+                do_insert_lesson(&lesson)?;
+                Ok(lesson)
+            } else {
+                Err(Error::NotFound { entity: Entity::ScheduleEntry, id: schedule_id } )
+            }
+
         } else {
-            return Err(Error::NotFound {
-                msg: format!(
-                    "Schedule not found ",
-            )} )
+            Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id } )
         }
-   
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Lesson  with id={}. not found",
-                lesson_id
-        )} )
-    }
-
+    })
 }
 
 // add a lesson to a teacher
 #[ic_cdk::update]
 fn insert_lesson_to_teacher(teacher_id: u64, lesson_id: u64) -> Result<Teacher, Error> {
-    let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
-    if let Some(mut teacher) = teacher {
-        teacher.lessons.push(lesson_id);
-        do_insert_teacher(&teacher);
-        Ok(teacher)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Teacher  with id={}. not found",
-                teacher_id
-        )} )
-    }
+    audited("insert_lesson_to_teacher", &[record(Entity::Teacher, teacher_id), record(Entity::Lesson, lesson_id)], || {
+        require_teacher(teacher_id, "add lessons to this teacher")?;
+        let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+        let lesson = if let Some(lesson) = lesson {
+            lesson
+        } else {
+            return Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id });
+        };
+        // the list mirrors Lesson.teacher_id, which update_lesson changes
+        if lesson.teacher_id != teacher_id {
+            return Err(Error::Conflict {
+                reason: format!(
+                    "Lesson with id={} is taught by teacher with id={}; reassign it with update_lesson",
+                    lesson_id, lesson.teacher_id
+                ),
+            });
+        }
+        let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
+        if let Some(mut teacher) = teacher {
+            if teacher.lessons.contains(&lesson_id) {
+                return Err(Error::Conflict {
+                    reason: format!("Teacher with id={} already has lesson id={}", teacher_id, lesson_id),
+                });
+            }
+            teacher.lessons.push(lesson_id);
+            do_insert_teacher(&teacher);
+            Ok(teacher)
+        } else {
+            Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id } )
+        }
 
+    })
 }
 
 // add a schedule to a teacher
 #[ic_cdk::update]
-fn insert_schedule_to_teacher(teacher_id: u64, schedule_id: u64) -> Result<Teacher, Error> {
-    let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
-    if let Some(mut teacher) = teacher {
-        let schedule = SCHEDULE_ENTRY_MAP.with(|service| service.borrow().get(&schedule_id));
-        if let Some(schedule) = schedule {
-            teacher.availability.push(schedule.clone());
-            do_insert_teacher(&teacher);
-            Ok(teacher)
-        } else {
-            return Err(Error::NotFound {
-                msg: format!(
-                    "Schedule not found ",
-            )} )
+// overlapping availability entries are refused unless an admin passes `force`
+fn insert_schedule_to_teacher(teacher_id: u64, schedule_id: u64, force: bool) -> Result<Teacher, Error> {
+    audited("insert_schedule_to_teacher", &[record(Entity::Teacher, teacher_id), record(Entity::ScheduleEntry, schedule_id)], || {
+        require_teacher(teacher_id, "change this teacher's availability")?;
+        let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
+        if let Some(mut teacher) = teacher {
+            let schedule = SCHEDULE_ENTRY_MAP.with(|service| service.borrow().get(&schedule_id));
+            if let Some(schedule) = schedule {
+                schedule::check_conflicts(schedule::availability_conflicts(&teacher, &schedule), force)?;
+                teacher.availability.push(schedule.clone());
+                do_insert_teacher(&teacher);
+                Ok(teacher)
+            } else {
+                Err(Error::NotFound { entity: Entity::ScheduleEntry, id: schedule_id } )
 
-        }
-   
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Teacher  with id={}. not found",
-                teacher_id
-        )} )
-    }
+            }
 
+        } else {
+            Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id } )
+        }
+    })
 }
 
 
 
-// add a lesson to a student
+// add a lesson to a student; the same as enroll, so a full lesson puts the
+// student on its waitlist and the eligibility rules apply
 #[ic_cdk::update]
 fn insert_lesson_to_student(student_id: u64, lesson_id: u64) -> Result<Student, Error> {
-    let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
-    if let Some(mut student) = student {
-        student.lessons.push(lesson_id);
-        do_insert_student(&student);
-        Ok(student)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Student  with id={}. not found",
-                student_id
-        )} )
-    }
-
+    audited("insert_lesson_to_student", &[record(Entity::Student, student_id), record(Entity::Lesson, lesson_id)], || {
+        Ok(enrollment::enroll_student(lesson_id, student_id)?.student)
+    })
 }
 
-//  function to get all lessons for a teacher
-#[ic_cdk::query]
-fn get_all_lessons_for_teacher(teacher_id: u64) -> Result<Vec<Lesson>, Error> {
+// helper function to load a teacher and the lessons in their list
+fn teacher_lessons(teacher_id: u64) -> Result<(Teacher, Vec<Lesson>), Error> {
     let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
     if let Some(teacher) = teacher {
         let mut lessons: Vec<Lesson> = Vec::new();
         for lesson_id in &teacher.lessons {
-            let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+            let lesson = LESSON_MAP.with(|service| service.borrow().get(lesson_id));
             if let Some(lesson) = lesson {
                 lessons.push(lesson.clone());
             }
         }
-        Ok(lessons)
+        Ok((teacher, lessons))
     } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Teacher with id={}. not found",
-                teacher_id
-        )} )
+        Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id } )
     }
-
 }
 
-//  function to get all lessons for a student
-#[ic_cdk::query]
-fn get_all_lessons_for_student(student_id: u64) -> Result<Vec<Lesson>, Error> {
+// helper function to load a student and the lessons in their list
+fn student_lessons(student_id: u64) -> Result<(Student, Vec<Lesson>), Error> {
     let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
     if let Some(student) = student {
         let mut lessons: Vec<Lesson> = Vec::new();
         for lesson_id in &student.lessons {
-            let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+            let lesson = LESSON_MAP.with(|service| service.borrow().get(lesson_id));
             if let Some(lesson) = lesson {
                 lessons.push(lesson.clone());
             }
         }
-        Ok(lessons)
+        Ok((student, lessons))
     } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Student with id={}. not found",
-                student_id
-        )} )
+        Err(Error::NotFound { entity: Entity::Student, id: student_id } )
     }
+}
 
+//  function to get all lessons for a teacher
+#[ic_cdk::query]
+fn get_all_lessons_for_teacher(teacher_id: u64) -> Result<Vec<Lesson>, Error> {
+    teacher_lessons(teacher_id).map(|(_, lessons)| lessons)
+}
+
+//  function to get all lessons for a student
+#[ic_cdk::query]
+fn get_all_lessons_for_student(student_id: u64) -> Result<Vec<Lesson>, Error> {
+    student_lessons(student_id).map(|(_, lessons)| lessons)
 }
 
 //  function to get all students for a lesson
@@ -770,120 +939,137 @@ fn get_all_students_for_lesson(lesson_id: u64) -> Result<Vec<Student>, Error> {
     if let Some(lesson) = lesson {
         let mut students: Vec<Student> = Vec::new();
         for student_id in &lesson.students {
-            let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
+            let student = STUDENT_MAP.with(|service| service.borrow().get(student_id));
             if let Some(student) = student {
                 students.push(student.clone());
             }
         }
         Ok(students)
     } else {
-        Err(Error::NotFound {
-            msg: format!(
-                "Lesson with id={}. not found",
-                lesson_id
-        )} )
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id } )
     }
 
 }
 
-// delete a lesson from a teacher
+// delete a lesson from a teacher; only ids of lessons the teacher no longer
+// teaches can be removed, since the list mirrors Lesson.teacher_id
 #[ic_cdk::update]
 fn delete_lesson_from_teacher(teacher_id: u64, lesson_id: u64) -> Result<Teacher, Error> {
-    let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
-    if let Some(mut teacher) = teacher {
-        teacher.lessons.retain(|lesson| lesson != &lesson_id);
-        do_insert_teacher(&teacher);
-        Ok(teacher)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Teacher  with id={}. not found",
-                teacher_id
-        )} )
-    }
+    audited("delete_lesson_from_teacher", &[record(Entity::Teacher, teacher_id), record(Entity::Lesson, lesson_id)], || {
+        require_teacher(teacher_id, "remove lessons from this teacher")?;
+        let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+        if lesson.is_some_and(|lesson| lesson.teacher_id == teacher_id) {
+            return Err(Error::Conflict {
+                reason: format!(
+                    "Lesson with id={} is taught by teacher with id={}; reassign it with update_lesson or remove it with delete_lesson",
+                    lesson_id, teacher_id
+                ),
+            });
+        }
+        let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
+        if let Some(mut teacher) = teacher {
+            teacher.lessons.retain(|lesson| lesson != &lesson_id);
+            do_insert_teacher(&teacher);
+            Ok(teacher)
+        } else {
+            Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id } )
+        }
 
+    })
 }
 
-// delete a lesson from a student
+// delete a lesson from a student; the same as unenroll, so both sides are
+// unlinked
 #[ic_cdk::update]
 fn delete_lesson_from_student(student_id: u64, lesson_id: u64) -> Result<Student, Error> {
-    let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
-    if let Some(mut student) = student {
-        student.lessons.retain(|lesson| lesson != &lesson_id);
-        do_insert_student(&student);
-        Ok(student)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Student  with id={}. not found",
-                student_id
-        )} )
-    }
-
+    audited("delete_lesson_from_student", &[record(Entity::Student, student_id), record(Entity::Lesson, lesson_id)], || {
+        Ok(enrollment::unenroll_student(lesson_id, student_id)?.student)
+    })
 }
 
-// delete a student from a lesson
+// delete a student from a lesson or its waitlist on both sides; a freed
+// place goes to the first student on the waitlist
 #[ic_cdk::update]
 fn delete_student_from_lesson(lesson_id: u64, student_id: u64) -> Result<Lesson, Error> {
-    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
-    if let Some(mut lesson) = lesson {
-        lesson.students.retain(|student| student != &student_id);
-        do_insert_lesson(&lesson);
-        Ok(lesson)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Lesson  with id={}. not found",
-                lesson_id
-        )} )
-    }
-
+    audited("delete_student_from_lesson", &[record(Entity::Lesson, lesson_id), record(Entity::Student, student_id)], || {
+        Ok(enrollment::unenroll_student(lesson_id, student_id)?.lesson)
+    })
 }
 
 // delete a schedule from a lesson
 #[ic_cdk::update]
 fn delete_schedule_from_lesson(lesson_id: u64, schedule_id: u64) -> Result<Lesson, Error> {
-    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
-    if let Some(mut lesson) = lesson {
-        lesson.schedule.retain(|schedule| schedule.id != schedule_id);
-        do_insert_lesson(&lesson);
-        Ok(lesson)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Lesson  with id={}. not found",
-                lesson_id
-        )} )
-    }
+    audited("delete_schedule_from_lesson", &[record(Entity::Lesson, lesson_id), record(Entity::ScheduleEntry, schedule_id)], || {
+        let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+        if let Some(mut lesson) = lesson {
+            require_teacher(lesson.teacher_id, "schedule this lesson")?;
+            lesson.schedule.retain(|schedule| schedule.id != schedule_id);
+            do_insert_lesson(&lesson)?;
+            rooms::remove_slot_room(lesson_id, schedule_id);
+            Ok(lesson)
+        } else {
+            Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id } )
+        }
 
+    })
 }
 
 // delete a schedule from a teacher
 #[ic_cdk::update]
 fn delete_schedule_from_teacher(teacher_id: u64, schedule_id: u64) -> Result<Teacher, Error> {
-    let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
-    if let Some(mut teacher) = teacher {
-        teacher.availability.retain(|schedule| schedule.id != schedule_id);
-        do_insert_teacher(&teacher);
-        Ok(teacher)
-    } else {
-        Err(Error::NotFound {
-            msg: format!(
-                " Teacher  with id={}. not found",
-                teacher_id
-        )} )
-    }
+    audited("delete_schedule_from_teacher", &[record(Entity::Teacher, teacher_id), record(Entity::ScheduleEntry, schedule_id)], || {
+        require_teacher(teacher_id, "change this teacher's availability")?;
+        let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
+        if let Some(mut teacher) = teacher {
+            teacher.availability.retain(|schedule| schedule.id != schedule_id);
+            do_insert_teacher(&teacher);
+            Ok(teacher)
+        } else {
+            Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id } )
+        }
 
+    })
 }
 
 
 
 
 
+// Kind of record an error refers to
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize, PartialEq)]
+enum Entity {
+    Lesson,
+    Teacher,
+    Student,
+    ScheduleEntry,
+    LessonRevision,
+    Template,
+    Term,
+    Holiday,
+    Upload,
+    AttendanceSession,
+    Assignment,
+    Room,
+}
+
 // Error type for the service
-#[derive(candid::CandidType, Deserialize, Serialize)]
+#[derive(candid::CandidType, Clone, Deserialize, Serialize)]
 enum  Error {
-    NotFound { msg: String },
+    // a payload field failed validation
+    InvalidInput { field: String, reason: String },
+    NotFound { entity: Entity, id: u64 },
+    // the change clashes with the current state, e.g. links that block a delete
+    Conflict { reason: String },
+    // the caller's role does not allow the action
+    Unauthorized { action: String },
+    // the record already holds as many items as it may
+    CapacityExceeded { entity: Entity, id: u64, capacity: u64 },
+    // a value is larger than the canister stores
+    PayloadTooLarge { field: String, size: u64, max_size: u64 },
+    // the slot clashes with existing schedules, see the report
+    ScheduleConflict { report: ConflictReport },
+    // the student is outside the lesson's grade range or misses a prerequisite
+    NotEligible { lesson_id: u64, student_id: u64, reason: String },
 }
 
 // Export the candid interface
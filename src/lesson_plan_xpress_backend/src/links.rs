@@ -0,0 +1,216 @@
+use crate::{do_insert_lesson, do_insert_student, do_insert_teacher, Entity, Error, Lesson, Student, Teacher};
+use crate::{attendance, eligibility, gradebook, revisions, rooms, search, waitlist};
+use crate::{LESSON_MAP, STUDENT_MAP, TEACHER_MAP};
+
+// How a delete treats the records that still link to the deleted one
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum DeleteMode {
+    // remove the links and also delete records owned by the deleted one
+    // (a teacher's lessons). Lessons and students own nothing, so for them
+    // this behaves like Detach.
+    Cascade,
+    // refuse the delete while any link exists
+    Restrict,
+    // remove the id from every linking record and keep those records
+    Detach,
+}
+
+// teachers whose lesson list contains the lesson
+fn teachers_linked_to_lesson(lesson_id: u64) -> Vec<Teacher> {
+    TEACHER_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, teacher)| teacher.lessons.contains(&lesson_id))
+            .map(|(_, teacher)| teacher)
+            .collect()
+    })
+}
+
+// students whose lesson list contains the lesson
+fn students_linked_to_lesson(lesson_id: u64) -> Vec<Student> {
+    STUDENT_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, student)| student.lessons.contains(&lesson_id))
+            .map(|(_, student)| student)
+            .collect()
+    })
+}
+
+// lessons whose student list or waitlist contains the student
+fn lessons_linked_to_student(student_id: u64) -> Vec<Lesson> {
+    LESSON_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, lesson)| lesson.students.contains(&student_id) || lesson.waitlist.contains(&student_id))
+            .map(|(_, lesson)| lesson)
+            .collect()
+    })
+}
+
+// lessons taught by the teacher
+pub(crate) fn lessons_owned_by_teacher(teacher_id: u64) -> Vec<Lesson> {
+    LESSON_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, lesson)| lesson.teacher_id == teacher_id)
+            .map(|(_, lesson)| lesson)
+            .collect()
+    })
+}
+
+// move the lesson's id from the old teacher's lesson list to the new one's
+pub(crate) fn move_lesson_to_teacher(lesson_id: u64, old_teacher_id: u64, new_teacher_id: u64) {
+    let old_teacher = TEACHER_MAP.with(|service| service.borrow().get(&old_teacher_id));
+    if let Some(mut teacher) = old_teacher {
+        teacher.lessons.retain(|id| id != &lesson_id);
+        do_insert_teacher(&teacher);
+    }
+    let new_teacher = TEACHER_MAP.with(|service| service.borrow().get(&new_teacher_id));
+    if let Some(mut teacher) = new_teacher {
+        if !teacher.lessons.contains(&lesson_id) {
+            teacher.lessons.push(lesson_id);
+            do_insert_teacher(&teacher);
+        }
+    }
+}
+
+// remove a lesson and strip its id from every teacher and student
+pub(crate) fn remove_lesson(lesson_id: u64, mode: DeleteMode) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    let lesson = if let Some(lesson) = lesson {
+        lesson
+    } else {
+        return Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id });
+    };
+    let teachers = teachers_linked_to_lesson(lesson_id);
+    let students = students_linked_to_lesson(lesson_id);
+    if mode == DeleteMode::Restrict
+        && (!teachers.is_empty() || !students.is_empty() || !lesson.students.is_empty())
+    {
+        return Err(Error::Conflict {
+            reason: format!(
+                "Lesson with id={} is still linked to {} teacher(s) and {} student(s)",
+                lesson_id,
+                teachers.len(),
+                students.len().max(lesson.students.len())
+            ),
+        });
+    }
+
+    for mut teacher in teachers {
+        teacher.lessons.retain(|id| id != &lesson_id);
+        do_insert_teacher(&teacher);
+    }
+    for mut student in students {
+        student.lessons.retain(|id| id != &lesson_id);
+        do_insert_student(&student);
+    }
+    LESSON_MAP.with(|service| service.borrow_mut().remove(&lesson_id));
+    attendance::remove_lesson_attendance(lesson_id);
+    gradebook::remove_lesson_grades(lesson_id);
+    rooms::remove_lesson_rooms(lesson_id);
+    eligibility::remove_lesson_eligibility(lesson_id);
+    search::index_lesson(Some(&lesson), None);
+    revisions::remove_revisions(lesson_id);
+    Ok(lesson)
+}
+
+// remove a teacher; with Cascade the lessons they teach are removed too
+pub(crate) fn remove_teacher(teacher_id: u64, mode: DeleteMode) -> Result<Teacher, Error> {
+    let teacher = TEACHER_MAP.with(|service| service.borrow().get(&teacher_id));
+    let teacher = if let Some(teacher) = teacher {
+        teacher
+    } else {
+        return Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id });
+    };
+    let owned = lessons_owned_by_teacher(teacher_id);
+    match mode {
+        DeleteMode::Restrict if !owned.is_empty() || !teacher.lessons.is_empty() => {
+            return Err(Error::Conflict {
+                reason: format!(
+                    "Teacher with id={} is still linked to {} lesson(s)",
+                    teacher_id,
+                    owned.len().max(teacher.lessons.len())
+                ),
+            });
+        }
+        // a lesson cannot exist without its teacher, so detaching is only
+        // possible once the lessons were reassigned with update_lesson
+        DeleteMode::Detach if !owned.is_empty() => {
+            return Err(Error::Conflict {
+                reason: format!(
+                    "Teacher with id={} still teaches {} lesson(s); reassign them or use Cascade",
+                    teacher_id,
+                    owned.len()
+                ),
+            });
+        }
+        _ => {}
+    }
+
+    for lesson in owned {
+        remove_lesson(lesson.id, DeleteMode::Cascade)?;
+    }
+    TEACHER_MAP.with(|service| service.borrow_mut().remove(&teacher_id));
+    Ok(teacher)
+}
+
+// remove a student and strip their id from every lesson
+pub(crate) fn remove_student(student_id: u64, mode: DeleteMode) -> Result<Student, Error> {
+    let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
+    let student = if let Some(student) = student {
+        student
+    } else {
+        return Err(Error::NotFound { entity: Entity::Student, id: student_id });
+    };
+    let lessons = lessons_linked_to_student(student_id);
+    if mode == DeleteMode::Restrict && (!lessons.is_empty() || !student.lessons.is_empty()) {
+        return Err(Error::Conflict {
+            reason: format!(
+                "Student with id={} is still linked to {} lesson(s)",
+                student_id,
+                lessons.len().max(student.lessons.len())
+            ),
+        });
+    }
+
+    for mut lesson in lessons {
+        waitlist::release_student(&mut lesson, student_id);
+        do_insert_lesson(&lesson)?;
+    }
+    STUDENT_MAP.with(|service| service.borrow_mut().remove(&student_id));
+    attendance::remove_student_attendance(student_id);
+    gradebook::remove_student_scores(student_id);
+    eligibility::remove_student_completions(student_id);
+    Ok(student)
+}
+
+// the referenced records must exist before they can be linked
+pub(crate) fn require_lesson_exists(lesson_id: u64) -> Result<(), Error> {
+    if LESSON_MAP.with(|service| service.borrow().contains_key(&lesson_id)) {
+        Ok(())
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+pub(crate) fn require_teacher_exists(teacher_id: u64) -> Result<(), Error> {
+    if TEACHER_MAP.with(|service| service.borrow().contains_key(&teacher_id)) {
+        Ok(())
+    } else {
+        Err(Error::NotFound { entity: Entity::Teacher, id: teacher_id })
+    }
+}
+
+pub(crate) fn require_student_exists(student_id: u64) -> Result<(), Error> {
+    if STUDENT_MAP.with(|service| service.borrow().contains_key(&student_id)) {
+        Ok(())
+    } else {
+        Err(Error::NotFound { entity: Entity::Student, id: student_id })
+    }
+}
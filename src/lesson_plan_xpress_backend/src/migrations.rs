@@ -0,0 +1,1326 @@
+use crate::attendance::AttendanceSession;
+use crate::backup;
+use crate::auth::require_admin;
+use crate::schedule::{parse_legacy_time, Weekday};
+use crate::plan::LessonPlan;
+use crate::revisions::record_initial_revisions;
+use crate::search::rebuild_lesson_indexes;
+use crate::calendar::{Holiday, Term};
+use crate::eligibility::{canonical_grade_level, Completions, Eligibility};
+use crate::gradebook::{Assignment, GradeWeights};
+use crate::rooms::{LessonRooms, Room};
+use crate::templates::{LessonSource, LessonTemplate};
+use crate::{Error, Lesson, Memory, ScheduleEntry, Student, Teacher, MEMORY_MANAGER};
+use crate::{LESSON_MAP, SCHEDULE_ENTRY_MAP, STUDENT_MAP, TEACHER_MAP};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use std::{borrow::Cow, cell::RefCell};
+
+// Layout version of the stable memory written by this code. Bump it and add
+// a step to MIGRATIONS whenever a stored record changes shape.
+//   1: original layout, schedule times as free text
+//   2: schedule entries with a weekday and minutes since midnight
+//   3: records wrapped in versioned envelopes
+//   4: secondary indexes for search_lessons
+//   5: lessons carry a structured lesson plan
+//   6: lesson revisions, existing lessons start at revision 1
+//   7: lessons link back to the lesson or template they were cloned from
+//   8: lessons are bound to an academic term
+//   9: lessons have a capacity and a waitlist
+//  10: grade levels of lessons and students in their normalized form
+pub(crate) const SCHEMA_VERSION: u32 = 10;
+
+// Steps run on upgrade, each bringing the memory to the version it is listed with
+const MIGRATIONS: &[(u32, fn())] = &[
+    (2, migrate_schedule_entries),
+    (3, wrap_records),
+    (4, rebuild_lesson_indexes),
+    (5, rewrite_lessons),
+    (6, record_initial_revisions),
+    (7, rewrite_lessons),
+    (8, rewrite_lessons),
+    (9, rewrite_lessons),
+    (10, normalize_grade_levels),
+];
+
+// Memory ids of the maps whose records are migrated in place
+const LESSON_MEMORY_ID: u8 = 6;
+const TEACHER_MEMORY_ID: u8 = 5;
+const SCHEDULE_ENTRY_MEMORY_ID: u8 = 7;
+
+// Layout of ScheduleEntry before day and times were structured
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LegacyScheduleEntry {
+    id: u64,
+    day: String,
+    start_time: String,
+    end_time: String,
+}
+
+impl LegacyScheduleEntry {
+    // None when the free text cannot be read as a valid slot
+    fn upgrade(&self) -> Option<ScheduleEntry> {
+        let day = Weekday::parse(&self.day)?;
+        let start_minute = parse_legacy_time(&self.start_time)?;
+        let end_minute = parse_legacy_time(&self.end_time)?;
+        if start_minute >= end_minute {
+            return None;
+        }
+        Some(ScheduleEntry {
+            id: self.id,
+            day,
+            start_minute,
+            end_minute,
+        })
+    }
+}
+
+impl Storable for LegacyScheduleEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyLesson {
+    id: u64,
+    title: String,
+    description: String,
+    grade_level: String,
+    subject: String,
+    teacher_id: u64,
+    students: Vec<u64>,
+    schedule: Vec<LegacyScheduleEntry>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyTeacher {
+    id: u64,
+    name: String,
+    subject: String,
+    lessons: Vec<u64>,
+    availability: Vec<LegacyScheduleEntry>,
+}
+
+// Layout of Lesson before it had a lesson plan
+#[derive(candid::CandidType, Deserialize)]
+struct LessonV2 {
+    id: u64,
+    title: String,
+    description: String,
+    grade_level: String,
+    subject: String,
+    teacher_id: u64,
+    students: Vec<u64>,
+    schedule: Vec<ScheduleEntry>,
+}
+
+impl From<LessonV2> for Lesson {
+    fn from(lesson: LessonV2) -> Self {
+        Lesson {
+            id: lesson.id,
+            title: lesson.title,
+            description: lesson.description,
+            grade_level: lesson.grade_level,
+            subject: lesson.subject,
+            teacher_id: lesson.teacher_id,
+            students: lesson.students,
+            schedule: lesson.schedule,
+            plan: LessonPlan::default(),
+            cloned_from: None,
+            term_id: None,
+            capacity: None,
+            waitlist: Vec::new(),
+        }
+    }
+}
+
+// Layout of Lesson before it linked back to its source
+#[derive(candid::CandidType, Deserialize)]
+struct LessonV3 {
+    id: u64,
+    title: String,
+    description: String,
+    grade_level: String,
+    subject: String,
+    teacher_id: u64,
+    students: Vec<u64>,
+    schedule: Vec<ScheduleEntry>,
+    plan: LessonPlan,
+}
+
+impl From<LessonV3> for Lesson {
+    fn from(lesson: LessonV3) -> Self {
+        Lesson {
+            id: lesson.id,
+            title: lesson.title,
+            description: lesson.description,
+            grade_level: lesson.grade_level,
+            subject: lesson.subject,
+            teacher_id: lesson.teacher_id,
+            students: lesson.students,
+            schedule: lesson.schedule,
+            plan: lesson.plan,
+            cloned_from: None,
+            term_id: None,
+            capacity: None,
+            waitlist: Vec::new(),
+        }
+    }
+}
+
+// Layout of Lesson before it was bound to a term
+#[derive(candid::CandidType, Deserialize)]
+struct LessonV4 {
+    id: u64,
+    title: String,
+    description: String,
+    grade_level: String,
+    subject: String,
+    teacher_id: u64,
+    students: Vec<u64>,
+    schedule: Vec<ScheduleEntry>,
+    plan: LessonPlan,
+    cloned_from: Option<LessonSource>,
+}
+
+impl From<LessonV4> for Lesson {
+    fn from(lesson: LessonV4) -> Self {
+        Lesson {
+            id: lesson.id,
+            title: lesson.title,
+            description: lesson.description,
+            grade_level: lesson.grade_level,
+            subject: lesson.subject,
+            teacher_id: lesson.teacher_id,
+            students: lesson.students,
+            schedule: lesson.schedule,
+            plan: lesson.plan,
+            cloned_from: lesson.cloned_from,
+            term_id: None,
+            capacity: None,
+            waitlist: Vec::new(),
+        }
+    }
+}
+
+// Layout of Lesson before it had a capacity and a waitlist
+#[derive(candid::CandidType, Deserialize)]
+struct LessonV5 {
+    id: u64,
+    title: String,
+    description: String,
+    grade_level: String,
+    subject: String,
+    teacher_id: u64,
+    students: Vec<u64>,
+    schedule: Vec<ScheduleEntry>,
+    plan: LessonPlan,
+    cloned_from: Option<LessonSource>,
+    term_id: Option<u64>,
+}
+
+impl From<LessonV5> for Lesson {
+    fn from(lesson: LessonV5) -> Self {
+        Lesson {
+            id: lesson.id,
+            title: lesson.title,
+            description: lesson.description,
+            grade_level: lesson.grade_level,
+            subject: lesson.subject,
+            teacher_id: lesson.teacher_id,
+            students: lesson.students,
+            schedule: lesson.schedule,
+            plan: lesson.plan,
+            cloned_from: lesson.cloned_from,
+            term_id: lesson.term_id,
+            capacity: None,
+            waitlist: Vec::new(),
+        }
+    }
+}
+
+// Undecoded value of a stable map, used to rewrite records whose layout no
+// longer matches their Rust type
+struct RawRecord(Vec<u8>);
+
+impl Storable for RawRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawRecord(bytes.into_owned())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Envelopes records are stored in. A new layout of a record gets a new
+// variant and the conversion from the previous one in its From impl, so
+// records written by any earlier version still decode.
+#[derive(CandidType, Deserialize)]
+enum LessonRecord {
+    V2(LessonV2),
+    V3(LessonV3),
+    V4(LessonV4),
+    V5(LessonV5),
+    V6(Lesson),
+}
+
+#[derive(CandidType)]
+enum LessonRecordRef<'a> {
+    V6(&'a Lesson),
+}
+
+impl From<LessonRecord> for Lesson {
+    fn from(record: LessonRecord) -> Self {
+        match record {
+            LessonRecord::V2(lesson) => lesson.into(),
+            LessonRecord::V3(lesson) => lesson.into(),
+            LessonRecord::V4(lesson) => lesson.into(),
+            LessonRecord::V5(lesson) => lesson.into(),
+            LessonRecord::V6(lesson) => lesson,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum TeacherRecord {
+    V2(Teacher),
+}
+
+#[derive(CandidType)]
+enum TeacherRecordRef<'a> {
+    V2(&'a Teacher),
+}
+
+impl From<TeacherRecord> for Teacher {
+    fn from(record: TeacherRecord) -> Self {
+        match record {
+            TeacherRecord::V2(teacher) => teacher,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum StudentRecord {
+    V1(Student),
+}
+
+#[derive(CandidType)]
+enum StudentRecordRef<'a> {
+    V1(&'a Student),
+}
+
+impl From<StudentRecord> for Student {
+    fn from(record: StudentRecord) -> Self {
+        match record {
+            StudentRecord::V1(student) => student,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum ScheduleEntryRecord {
+    V2(ScheduleEntry),
+}
+
+#[derive(CandidType)]
+enum ScheduleEntryRecordRef<'a> {
+    V2(&'a ScheduleEntry),
+}
+
+impl From<ScheduleEntryRecord> for ScheduleEntry {
+    fn from(record: ScheduleEntryRecord) -> Self {
+        match record {
+            ScheduleEntryRecord::V2(entry) => entry,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum TemplateRecord {
+    V1(LessonTemplate),
+}
+
+#[derive(CandidType)]
+enum TemplateRecordRef<'a> {
+    V1(&'a LessonTemplate),
+}
+
+impl From<TemplateRecord> for LessonTemplate {
+    fn from(record: TemplateRecord) -> Self {
+        match record {
+            TemplateRecord::V1(template) => template,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum TermRecord {
+    V1(Term),
+}
+
+#[derive(CandidType)]
+enum TermRecordRef<'a> {
+    V1(&'a Term),
+}
+
+impl From<TermRecord> for Term {
+    fn from(record: TermRecord) -> Self {
+        match record {
+            TermRecord::V1(term) => term,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum HolidayRecord {
+    V1(Holiday),
+}
+
+#[derive(CandidType)]
+enum HolidayRecordRef<'a> {
+    V1(&'a Holiday),
+}
+
+impl From<HolidayRecord> for Holiday {
+    fn from(record: HolidayRecord) -> Self {
+        match record {
+            HolidayRecord::V1(holiday) => holiday,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum AttendanceSessionRecord {
+    V1(AttendanceSession),
+}
+
+#[derive(CandidType)]
+enum AttendanceSessionRecordRef<'a> {
+    V1(&'a AttendanceSession),
+}
+
+impl From<AttendanceSessionRecord> for AttendanceSession {
+    fn from(record: AttendanceSessionRecord) -> Self {
+        match record {
+            AttendanceSessionRecord::V1(session) => session,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum AssignmentRecord {
+    V1(Assignment),
+}
+
+#[derive(CandidType)]
+enum AssignmentRecordRef<'a> {
+    V1(&'a Assignment),
+}
+
+impl From<AssignmentRecord> for Assignment {
+    fn from(record: AssignmentRecord) -> Self {
+        match record {
+            AssignmentRecord::V1(assignment) => assignment,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum GradeWeightsRecord {
+    V1(GradeWeights),
+}
+
+#[derive(CandidType)]
+enum GradeWeightsRecordRef<'a> {
+    V1(&'a GradeWeights),
+}
+
+impl From<GradeWeightsRecord> for GradeWeights {
+    fn from(record: GradeWeightsRecord) -> Self {
+        match record {
+            GradeWeightsRecord::V1(weights) => weights,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum RoomRecord {
+    V1(Room),
+}
+
+#[derive(CandidType)]
+enum RoomRecordRef<'a> {
+    V1(&'a Room),
+}
+
+impl From<RoomRecord> for Room {
+    fn from(record: RoomRecord) -> Self {
+        match record {
+            RoomRecord::V1(room) => room,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum LessonRoomsRecord {
+    V1(LessonRooms),
+}
+
+#[derive(CandidType)]
+enum LessonRoomsRecordRef<'a> {
+    V1(&'a LessonRooms),
+}
+
+impl From<LessonRoomsRecord> for LessonRooms {
+    fn from(record: LessonRoomsRecord) -> Self {
+        match record {
+            LessonRoomsRecord::V1(rooms) => rooms,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum EligibilityRecord {
+    V1(Eligibility),
+}
+
+#[derive(CandidType)]
+enum EligibilityRecordRef<'a> {
+    V1(&'a Eligibility),
+}
+
+impl From<EligibilityRecord> for Eligibility {
+    fn from(record: EligibilityRecord) -> Self {
+        match record {
+            EligibilityRecord::V1(rules) => rules,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum CompletionsRecord {
+    V1(Completions),
+}
+
+#[derive(CandidType)]
+enum CompletionsRecordRef<'a> {
+    V1(&'a Completions),
+}
+
+impl From<CompletionsRecord> for Completions {
+    fn from(record: CompletionsRecord) -> Self {
+        match record {
+            CompletionsRecord::V1(completions) => completions,
+        }
+    }
+}
+
+pub(crate) fn encode_lesson(lesson: &Lesson) -> Vec<u8> {
+    Encode!(&LessonRecordRef::V6(lesson)).unwrap()
+}
+
+pub(crate) fn encode_teacher(teacher: &Teacher) -> Vec<u8> {
+    Encode!(&TeacherRecordRef::V2(teacher)).unwrap()
+}
+
+pub(crate) fn encode_student(student: &Student) -> Vec<u8> {
+    Encode!(&StudentRecordRef::V1(student)).unwrap()
+}
+
+pub(crate) fn encode_schedule_entry(entry: &ScheduleEntry) -> Vec<u8> {
+    Encode!(&ScheduleEntryRecordRef::V2(entry)).unwrap()
+}
+
+pub(crate) fn encode_template(template: &LessonTemplate) -> Vec<u8> {
+    Encode!(&TemplateRecordRef::V1(template)).unwrap()
+}
+
+pub(crate) fn encode_term(term: &Term) -> Vec<u8> {
+    Encode!(&TermRecordRef::V1(term)).unwrap()
+}
+
+pub(crate) fn encode_holiday(holiday: &Holiday) -> Vec<u8> {
+    Encode!(&HolidayRecordRef::V1(holiday)).unwrap()
+}
+
+pub(crate) fn encode_attendance_session(session: &AttendanceSession) -> Vec<u8> {
+    Encode!(&AttendanceSessionRecordRef::V1(session)).unwrap()
+}
+
+pub(crate) fn encode_assignment(assignment: &Assignment) -> Vec<u8> {
+    Encode!(&AssignmentRecordRef::V1(assignment)).unwrap()
+}
+
+pub(crate) fn encode_grade_weights(weights: &GradeWeights) -> Vec<u8> {
+    Encode!(&GradeWeightsRecordRef::V1(weights)).unwrap()
+}
+
+pub(crate) fn encode_room(room: &Room) -> Vec<u8> {
+    Encode!(&RoomRecordRef::V1(room)).unwrap()
+}
+
+pub(crate) fn encode_lesson_rooms(rooms: &LessonRooms) -> Vec<u8> {
+    Encode!(&LessonRoomsRecordRef::V1(rooms)).unwrap()
+}
+
+pub(crate) fn encode_eligibility(rules: &Eligibility) -> Vec<u8> {
+    Encode!(&EligibilityRecordRef::V1(rules)).unwrap()
+}
+
+pub(crate) fn encode_completions(completions: &Completions) -> Vec<u8> {
+    Encode!(&CompletionsRecordRef::V1(completions)).unwrap()
+}
+
+// Decode a record from its envelope `E`, converting older versions to `T`.
+// Records written before envelopes existed are read in layout `B`.
+pub(crate) fn decode_record<E, B, T>(bytes: &[u8]) -> T
+where
+    E: CandidType + DeserializeOwned + Into<T>,
+    B: CandidType + DeserializeOwned + Into<T>,
+{
+    match Decode!(bytes, E) {
+        Ok(record) => record.into(),
+        // written before records were wrapped in envelopes
+        Err(_) => Decode!(bytes, B).unwrap().into(),
+    }
+}
+
+pub(crate) fn decode_lesson(bytes: &[u8]) -> Lesson {
+    decode_record::<LessonRecord, LessonV2, _>(bytes)
+}
+
+pub(crate) fn decode_teacher(bytes: &[u8]) -> Teacher {
+    decode_record::<TeacherRecord, Teacher, _>(bytes)
+}
+
+pub(crate) fn decode_student(bytes: &[u8]) -> Student {
+    decode_record::<StudentRecord, Student, _>(bytes)
+}
+
+pub(crate) fn decode_schedule_entry(bytes: &[u8]) -> ScheduleEntry {
+    decode_record::<ScheduleEntryRecord, ScheduleEntry, _>(bytes)
+}
+
+pub(crate) fn decode_template(bytes: &[u8]) -> LessonTemplate {
+    decode_record::<TemplateRecord, LessonTemplate, _>(bytes)
+}
+
+pub(crate) fn decode_term(bytes: &[u8]) -> Term {
+    decode_record::<TermRecord, Term, _>(bytes)
+}
+
+pub(crate) fn decode_holiday(bytes: &[u8]) -> Holiday {
+    decode_record::<HolidayRecord, Holiday, _>(bytes)
+}
+
+pub(crate) fn decode_attendance_session(bytes: &[u8]) -> AttendanceSession {
+    decode_record::<AttendanceSessionRecord, AttendanceSession, _>(bytes)
+}
+
+pub(crate) fn decode_assignment(bytes: &[u8]) -> Assignment {
+    decode_record::<AssignmentRecord, Assignment, _>(bytes)
+}
+
+pub(crate) fn decode_grade_weights(bytes: &[u8]) -> GradeWeights {
+    decode_record::<GradeWeightsRecord, GradeWeights, _>(bytes)
+}
+
+pub(crate) fn decode_room(bytes: &[u8]) -> Room {
+    decode_record::<RoomRecord, Room, _>(bytes)
+}
+
+pub(crate) fn decode_lesson_rooms(bytes: &[u8]) -> LessonRooms {
+    decode_record::<LessonRoomsRecord, LessonRooms, _>(bytes)
+}
+
+pub(crate) fn decode_eligibility(bytes: &[u8]) -> Eligibility {
+    decode_record::<EligibilityRecord, Eligibility, _>(bytes)
+}
+
+pub(crate) fn decode_completions(bytes: &[u8]) -> Completions {
+    decode_record::<CompletionsRecord, Completions, _>(bytes)
+}
+
+thread_local! {
+    // version of the layout the stable memory is in; canisters that predate
+    // the counter start at 1
+    static STORED_SCHEMA_VERSION: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))), 1)
+            .expect("Cannot create schema version")
+    );
+
+    // schedule entries whose free text could not be converted, kept so an
+    // admin can re-enter them
+    static UNMIGRATED_SCHEDULE_MAP: RefCell<StableBTreeMap<u64, LegacyScheduleEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
+    );
+}
+
+// Write to the canister log. Unit tests run the steps outside a canister,
+// where there is none.
+fn log(message: &str) {
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::println!("{}", message);
+    #[cfg(not(target_arch = "wasm32"))]
+    let _ = message;
+}
+
+// What to do with a record that does not decode in the current layout
+enum Migrated {
+    Rewrite(Vec<u8>),
+    Drop,
+    // unknown layout, left for a later migration to deal with
+    Keep,
+}
+
+fn raw_map(memory_id: u8) -> StableBTreeMap<u64, RawRecord, Memory> {
+    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))))
+}
+
+// Rewrite every record of a map that no longer decodes as `T`
+fn migrate_map<T, F>(memory_id: u8, mut convert: F)
+where
+    T: candid::CandidType + serde::de::DeserializeOwned,
+    F: FnMut(u64, &[u8]) -> Migrated,
+{
+    let mut map = raw_map(memory_id);
+    let records: Vec<(u64, RawRecord)> = map.iter().collect();
+    for (id, record) in records {
+        if Decode!(&record.0, T).is_ok() {
+            continue;
+        }
+        match convert(id, &record.0) {
+            Migrated::Rewrite(bytes) => {
+                map.insert(id, RawRecord(bytes));
+            }
+            Migrated::Drop => {
+                map.remove(&id);
+            }
+            Migrated::Keep => {}
+        }
+    }
+}
+
+// converts the embedded copies of schedule entries, dropping unreadable ones;
+// their originals are kept in UNMIGRATED_SCHEDULE_MAP
+fn upgrade_entries(entries: &[LegacyScheduleEntry]) -> Vec<ScheduleEntry> {
+    entries.iter().filter_map(LegacyScheduleEntry::upgrade).collect()
+}
+
+fn migrate_schedule_entries() {
+    // records already in the structured layout decode as the bare struct
+    migrate_map::<ScheduleEntry, _>(SCHEDULE_ENTRY_MEMORY_ID, |id, bytes| {
+        let legacy = match Decode!(bytes, LegacyScheduleEntry) {
+            Ok(legacy) => legacy,
+            Err(_) => return Migrated::Keep,
+        };
+        if let Some(entry) = legacy.upgrade() {
+            Migrated::Rewrite(entry.to_bytes().into_owned())
+        } else {
+            log(&format!("schedule entry {} could not be migrated", id));
+            UNMIGRATED_SCHEDULE_MAP.with(|service| service.borrow_mut().insert(id, legacy));
+            Migrated::Drop
+        }
+    });
+    migrate_map::<LessonV2, _>(LESSON_MEMORY_ID, |_, bytes| {
+        let legacy = match Decode!(bytes, LegacyLesson) {
+            Ok(legacy) => legacy,
+            Err(_) => return Migrated::Keep,
+        };
+        let lesson = Lesson {
+            id: legacy.id,
+            title: legacy.title,
+            description: legacy.description,
+            grade_level: legacy.grade_level,
+            subject: legacy.subject,
+            teacher_id: legacy.teacher_id,
+            students: legacy.students,
+            schedule: upgrade_entries(&legacy.schedule),
+            plan: LessonPlan::default(),
+            cloned_from: None,
+            term_id: None,
+            capacity: None,
+            waitlist: Vec::new(),
+        };
+        Migrated::Rewrite(lesson.to_bytes().into_owned())
+    });
+    migrate_map::<Teacher, _>(TEACHER_MEMORY_ID, |_, bytes| {
+        let legacy = match Decode!(bytes, LegacyTeacher) {
+            Ok(legacy) => legacy,
+            Err(_) => return Migrated::Keep,
+        };
+        let teacher = Teacher {
+            id: legacy.id,
+            name: legacy.name,
+            subject: legacy.subject,
+            lessons: legacy.lessons,
+            availability: upgrade_entries(&legacy.availability),
+        };
+        Migrated::Rewrite(teacher.to_bytes().into_owned())
+    });
+}
+
+// Re-insert every lesson so it is written in the current envelope version
+fn rewrite_lessons() {
+    LESSON_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let records: Vec<(u64, Lesson)> = map.iter().collect();
+        for (id, lesson) in records {
+            map.insert(id, lesson);
+        }
+    });
+}
+
+// Re-insert every record so it is written in its envelope
+fn wrap_records() {
+    rewrite_lessons();
+    TEACHER_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let records: Vec<(u64, Teacher)> = map.iter().collect();
+        for (id, teacher) in records {
+            map.insert(id, teacher);
+        }
+    });
+    STUDENT_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let records: Vec<(u64, Student)> = map.iter().collect();
+        for (id, student) in records {
+            map.insert(id, student);
+        }
+    });
+    SCHEDULE_ENTRY_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let records: Vec<(u64, ScheduleEntry)> = map.iter().collect();
+        for (id, entry) in records {
+            map.insert(id, entry);
+        }
+    });
+}
+
+// Store recognized grade levels as "K" or "1" to "12". Levels that are not
+// recognized are kept as they are; lessons with one admit any grade.
+fn normalize_grade_levels() {
+    LESSON_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let records: Vec<(u64, Lesson)> = map.iter().collect();
+        for (id, mut lesson) in records {
+            let grade_level = canonical_grade_level(&lesson.grade_level);
+            if grade_level != lesson.grade_level {
+                lesson.grade_level = grade_level;
+                map.insert(id, lesson);
+            }
+        }
+    });
+    STUDENT_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let records: Vec<(u64, Student)> = map.iter().collect();
+        for (id, mut student) in records {
+            let grade_level = canonical_grade_level(&student.grade_level);
+            if grade_level != student.grade_level {
+                student.grade_level = grade_level;
+                map.insert(id, student);
+            }
+        }
+    });
+    rebuild_lesson_indexes();
+}
+
+fn stored_schema_version() -> u32 {
+    STORED_SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+fn set_stored_schema_version(value: u32) {
+    STORED_SCHEMA_VERSION
+        .with(|version| version.borrow_mut().set(value))
+        .expect("cannot store schema version");
+}
+
+// Bring stable memory from the stored version to SCHEMA_VERSION. Steps that
+// work on raw records run before the typed maps are first touched.
+fn migrate() {
+    let from = stored_schema_version();
+    if from > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "stable memory is at schema version {} but this code only knows {}",
+            from, SCHEMA_VERSION
+        ));
+    }
+    for (version, step) in MIGRATIONS {
+        if *version > from {
+            log(&format!("migrating stable memory to schema version {}", version));
+            step();
+        }
+    }
+    set_stored_schema_version(SCHEMA_VERSION);
+}
+
+#[ic_cdk::init]
+fn init() {
+    set_stored_schema_version(SCHEMA_VERSION);
+}
+
+// Everything in stable memory was written by this code, so record its
+// version for the next post_upgrade.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    set_stored_schema_version(SCHEMA_VERSION);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate();
+    // writes stay blocked until the restore is finished
+    if let Some(chunks) = backup::restore_progress() {
+        log(&format!(
+            "a restore is in progress with {} chunks stored, send the rest and call finish_restore",
+            chunks
+        ));
+    }
+}
+
+// schedule entries dropped by the migration because their text was unreadable
+#[ic_cdk::query]
+fn get_unmigrated_schedule_entries() -> Result<Vec<LegacyScheduleEntry>, Error> {
+    require_admin("read unmigrated schedule entries")?;
+    Ok(UNMIGRATED_SCHEDULE_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::revisions::latest_revision;
+    use crate::search::lessons_of_teacher;
+    use std::collections::BTreeSet;
+
+    const STUDENT_MEMORY_ID: u8 = 4;
+
+    fn legacy_entry(id: u64, day: &str, start_time: &str, end_time: &str) -> LegacyScheduleEntry {
+        LegacyScheduleEntry {
+            id,
+            day: day.to_string(),
+            start_time: start_time.to_string(),
+            end_time: end_time.to_string(),
+        }
+    }
+
+    fn legacy_lesson(id: u64, schedule: Vec<LegacyScheduleEntry>) -> LegacyLesson {
+        LegacyLesson {
+            id,
+            title: "Fractions".to_string(),
+            description: "Adding fractions".to_string(),
+            grade_level: "3rd grade".to_string(),
+            subject: "Math".to_string(),
+            teacher_id: 1,
+            students: vec![7],
+            schedule,
+        }
+    }
+
+    fn legacy_teacher(availability: Vec<LegacyScheduleEntry>) -> LegacyTeacher {
+        LegacyTeacher {
+            id: 1,
+            name: "Ada".to_string(),
+            subject: "Math".to_string(),
+            lessons: vec![3],
+            availability,
+        }
+    }
+
+    fn teacher() -> Teacher {
+        Teacher {
+            id: 1,
+            name: "Ada".to_string(),
+            subject: "Math".to_string(),
+            lessons: vec![3],
+            availability: vec![entry(12)],
+        }
+    }
+
+    fn student(grade_level: &str) -> Student {
+        Student {
+            id: 7,
+            name: "Grace".to_string(),
+            grade_level: grade_level.to_string(),
+            lessons: vec![3],
+        }
+    }
+
+    fn entry(id: u64) -> ScheduleEntry {
+        ScheduleEntry {
+            id,
+            day: Weekday::Monday,
+            start_minute: 9 * 60,
+            end_minute: 10 * 60 + 30,
+        }
+    }
+
+    fn lesson_v2(id: u64) -> LessonV2 {
+        LessonV2 {
+            id,
+            title: "Fractions".to_string(),
+            description: "Adding fractions".to_string(),
+            grade_level: "3".to_string(),
+            subject: "Math".to_string(),
+            teacher_id: 1,
+            students: vec![7],
+            schedule: vec![entry(11)],
+        }
+    }
+
+    fn lesson(id: u64, teacher_id: u64, grade_level: &str) -> Lesson {
+        let mut lesson: Lesson = lesson_v2(id).into();
+        lesson.teacher_id = teacher_id;
+        lesson.grade_level = grade_level.to_string();
+        lesson
+    }
+
+    fn slots(entries: &[ScheduleEntry]) -> Vec<(u64, Weekday, u16, u16)> {
+        entries
+            .iter()
+            .map(|entry| (entry.id, entry.day, entry.start_minute, entry.end_minute))
+            .collect()
+    }
+
+    fn put_raw<T: CandidType>(memory_id: u8, id: u64, record: &T) {
+        raw_map(memory_id).insert(id, RawRecord(Encode!(record).unwrap()));
+    }
+
+    fn get_raw(memory_id: u8, id: u64) -> Vec<u8> {
+        raw_map(memory_id).get(&id).expect("record is missing").0
+    }
+
+    fn assert_current_lesson(bytes: &[u8]) {
+        assert!(matches!(Decode!(bytes, LessonRecord), Ok(LessonRecord::V6(_))));
+    }
+
+    fn assert_lesson_v2_fields(lesson: &Lesson, id: u64) {
+        assert_eq!(lesson.id, id);
+        assert_eq!(lesson.title, "Fractions");
+        assert_eq!(lesson.description, "Adding fractions");
+        assert_eq!(lesson.grade_level, "3");
+        assert_eq!(lesson.subject, "Math");
+        assert_eq!(lesson.teacher_id, 1);
+        assert_eq!(lesson.students, vec![7]);
+        assert_eq!(slots(&lesson.schedule), vec![(11, Weekday::Monday, 540, 630)]);
+    }
+
+    #[test]
+    fn migrations_cover_every_version_in_order() {
+        let versions: Vec<u32> = MIGRATIONS.iter().map(|(version, _)| *version).collect();
+        let expected: Vec<u32> = (2..=SCHEMA_VERSION).collect();
+        assert_eq!(versions, expected);
+    }
+
+    #[test]
+    fn bare_records_decode_in_current_layout() {
+        let lesson = decode_lesson(&Encode!(&lesson_v2(3)).unwrap());
+        assert_lesson_v2_fields(&lesson, 3);
+        assert!(lesson.cloned_from.is_none());
+        assert_eq!(lesson.term_id, None);
+        assert_eq!(lesson.capacity, None);
+        assert!(lesson.waitlist.is_empty());
+
+        let teacher = decode_teacher(&Encode!(&teacher()).unwrap());
+        assert_eq!((teacher.id, teacher.name.as_str(), teacher.subject.as_str()), (1, "Ada", "Math"));
+        assert_eq!(teacher.lessons, vec![3]);
+        assert_eq!(slots(&teacher.availability), vec![(12, Weekday::Monday, 540, 630)]);
+
+        let student = decode_student(&Encode!(&student("4")).unwrap());
+        assert_eq!((student.id, student.name.as_str(), student.grade_level.as_str()), (7, "Grace", "4"));
+        assert_eq!(student.lessons, vec![3]);
+
+        let entry = decode_schedule_entry(&Encode!(&entry(11)).unwrap());
+        assert_eq!(slots(&[entry]), vec![(11, Weekday::Monday, 540, 630)]);
+    }
+
+    #[test]
+    fn every_lesson_envelope_decodes_as_current_lesson() {
+        let v3 = LessonV3 {
+            id: 3,
+            title: "Fractions".to_string(),
+            description: "Adding fractions".to_string(),
+            grade_level: "3".to_string(),
+            subject: "Math".to_string(),
+            teacher_id: 1,
+            students: vec![7],
+            schedule: vec![entry(11)],
+            plan: LessonPlan::default(),
+        };
+        let v4 = LessonV4 {
+            id: 4,
+            title: v3.title.clone(),
+            description: v3.description.clone(),
+            grade_level: v3.grade_level.clone(),
+            subject: v3.subject.clone(),
+            teacher_id: 1,
+            students: vec![7],
+            schedule: vec![entry(11)],
+            plan: LessonPlan::default(),
+            cloned_from: Some(LessonSource::Template(2)),
+        };
+        let v5 = LessonV5 {
+            id: 5,
+            title: v3.title.clone(),
+            description: v3.description.clone(),
+            grade_level: v3.grade_level.clone(),
+            subject: v3.subject.clone(),
+            teacher_id: 1,
+            students: vec![7],
+            schedule: vec![entry(11)],
+            plan: LessonPlan::default(),
+            cloned_from: Some(LessonSource::Lesson(1)),
+            term_id: Some(9),
+        };
+
+        assert_lesson_v2_fields(&decode_lesson(&Encode!(&LessonRecord::V2(lesson_v2(2))).unwrap()), 2);
+        assert_lesson_v2_fields(&decode_lesson(&Encode!(&LessonRecord::V3(v3)).unwrap()), 3);
+        let lesson = decode_lesson(&Encode!(&LessonRecord::V4(v4)).unwrap());
+        assert_lesson_v2_fields(&lesson, 4);
+        assert!(lesson.cloned_from == Some(LessonSource::Template(2)));
+        assert_eq!(lesson.term_id, None);
+        let lesson = decode_lesson(&Encode!(&LessonRecord::V5(v5)).unwrap());
+        assert_lesson_v2_fields(&lesson, 5);
+        assert!(lesson.cloned_from == Some(LessonSource::Lesson(1)));
+        assert_eq!(lesson.term_id, Some(9));
+        assert_eq!(lesson.capacity, None);
+
+        let mut current: Lesson = lesson_v2(6).into();
+        current.capacity = Some(20);
+        current.waitlist.push(8);
+        let lesson = decode_lesson(&encode_lesson(&current));
+        assert_lesson_v2_fields(&lesson, 6);
+        assert_eq!(lesson.capacity, Some(20));
+        assert_eq!(lesson.waitlist, vec![8]);
+    }
+
+    #[test]
+    fn step_2_structures_free_text_schedule_entries() {
+        put_raw(SCHEDULE_ENTRY_MEMORY_ID, 11, &legacy_entry(11, "Monday", "9am", "10.30"));
+        put_raw(SCHEDULE_ENTRY_MEMORY_ID, 12, &legacy_entry(12, "tue", "2:15 PM", "15:00"));
+        put_raw(SCHEDULE_ENTRY_MEMORY_ID, 13, &legacy_entry(13, "Funday", "soon", "later"));
+        put_raw(SCHEDULE_ENTRY_MEMORY_ID, 14, &legacy_entry(14, "Friday", "11:00", "10:00"));
+        let schedule = vec![legacy_entry(11, "Monday", "9am", "10.30"), legacy_entry(13, "Funday", "soon", "later")];
+        put_raw(LESSON_MEMORY_ID, 3, &legacy_lesson(3, schedule));
+        let availability = vec![legacy_entry(12, "tue", "2:15 PM", "15:00"), legacy_entry(14, "Friday", "11:00", "10:00")];
+        put_raw(TEACHER_MEMORY_ID, 1, &legacy_teacher(availability));
+
+        migrate_schedule_entries();
+
+        let entries = raw_map(SCHEDULE_ENTRY_MEMORY_ID);
+        assert_eq!(entries.len(), 2);
+        let monday = decode_schedule_entry(&get_raw(SCHEDULE_ENTRY_MEMORY_ID, 11));
+        assert_eq!(slots(&[monday]), vec![(11, Weekday::Monday, 540, 630)]);
+        let tuesday = decode_schedule_entry(&get_raw(SCHEDULE_ENTRY_MEMORY_ID, 12));
+        assert_eq!(slots(&[tuesday]), vec![(12, Weekday::Tuesday, 855, 900)]);
+
+        let unmigrated: Vec<(u64, String)> = UNMIGRATED_SCHEDULE_MAP
+            .with(|service| service.borrow().iter().map(|(id, entry)| (id, entry.day)).collect());
+        assert_eq!(unmigrated, vec![(13, "Funday".to_string()), (14, "Friday".to_string())]);
+
+        let lesson = decode_lesson(&get_raw(LESSON_MEMORY_ID, 3));
+        assert_eq!(lesson.grade_level, "3rd grade");
+        assert_eq!(slots(&lesson.schedule), vec![(11, Weekday::Monday, 540, 630)]);
+        let teacher = decode_teacher(&get_raw(TEACHER_MEMORY_ID, 1));
+        assert_eq!(slots(&teacher.availability), vec![(12, Weekday::Tuesday, 855, 900)]);
+
+        // a second run finds nothing left to convert
+        migrate_schedule_entries();
+        assert_eq!(raw_map(SCHEDULE_ENTRY_MEMORY_ID).len(), 2);
+        assert_eq!(UNMIGRATED_SCHEDULE_MAP.with(|service| service.borrow().len()), 2);
+    }
+
+    #[test]
+    fn step_3_wraps_bare_records_in_envelopes() {
+        put_raw(LESSON_MEMORY_ID, 3, &lesson_v2(3));
+        put_raw(TEACHER_MEMORY_ID, 1, &teacher());
+        put_raw(STUDENT_MEMORY_ID, 7, &student("4"));
+        put_raw(SCHEDULE_ENTRY_MEMORY_ID, 11, &entry(11));
+
+        wrap_records();
+
+        assert_current_lesson(&get_raw(LESSON_MEMORY_ID, 3));
+        assert!(matches!(Decode!(&get_raw(TEACHER_MEMORY_ID, 1), TeacherRecord), Ok(TeacherRecord::V2(_))));
+        assert!(matches!(Decode!(&get_raw(STUDENT_MEMORY_ID, 7), StudentRecord), Ok(StudentRecord::V1(_))));
+        assert!(matches!(
+            Decode!(&get_raw(SCHEDULE_ENTRY_MEMORY_ID, 11), ScheduleEntryRecord),
+            Ok(ScheduleEntryRecord::V2(_))
+        ));
+        assert_lesson_v2_fields(&decode_lesson(&get_raw(LESSON_MEMORY_ID, 3)), 3);
+    }
+
+    #[test]
+    fn step_4_indexes_existing_lessons() {
+        LESSON_MAP.with(|service| {
+            let mut map = service.borrow_mut();
+            map.insert(3, lesson(3, 1, "3"));
+            map.insert(4, lesson(4, 2, "3"));
+            map.insert(5, lesson(5, 1, "4"));
+        });
+        assert!(lessons_of_teacher(1).is_empty());
+
+        rebuild_lesson_indexes();
+
+        assert_eq!(lessons_of_teacher(1), BTreeSet::from([3, 5]));
+        assert_eq!(lessons_of_teacher(2), BTreeSet::from([4]));
+    }
+
+    // the steps that only re-encode lessons, each with the envelope the
+    // lessons were stored in before it
+    fn rewrite_from(record: LessonRecord) -> Lesson {
+        put_raw(LESSON_MEMORY_ID, 3, &record);
+        rewrite_lessons();
+        let bytes = get_raw(LESSON_MEMORY_ID, 3);
+        assert_current_lesson(&bytes);
+        decode_lesson(&bytes)
+    }
+
+    #[test]
+    fn step_5_rewrites_v2_lessons() {
+        let lesson = rewrite_from(LessonRecord::V2(lesson_v2(3)));
+        assert_lesson_v2_fields(&lesson, 3);
+    }
+
+    #[test]
+    fn step_7_rewrites_v3_lessons() {
+        let v2 = lesson_v2(3);
+        let lesson = rewrite_from(LessonRecord::V3(LessonV3 {
+            id: v2.id,
+            title: v2.title,
+            description: v2.description,
+            grade_level: v2.grade_level,
+            subject: v2.subject,
+            teacher_id: v2.teacher_id,
+            students: v2.students,
+            schedule: v2.schedule,
+            plan: LessonPlan::default(),
+        }));
+        assert_lesson_v2_fields(&lesson, 3);
+        assert!(lesson.cloned_from.is_none());
+    }
+
+    #[test]
+    fn step_8_rewrites_v4_lessons() {
+        let v2 = lesson_v2(3);
+        let lesson = rewrite_from(LessonRecord::V4(LessonV4 {
+            id: v2.id,
+            title: v2.title,
+            description: v2.description,
+            grade_level: v2.grade_level,
+            subject: v2.subject,
+            teacher_id: v2.teacher_id,
+            students: v2.students,
+            schedule: v2.schedule,
+            plan: LessonPlan::default(),
+            cloned_from: Some(LessonSource::Lesson(2)),
+        }));
+        assert_lesson_v2_fields(&lesson, 3);
+        assert!(lesson.cloned_from == Some(LessonSource::Lesson(2)));
+        assert_eq!(lesson.term_id, None);
+    }
+
+    #[test]
+    fn step_9_rewrites_v5_lessons() {
+        let v2 = lesson_v2(3);
+        let lesson = rewrite_from(LessonRecord::V5(LessonV5 {
+            id: v2.id,
+            title: v2.title,
+            description: v2.description,
+            grade_level: v2.grade_level,
+            subject: v2.subject,
+            teacher_id: v2.teacher_id,
+            students: v2.students,
+            schedule: v2.schedule,
+            plan: LessonPlan::default(),
+            cloned_from: None,
+            term_id: Some(9),
+        }));
+        assert_lesson_v2_fields(&lesson, 3);
+        assert_eq!(lesson.term_id, Some(9));
+        assert_eq!(lesson.capacity, None);
+        assert!(lesson.waitlist.is_empty());
+    }
+
+    #[test]
+    fn step_6_gives_each_lesson_a_first_revision() {
+        LESSON_MAP.with(|service| {
+            let mut map = service.borrow_mut();
+            map.insert(3, lesson(3, 1, "3"));
+            map.insert(4, lesson(4, 1, "3"));
+        });
+
+        record_initial_revisions();
+        assert_eq!((latest_revision(3), latest_revision(4)), (1, 1));
+
+        // lessons that already have a history are left alone
+        record_initial_revisions();
+        assert_eq!((latest_revision(3), latest_revision(4)), (1, 1));
+    }
+
+    #[test]
+    fn step_10_normalizes_grade_levels() {
+        LESSON_MAP.with(|service| {
+            let mut map = service.borrow_mut();
+            map.insert(3, lesson(3, 1, "3rd grade"));
+            map.insert(4, lesson(4, 1, "Mixed ages"));
+        });
+        STUDENT_MAP.with(|service| service.borrow_mut().insert(7, student("Kindergarten")));
+
+        normalize_grade_levels();
+
+        let grades: Vec<String> = LESSON_MAP.with(|service| {
+            service.borrow().iter().map(|(_, lesson)| lesson.grade_level).collect()
+        });
+        assert_eq!(grades, vec!["3".to_string(), "Mixed ages".to_string()]);
+        let student = STUDENT_MAP.with(|service| service.borrow().get(&7)).unwrap();
+        assert_eq!(student.grade_level, "K");
+        assert_eq!(lessons_of_teacher(1), BTreeSet::from([3, 4]));
+    }
+
+    #[test]
+    fn migrate_upgrades_the_original_layout() {
+        put_raw(SCHEDULE_ENTRY_MEMORY_ID, 11, &legacy_entry(11, "Monday", "9:00", "10:30"));
+        put_raw(SCHEDULE_ENTRY_MEMORY_ID, 12, &legacy_entry(12, "someday", "9", "10"));
+        let schedule = vec![legacy_entry(11, "Monday", "9:00", "10:30"), legacy_entry(12, "someday", "9", "10")];
+        put_raw(LESSON_MEMORY_ID, 3, &legacy_lesson(3, schedule));
+        put_raw(TEACHER_MEMORY_ID, 1, &legacy_teacher(vec![legacy_entry(11, "Monday", "9:00", "10:30")]));
+        put_raw(STUDENT_MEMORY_ID, 7, &student("grade 4"));
+        assert_eq!(stored_schema_version(), 1);
+
+        migrate();
+
+        assert_eq!(stored_schema_version(), SCHEMA_VERSION);
+        assert_current_lesson(&get_raw(LESSON_MEMORY_ID, 3));
+        let lesson = LESSON_MAP.with(|service| service.borrow().get(&3)).unwrap();
+        assert_lesson_v2_fields(&lesson, 3);
+        assert_eq!(latest_revision(3), 1);
+        assert_eq!(lessons_of_teacher(1), BTreeSet::from([3]));
+        let teacher = TEACHER_MAP.with(|service| service.borrow().get(&1)).unwrap();
+        assert_eq!(slots(&teacher.availability), vec![(11, Weekday::Monday, 540, 630)]);
+        let student = STUDENT_MAP.with(|service| service.borrow().get(&7)).unwrap();
+        assert_eq!(student.grade_level, "4");
+        assert_eq!(SCHEDULE_ENTRY_MAP.with(|service| service.borrow().len()), 1);
+        assert!(UNMIGRATED_SCHEDULE_MAP.with(|service| service.borrow().contains_key(&12)));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{Error, Lesson, Memory, ScheduleEntry, Student, Teacher};
+use crate::{LESSON_MAP, SCHEDULE_ENTRY_MAP, STUDENT_MAP, TEACHER_MAP};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::ops::Bound;
+
+// Largest page a single query returns, keeps responses well below the
+// query size and instruction limits
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Most encoded bytes of records in one page, below the ~2MB reply limit. A
+// page holds at least one record, which MAX_LESSON_SIZE keeps below it.
+const MAX_PAGE_BYTES: usize = 1536 * 1024;
+
+// One page of records in id order. Pass next_cursor as start_after to get
+// the following page; it is None on the last page.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Page<T> {
+    pub(crate) items: Vec<T>,
+    pub(crate) next_cursor: Option<u64>,
+    pub(crate) total: u64,
+}
+
+// number of records to return for the requested limit
+pub(crate) fn page_size(limit: u32) -> Result<usize, Error> {
+    if limit == 0 {
+        return Err(Error::InvalidInput {
+            field: "limit".to_string(),
+            reason: "must be greater than 0".to_string(),
+        });
+    }
+    Ok(limit.min(MAX_PAGE_SIZE) as usize)
+}
+
+// Take records in order until `limit` of them or MAX_PAGE_BYTES are
+// reached. The cursor is the id of the last record taken when more follow.
+pub(crate) fn fill_page<V: Storable>(records: impl Iterator<Item = (u64, V)>, limit: usize) -> (Vec<V>, Option<u64>) {
+    let mut items = Vec::new();
+    let mut last_id = None;
+    let mut bytes = 0;
+    for (id, value) in records {
+        let size = value.to_bytes().len();
+        if items.len() == limit || (!items.is_empty() && bytes + size > MAX_PAGE_BYTES) {
+            return (items, last_id);
+        }
+        bytes += size;
+        last_id = Some(id);
+        items.push(value);
+    }
+    (items, None)
+}
+
+// read up to `limit` records with ids greater than `start_after`
+pub(crate) fn page<V: Storable + Clone>(
+    map: &StableBTreeMap<u64, V, Memory>,
+    start_after: Option<u64>,
+    limit: u32,
+) -> Result<Page<V>, Error> {
+    let limit = page_size(limit)?;
+    let start = match start_after {
+        Some(id) => Bound::Excluded(id),
+        None => Bound::Unbounded,
+    };
+    let (items, next_cursor) = fill_page(map.range((start, Bound::Unbounded)), limit);
+    Ok(Page {
+        items,
+        next_cursor,
+        total: map.len(),
+    })
+}
+
+#[ic_cdk::query]
+fn list_lessons(start_after: Option<u64>, limit: u32) -> Result<Page<Lesson>, Error> {
+    LESSON_MAP.with(|service| page(&service.borrow(), start_after, limit))
+}
+
+#[ic_cdk::query]
+fn list_teachers(start_after: Option<u64>, limit: u32) -> Result<Page<Teacher>, Error> {
+    TEACHER_MAP.with(|service| page(&service.borrow(), start_after, limit))
+}
+
+#[ic_cdk::query]
+fn list_students(start_after: Option<u64>, limit: u32) -> Result<Page<Student>, Error> {
+    STUDENT_MAP.with(|service| page(&service.borrow(), start_after, limit))
+}
+
+#[ic_cdk::query]
+fn list_schedule_entries(start_after: Option<u64>, limit: u32) -> Result<Page<ScheduleEntry>, Error> {
+    SCHEDULE_ENTRY_MAP.with(|service| page(&service.borrow(), start_after, limit))
+}
@@ -0,0 +1,212 @@
+use crate::audit::{audited, record};
+use crate::auth::require_teacher;
+use crate::{do_insert_lesson, require_max_size, require_not_empty, Entity, Error, Lesson, LESSON_MAP};
+use crate::revisions::{push_change, record_revision, FieldChange};
+use crate::{MAX_DESCRIPTION_SIZE, MAX_NAME_SIZE};
+
+// Most entries a single list section of a plan may hold
+const MAX_SECTION_ENTRIES: usize = 100;
+
+// Part of the lesson an activity belongs to, in teaching order
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ActivityPhase {
+    WarmUp,
+    Instruction,
+    Practice,
+    Closure,
+}
+
+// One timed step of the activity sequence
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Activity {
+    phase: ActivityPhase,
+    title: String,
+    description: String,
+    duration_minutes: u16,
+}
+
+impl ActivityPhase {
+    fn name(&self) -> &'static str {
+        match self {
+            ActivityPhase::WarmUp => "Warm-up",
+            ActivityPhase::Instruction => "Instruction",
+            ActivityPhase::Practice => "Practice",
+            ActivityPhase::Closure => "Closure",
+        }
+    }
+}
+
+// Structured content of a lesson plan
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct LessonPlan {
+    objectives: Vec<String>,
+    materials: Vec<String>,
+    // run in the order given
+    activities: Vec<Activity>,
+    differentiation: String,
+    homework: String,
+    assessment_criteria: Vec<String>,
+}
+
+fn render_activities(activities: &[Activity]) -> String {
+    let lines: Vec<String> = activities
+        .iter()
+        .map(|activity| {
+            format!(
+                "{} ({} min): {} - {}",
+                activity.phase.name(),
+                activity.duration_minutes,
+                activity.title,
+                activity.description
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+// add a change for every section that differs between two plans
+pub(crate) fn diff_plans(changes: &mut Vec<FieldChange>, before: &LessonPlan, after: &LessonPlan) {
+    push_change(changes, "plan.objectives", before.objectives.join("\n"), after.objectives.join("\n"));
+    push_change(changes, "plan.materials", before.materials.join("\n"), after.materials.join("\n"));
+    push_change(
+        changes,
+        "plan.activities",
+        render_activities(&before.activities),
+        render_activities(&after.activities),
+    );
+    push_change(
+        changes,
+        "plan.differentiation",
+        before.differentiation.clone(),
+        after.differentiation.clone(),
+    );
+    push_change(changes, "plan.homework", before.homework.clone(), after.homework.clone());
+    push_change(
+        changes,
+        "plan.assessment_criteria",
+        before.assessment_criteria.join("\n"),
+        after.assessment_criteria.join("\n"),
+    );
+}
+
+fn validate_entries(field: &str, entries: &[String]) -> Result<(), Error> {
+    if entries.len() > MAX_SECTION_ENTRIES {
+        return Err(Error::InvalidInput {
+            field: field.to_string(),
+            reason: format!("must have at most {} entries", MAX_SECTION_ENTRIES),
+        });
+    }
+    for entry in entries {
+        require_not_empty(field, entry)?;
+        require_max_size(field, entry, MAX_DESCRIPTION_SIZE)?;
+    }
+    Ok(())
+}
+
+fn validate_activities(activities: &[Activity]) -> Result<(), Error> {
+    if activities.len() > MAX_SECTION_ENTRIES {
+        return Err(Error::InvalidInput {
+            field: "activities".to_string(),
+            reason: format!("must have at most {} entries", MAX_SECTION_ENTRIES),
+        });
+    }
+    for activity in activities {
+        require_not_empty("activities.title", &activity.title)?;
+        require_max_size("activities.title", &activity.title, MAX_NAME_SIZE)?;
+        require_max_size("activities.description", &activity.description, MAX_DESCRIPTION_SIZE)?;
+        if activity.duration_minutes == 0 {
+            return Err(Error::InvalidInput {
+                field: "activities.duration_minutes".to_string(),
+                reason: "must be greater than 0".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_plan(plan: &LessonPlan) -> Result<(), Error> {
+    validate_entries("objectives", &plan.objectives)?;
+    validate_entries("materials", &plan.materials)?;
+    validate_activities(&plan.activities)?;
+    require_max_size("differentiation", &plan.differentiation, MAX_DESCRIPTION_SIZE)?;
+    require_max_size("homework", &plan.homework, MAX_DESCRIPTION_SIZE)?;
+    validate_entries("assessment_criteria", &plan.assessment_criteria)
+}
+
+// load a lesson, let its teacher change the plan and store it again
+fn edit_plan<F>(lesson_id: u64, edit: F) -> Result<Lesson, Error>
+where
+    F: FnOnce(&mut LessonPlan),
+{
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    let mut lesson = if let Some(lesson) = lesson {
+        lesson
+    } else {
+        return Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id });
+    };
+    require_teacher(lesson.teacher_id, "edit the plan of this lesson")?;
+    edit(&mut lesson.plan);
+    do_insert_lesson(&lesson)?;
+    record_revision(&lesson);
+    Ok(lesson)
+}
+
+#[ic_cdk::query]
+fn get_lesson_plan(lesson_id: u64) -> Result<LessonPlan, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson.plan)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+// Each section of a plan is replaced on its own, so a small edit does not
+// resend the whole plan. Empty values clear the section.
+#[ic_cdk::update]
+fn set_lesson_objectives(lesson_id: u64, objectives: Vec<String>) -> Result<Lesson, Error> {
+    audited("set_lesson_objectives", &[record(Entity::Lesson, lesson_id)], || {
+        validate_entries("objectives", &objectives)?;
+        edit_plan(lesson_id, |plan| plan.objectives = objectives)
+    })
+}
+
+#[ic_cdk::update]
+fn set_lesson_materials(lesson_id: u64, materials: Vec<String>) -> Result<Lesson, Error> {
+    audited("set_lesson_materials", &[record(Entity::Lesson, lesson_id)], || {
+        validate_entries("materials", &materials)?;
+        edit_plan(lesson_id, |plan| plan.materials = materials)
+    })
+}
+
+#[ic_cdk::update]
+fn set_lesson_activities(lesson_id: u64, activities: Vec<Activity>) -> Result<Lesson, Error> {
+    audited("set_lesson_activities", &[record(Entity::Lesson, lesson_id)], || {
+        validate_activities(&activities)?;
+        edit_plan(lesson_id, |plan| plan.activities = activities)
+    })
+}
+
+#[ic_cdk::update]
+fn set_lesson_differentiation(lesson_id: u64, differentiation: String) -> Result<Lesson, Error> {
+    audited("set_lesson_differentiation", &[record(Entity::Lesson, lesson_id)], || {
+        require_max_size("differentiation", &differentiation, MAX_DESCRIPTION_SIZE)?;
+        edit_plan(lesson_id, |plan| plan.differentiation = differentiation)
+    })
+}
+
+#[ic_cdk::update]
+fn set_lesson_homework(lesson_id: u64, homework: String) -> Result<Lesson, Error> {
+    audited("set_lesson_homework", &[record(Entity::Lesson, lesson_id)], || {
+        require_max_size("homework", &homework, MAX_DESCRIPTION_SIZE)?;
+        edit_plan(lesson_id, |plan| plan.homework = homework)
+    })
+}
+
+#[ic_cdk::update]
+fn set_lesson_assessment_criteria(lesson_id: u64, assessment_criteria: Vec<String>) -> Result<Lesson, Error> {
+    audited("set_lesson_assessment_criteria", &[record(Entity::Lesson, lesson_id)], || {
+        validate_entries("assessment_criteria", &assessment_criteria)?;
+        edit_plan(lesson_id, |plan| plan.assessment_criteria = assessment_criteria)
+    })
+}
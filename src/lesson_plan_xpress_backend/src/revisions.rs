@@ -0,0 +1,264 @@
+use crate::audit::{audited, record};
+use crate::auth::require_teacher;
+use crate::pagination::{page_size, Page};
+use crate::{do_insert_lesson, eligibility, migrations, plan, Entity, Error, Lesson, Memory, ScheduleEntry};
+use crate::{LESSON_MAP, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// A revision as stored. The snapshot is kept in the lesson's own envelope so
+// old snapshots decode through the same migrations as LESSON_MAP.
+#[derive(candid::CandidType, Deserialize)]
+struct StoredRevision {
+    author: Principal,
+    timestamp: u64,
+    lesson: Vec<u8>,
+}
+
+impl Storable for StoredRevision {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // (lesson_id, revision) -> snapshot taken after each change to the lesson
+    static REVISION_MAP: RefCell<StableBTreeMap<(u64, u64), StoredRevision, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))))
+    );
+}
+
+// Who changed a lesson and when
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RevisionSummary {
+    revision: u64,
+    author: Principal,
+    // nanoseconds since the epoch
+    timestamp: u64,
+}
+
+// A lesson as it was after one change
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Revision {
+    revision: u64,
+    author: Principal,
+    timestamp: u64,
+    lesson: Lesson,
+}
+
+// One field that differs between two revisions, both values as text
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldChange {
+    field: String,
+    before: String,
+    after: String,
+}
+
+// add a change for `field` when the two values differ
+pub(crate) fn push_change(changes: &mut Vec<FieldChange>, field: &str, before: String, after: String) {
+    if before != after {
+        changes.push(FieldChange {
+            field: field.to_string(),
+            before,
+            after,
+        });
+    }
+}
+
+pub(crate) fn latest_revision(lesson_id: u64) -> u64 {
+    REVISION_MAP.with(|service| {
+        service
+            .borrow()
+            .range((lesson_id, 0)..=(lesson_id, u64::MAX))
+            .next_back()
+            .map_or(0, |((_, revision), _)| revision)
+    })
+}
+
+// Who made the current call and when. Unit tests run outside a canister,
+// where neither is known.
+#[cfg(target_arch = "wasm32")]
+fn author_and_time() -> (Principal, u64) {
+    (ic_cdk::caller(), ic_cdk::api::time())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn author_and_time() -> (Principal, u64) {
+    (Principal::anonymous(), 0)
+}
+
+// Keep the stored state of a lesson as its next revision. Called after every
+// change to the content of a lesson.
+pub(crate) fn record_revision(lesson: &Lesson) {
+    let revision = latest_revision(lesson.id) + 1;
+    let (author, timestamp) = author_and_time();
+    let stored = StoredRevision {
+        author,
+        timestamp,
+        lesson: migrations::encode_lesson(lesson),
+    };
+    REVISION_MAP.with(|service| service.borrow_mut().insert((lesson.id, revision), stored));
+}
+
+// Give lessons created before revisions were kept their first revision,
+// authored by whoever installed the upgrade
+pub(crate) fn record_initial_revisions() {
+    let lessons: Vec<Lesson> = LESSON_MAP.with(|service| service.borrow().iter().map(|(_, lesson)| lesson).collect());
+    for lesson in &lessons {
+        if latest_revision(lesson.id) == 0 {
+            record_revision(lesson);
+        }
+    }
+}
+
+// drop the history of every lesson, before a restore replaces them
+pub(crate) fn clear_revisions() {
+    REVISION_MAP.with(|service| service.borrow_mut().clear_new());
+}
+
+// drop the history of a deleted lesson
+pub(crate) fn remove_revisions(lesson_id: u64) {
+    REVISION_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        let keys: Vec<(u64, u64)> = map
+            .range((lesson_id, 0)..=(lesson_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            map.remove(&key);
+        }
+    });
+}
+
+fn load_revision(lesson_id: u64, revision: u64) -> Result<Revision, Error> {
+    let stored = REVISION_MAP.with(|service| service.borrow().get(&(lesson_id, revision)));
+    if let Some(stored) = stored {
+        Ok(Revision {
+            revision,
+            author: stored.author,
+            timestamp: stored.timestamp,
+            lesson: migrations::decode_lesson(&stored.lesson),
+        })
+    } else {
+        Err(Error::NotFound { entity: Entity::LessonRevision, id: revision })
+    }
+}
+
+fn require_lesson(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+fn render_list(values: &[String]) -> String {
+    values.join("\n")
+}
+
+fn render_schedule(entries: &[ScheduleEntry]) -> String {
+    let entries: Vec<String> = entries.iter().map(ScheduleEntry::describe).collect();
+    render_list(&entries)
+}
+
+fn render_ids(ids: &[u64]) -> String {
+    let ids: Vec<String> = ids.iter().map(u64::to_string).collect();
+    ids.join(", ")
+}
+
+fn render_term(term_id: Option<u64>) -> String {
+    term_id.map_or_else(String::new, |term_id| term_id.to_string())
+}
+
+fn render_capacity(capacity: Option<u32>) -> String {
+    capacity.map_or_else(String::new, |capacity| capacity.to_string())
+}
+
+fn diff_lessons(before: &Lesson, after: &Lesson) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    push_change(&mut changes, "title", before.title.clone(), after.title.clone());
+    push_change(&mut changes, "description", before.description.clone(), after.description.clone());
+    push_change(&mut changes, "grade_level", before.grade_level.clone(), after.grade_level.clone());
+    push_change(&mut changes, "subject", before.subject.clone(), after.subject.clone());
+    push_change(&mut changes, "teacher_id", before.teacher_id.to_string(), after.teacher_id.to_string());
+    push_change(&mut changes, "students", render_ids(&before.students), render_ids(&after.students));
+    push_change(&mut changes, "schedule", render_schedule(&before.schedule), render_schedule(&after.schedule));
+    push_change(&mut changes, "term_id", render_term(before.term_id), render_term(after.term_id));
+    push_change(&mut changes, "capacity", render_capacity(before.capacity), render_capacity(after.capacity));
+    plan::diff_plans(&mut changes, &before.plan, &after.plan);
+    changes
+}
+
+// revisions of a lesson, oldest first
+#[ic_cdk::query]
+fn list_lesson_revisions(lesson_id: u64, start_after: Option<u64>, limit: u32) -> Result<Page<RevisionSummary>, Error> {
+    require_lesson(lesson_id)?;
+    let limit = page_size(limit)?;
+    let first = start_after.map_or(0, |revision| revision.saturating_add(1));
+    // revisions are numbered from 1 without gaps
+    let total = latest_revision(lesson_id);
+    REVISION_MAP.with(|service| {
+        let map = service.borrow();
+        let mut items: Vec<RevisionSummary> = map
+            .range((lesson_id, first)..=(lesson_id, u64::MAX))
+            .take(limit + 1)
+            .map(|((_, revision), stored)| RevisionSummary {
+                revision,
+                author: stored.author,
+                timestamp: stored.timestamp,
+            })
+            .collect();
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|summary| summary.revision)
+        } else {
+            None
+        };
+        Ok(Page { items, next_cursor, total })
+    })
+}
+
+#[ic_cdk::query]
+fn get_lesson_revision(lesson_id: u64, revision: u64) -> Result<Revision, Error> {
+    require_lesson(lesson_id)?;
+    load_revision(lesson_id, revision)
+}
+
+// fields that changed from revision `from` to revision `to`
+#[ic_cdk::query]
+fn diff_lesson_revisions(lesson_id: u64, from: u64, to: u64) -> Result<Vec<FieldChange>, Error> {
+    require_lesson(lesson_id)?;
+    let before = load_revision(lesson_id, from)?;
+    let after = load_revision(lesson_id, to)?;
+    Ok(diff_lessons(&before.lesson, &after.lesson))
+}
+
+// Make the content of an older revision current again. Enrollments, the
+// schedule and the teacher are links kept in step with other records, so
+// they stay as they are. The restore is recorded as a new revision.
+#[ic_cdk::update]
+fn restore_lesson_revision(lesson_id: u64, revision: u64) -> Result<Lesson, Error> {
+    audited("restore_lesson_revision", &[record(Entity::Lesson, lesson_id)], || {
+        let mut lesson = require_lesson(lesson_id)?;
+        require_teacher(lesson.teacher_id, "restore revisions of this lesson")?;
+        let old = load_revision(lesson_id, revision)?.lesson;
+        lesson.title = old.title;
+        lesson.description = old.description;
+        lesson.grade_level = eligibility::canonical_grade_level(&old.grade_level);
+        lesson.subject = old.subject;
+        lesson.plan = old.plan;
+        do_insert_lesson(&lesson)?;
+        record_revision(&lesson);
+        Ok(lesson)
+    })
+}
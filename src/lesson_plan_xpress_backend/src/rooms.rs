@@ -0,0 +1,443 @@
+use crate::audit::{audited, record};
+use crate::auth::{require_admin, require_lesson_teacher};
+use crate::pagination::{page, Page};
+use crate::schedule::Weekday;
+use crate::{calendar, migrations, require_max_size, require_not_empty};
+use crate::{Entity, Error, IdCell, Lesson, Memory, ScheduleEntry, LESSON_MAP, MAX_NAME_SIZE, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Most equipment tags a room can have
+const MAX_EQUIPMENT_TAGS: usize = 32;
+
+// A room lessons can be held in. Equipment tags are lower case, e.g.
+// "projector", "lab sinks", "computers".
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Room {
+    pub(crate) id: u64,
+    name: String,
+    pub(crate) capacity: u32,
+    equipment: Vec<String>,
+}
+
+impl Room {
+    // "Lab 1" (30 seats, projector, lab sinks)
+    pub(crate) fn describe(&self) -> String {
+        let mut summary = format!("{:?} ({} seats", self.name, self.capacity);
+        for tag in &self.equipment {
+            summary.push_str(", ");
+            summary.push_str(tag);
+        }
+        summary.push(')');
+        summary
+    }
+
+    fn has_equipment(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.equipment.contains(tag))
+    }
+}
+
+impl Storable for Room {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_room(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_room(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RoomPayload {
+    name: String,
+    capacity: u32,
+    equipment: Vec<String>,
+}
+
+// The room of one schedule slot of a lesson
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SlotRoom {
+    schedule_id: u64,
+    room_id: u64,
+}
+
+// Rooms given to the slots of a lesson; slots without a room are left out
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LessonRooms {
+    pub(crate) lesson_id: u64,
+    rooms: Vec<SlotRoom>,
+}
+
+impl Storable for LessonRooms {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_lesson_rooms(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_lesson_rooms(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl LessonRooms {
+    // "lesson 3: slot 5 in room 2, slot 6 in room 4"
+    pub(crate) fn describe(&self) -> String {
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .map(|slot| format!("slot {} in room {}", slot.schedule_id, slot.room_id))
+            .collect();
+        if rooms.is_empty() {
+            format!("lesson {}: no rooms", self.lesson_id)
+        } else {
+            format!("lesson {}: {}", self.lesson_id, rooms.join(", "))
+        }
+    }
+}
+
+thread_local! {
+    pub(crate) static ROOM_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), 0)
+            .expect("Cannot create a counter")
+    );
+    pub(crate) static ROOM_MAP: RefCell<StableBTreeMap<u64, Room, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))))
+    );
+    // lesson_id -> rooms of its slots
+    pub(crate) static LESSON_ROOMS_MAP: RefCell<StableBTreeMap<u64, LessonRooms, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))))
+    );
+}
+
+// A time window to find rooms for, and what the room must offer
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FreeRoomQuery {
+    day: Weekday,
+    start_minute: u16,
+    end_minute: u16,
+    min_capacity: u32,
+    equipment: Vec<String>,
+    // rooms used by lessons of other terms count as free; all lessons
+    // count when None
+    term_id: Option<u64>,
+}
+
+pub(crate) fn get_room_record(room_id: u64) -> Result<Room, Error> {
+    let room = ROOM_MAP.with(|service| service.borrow().get(&room_id));
+    if let Some(room) = room {
+        Ok(room)
+    } else {
+        Err(Error::NotFound { entity: Entity::Room, id: room_id })
+    }
+}
+
+fn get_lesson_record(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+fn lesson_rooms(lesson_id: u64) -> LessonRooms {
+    LESSON_ROOMS_MAP
+        .with(|service| service.borrow().get(&lesson_id))
+        .unwrap_or(LessonRooms { lesson_id, rooms: Vec::new() })
+}
+
+fn store_lesson_rooms(rooms: &LessonRooms) {
+    LESSON_ROOMS_MAP.with(|service| {
+        let mut map = service.borrow_mut();
+        if rooms.rooms.is_empty() {
+            map.remove(&rooms.lesson_id);
+        } else {
+            map.insert(rooms.lesson_id, rooms.clone());
+        }
+    });
+}
+
+// seats of the smallest room booked for any slot of the lesson, None while
+// no room is booked
+pub(crate) fn lesson_seats(lesson_id: u64) -> Option<u32> {
+    let rooms = lesson_rooms(lesson_id);
+    ROOM_MAP.with(|service| {
+        let map = service.borrow();
+        rooms
+            .rooms
+            .iter()
+            .filter_map(|slot| map.get(&slot.room_id))
+            .map(|room| room.capacity)
+            .min()
+    })
+}
+
+// drop the rooms of a deleted lesson
+pub(crate) fn remove_lesson_rooms(lesson_id: u64) {
+    LESSON_ROOMS_MAP.with(|service| service.borrow_mut().remove(&lesson_id));
+}
+
+// book one room for the given slots of a lesson, replacing its bookings
+pub(crate) fn book_lesson_rooms(lesson_id: u64, room_id: u64, schedule_ids: &[u64]) {
+    let rooms = schedule_ids
+        .iter()
+        .map(|&schedule_id| SlotRoom { schedule_id, room_id })
+        .collect();
+    store_lesson_rooms(&LessonRooms { lesson_id, rooms });
+}
+
+// drop the room of a slot taken off a lesson
+pub(crate) fn remove_slot_room(lesson_id: u64, schedule_id: u64) {
+    let mut rooms = lesson_rooms(lesson_id);
+    rooms.rooms.retain(|slot| slot.schedule_id != schedule_id);
+    store_lesson_rooms(&rooms);
+}
+
+// The room must not be booked by another slot at an overlapping time in an
+// overlapping term
+fn require_room_free(room_id: u64, lesson: &Lesson, slot: &ScheduleEntry) -> Result<(), Error> {
+    for (_, other, other_slot) in room_bookings(Some(room_id)) {
+        if other.id == lesson.id && other_slot.id == slot.id {
+            continue;
+        }
+        if slot.overlaps(&other_slot) && calendar::terms_overlap(lesson.term_id, other.term_id) {
+            return Err(Error::Conflict {
+                reason: format!(
+                    "room {} is booked on {} by lesson {}",
+                    room_id,
+                    other_slot.describe(),
+                    other.id
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Check the rooms booked for a lesson again, for a lesson moving to another
+// term or a term changing its dates
+pub(crate) fn check_lesson_rooms(lesson: &Lesson) -> Result<(), Error> {
+    for booked in lesson_rooms(lesson.id).rooms {
+        if let Some(slot) = lesson.schedule.iter().find(|entry| entry.id == booked.schedule_id) {
+            require_room_free(booked.room_id, lesson, slot)?;
+        }
+    }
+    Ok(())
+}
+
+// Every slot a room is booked for, as (lesson, slot). Bookings of slots the
+// lesson no longer has are skipped.
+pub(crate) fn room_bookings(room_id: Option<u64>) -> Vec<(u64, Lesson, ScheduleEntry)> {
+    let bookings: Vec<LessonRooms> =
+        LESSON_ROOMS_MAP.with(|service| service.borrow().iter().map(|(_, rooms)| rooms).collect());
+    let mut result = Vec::new();
+    for lesson_rooms in bookings {
+        let lesson = match LESSON_MAP.with(|service| service.borrow().get(&lesson_rooms.lesson_id)) {
+            Some(lesson) => lesson,
+            None => continue,
+        };
+        for slot in &lesson_rooms.rooms {
+            if room_id.is_some_and(|room_id| room_id != slot.room_id) {
+                continue;
+            }
+            if let Some(entry) = lesson.schedule.iter().find(|entry| entry.id == slot.schedule_id) {
+                result.push((slot.room_id, lesson.clone(), entry.clone()));
+            }
+        }
+    }
+    result
+}
+
+fn normalize_equipment(equipment: Vec<String>) -> Result<Vec<String>, Error> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in equipment {
+        let tag = tag.trim().to_lowercase();
+        require_not_empty("equipment", &tag)?;
+        require_max_size("equipment", &tag, MAX_NAME_SIZE)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() > MAX_EQUIPMENT_TAGS {
+        return Err(Error::InvalidInput {
+            field: "equipment".to_string(),
+            reason: format!("at most {} tags are allowed", MAX_EQUIPMENT_TAGS),
+        });
+    }
+    tags.sort();
+    Ok(tags)
+}
+
+fn room_from_payload(id: u64, payload: RoomPayload) -> Result<Room, Error> {
+    require_not_empty("name", &payload.name)?;
+    require_max_size("name", &payload.name, MAX_NAME_SIZE)?;
+    Ok(Room {
+        id,
+        name: payload.name,
+        capacity: payload.capacity,
+        equipment: normalize_equipment(payload.equipment)?,
+    })
+}
+
+#[ic_cdk::query]
+fn get_room(id: u64) -> Result<Room, Error> {
+    get_room_record(id)
+}
+
+#[ic_cdk::query]
+fn list_rooms(start_after: Option<u64>, limit: u32) -> Result<Page<Room>, Error> {
+    ROOM_MAP.with(|service| page(&service.borrow(), start_after, limit))
+}
+
+#[ic_cdk::update]
+fn add_room(payload: RoomPayload) -> Result<Room, Error> {
+    audited("add_room", &[], || {
+        require_admin("add rooms")?;
+        let mut room = room_from_payload(0, payload)?;
+        room.id = ROOM_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment id counter");
+        ROOM_MAP.with(|service| service.borrow_mut().insert(room.id, room.clone()));
+        Ok(room)
+    })
+}
+
+// Change a room. A smaller capacity must still seat the lessons booked in it.
+#[ic_cdk::update]
+fn update_room(id: u64, payload: RoomPayload) -> Result<Room, Error> {
+    audited("update_room", &[record(Entity::Room, id)], || {
+        require_admin("update rooms")?;
+        get_room_record(id)?;
+        let room = room_from_payload(id, payload)?;
+        for (_, lesson, _) in room_bookings(Some(id)) {
+            if lesson.students.len() as u64 > room.capacity as u64 {
+                return Err(Error::Conflict {
+                    reason: format!("lesson {} has {} students", lesson.id, lesson.students.len()),
+                });
+            }
+        }
+        ROOM_MAP.with(|service| service.borrow_mut().insert(id, room.clone()));
+        Ok(room)
+    })
+}
+
+// a room can only be deleted once no lesson is booked in it
+#[ic_cdk::update]
+fn delete_room(id: u64) -> Result<Room, Error> {
+    audited("delete_room", &[record(Entity::Room, id)], || {
+        require_admin("delete rooms")?;
+        let room = get_room_record(id)?;
+        let booked = room_bookings(Some(id)).len();
+        if booked > 0 {
+            return Err(Error::Conflict {
+                reason: format!("Room with id={} is still booked for {} slot(s)", id, booked),
+            });
+        }
+        ROOM_MAP.with(|service| service.borrow_mut().remove(&id));
+        Ok(room)
+    })
+}
+
+#[ic_cdk::query]
+fn get_lesson_rooms(lesson_id: u64) -> Result<LessonRooms, Error> {
+    get_lesson_record(lesson_id)?;
+    Ok(lesson_rooms(lesson_id))
+}
+
+// Hold a slot of a lesson in a room. The room must seat the lesson's
+// students and not be booked by another lesson at an overlapping time in an
+// overlapping term.
+#[ic_cdk::update]
+fn assign_room(lesson_id: u64, schedule_id: u64, room_id: u64) -> Result<LessonRooms, Error> {
+    audited("assign_room", &[record(Entity::Lesson, lesson_id), record(Entity::Room, room_id)], || {
+        let lesson = get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "book rooms for this lesson")?;
+        let room = get_room_record(room_id)?;
+        let slot = lesson
+            .schedule
+            .iter()
+            .find(|entry| entry.id == schedule_id)
+            .ok_or_else(|| Error::InvalidInput {
+                field: "schedule_id".to_string(),
+                reason: format!("lesson {} is not scheduled in slot {}", lesson_id, schedule_id),
+            })?;
+        if lesson.students.len() as u64 > room.capacity as u64 {
+            return Err(Error::CapacityExceeded {
+                entity: Entity::Room,
+                id: room_id,
+                capacity: room.capacity as u64,
+            });
+        }
+        require_room_free(room_id, &lesson, slot)?;
+
+        let mut rooms = lesson_rooms(lesson_id);
+        rooms.rooms.retain(|slot| slot.schedule_id != schedule_id);
+        rooms.rooms.push(SlotRoom { schedule_id, room_id });
+        rooms.rooms.sort_by_key(|slot| slot.schedule_id);
+        store_lesson_rooms(&rooms);
+        Ok(rooms)
+    })
+}
+
+#[ic_cdk::update]
+fn unassign_room(lesson_id: u64, schedule_id: u64) -> Result<LessonRooms, Error> {
+    audited("unassign_room", &[record(Entity::Lesson, lesson_id)], || {
+        get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "book rooms for this lesson")?;
+        let mut rooms = lesson_rooms(lesson_id);
+        if !rooms.rooms.iter().any(|slot| slot.schedule_id == schedule_id) {
+            return Err(Error::InvalidInput {
+                field: "schedule_id".to_string(),
+                reason: format!("slot {} of lesson {} has no room", schedule_id, lesson_id),
+            });
+        }
+        rooms.rooms.retain(|slot| slot.schedule_id != schedule_id);
+        store_lesson_rooms(&rooms);
+        Ok(rooms)
+    })
+}
+
+// rooms with enough seats and the equipment asked for that no lesson uses
+// during the window
+#[ic_cdk::query]
+fn find_free_rooms(query: FreeRoomQuery) -> Result<Vec<Room>, Error> {
+    if query.start_minute >= query.end_minute || query.end_minute > 24 * 60 {
+        return Err(Error::InvalidInput {
+            field: "end_minute".to_string(),
+            reason: "the window must end after it starts and within the day".to_string(),
+        });
+    }
+    if let Some(term_id) = query.term_id {
+        calendar::require_term_exists(term_id)?;
+    }
+    let equipment = normalize_equipment(query.equipment)?;
+    let window = ScheduleEntry {
+        id: 0,
+        day: query.day,
+        start_minute: query.start_minute,
+        end_minute: query.end_minute,
+    };
+    let busy: Vec<u64> = room_bookings(None)
+        .into_iter()
+        .filter(|(_, lesson, slot)| slot.overlaps(&window) && calendar::terms_overlap(query.term_id, lesson.term_id))
+        .map(|(room_id, _, _)| room_id)
+        .collect();
+    Ok(ROOM_MAP.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, room)| room)
+            .filter(|room| {
+                !busy.contains(&room.id) && room.capacity >= query.min_capacity && room.has_equipment(&equipment)
+            })
+            .collect()
+    }))
+}
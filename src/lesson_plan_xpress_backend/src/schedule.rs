@@ -0,0 +1,472 @@
+use crate::audit::{audited, AuditTarget};
+use crate::auth::{require_admin, require_lesson_teacher};
+use crate::calendar::terms_overlap;
+use crate::{Entity, Error, Lesson, Memory, ScheduleEntry, Teacher, MEMORY_MANAGER};
+use crate::{LESSON_MAP, SCHEDULE_ENTRY_MAP, STUDENT_MAP, TEACHER_MAP};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Last minute of the day a slot may end at (24:00)
+pub(crate) const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    pub(crate) const ALL: [Weekday; 7] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Weekday::Monday => "Monday",
+            Weekday::Tuesday => "Tuesday",
+            Weekday::Wednesday => "Wednesday",
+            Weekday::Thursday => "Thursday",
+            Weekday::Friday => "Friday",
+            Weekday::Saturday => "Saturday",
+            Weekday::Sunday => "Sunday",
+        }
+    }
+
+    // Lenient parsing of free-text day names ("mon", "Tues", "THURSDAY")
+    pub(crate) fn parse(value: &str) -> Option<Weekday> {
+        let value = value.trim().to_lowercase();
+        if value.len() < 3 {
+            return None;
+        }
+        Weekday::ALL
+            .into_iter()
+            .find(|day| day.name().to_lowercase().starts_with(&value))
+    }
+}
+
+// Parse a 24 hour "HH:MM" time into minutes since midnight. "24:00" is
+// accepted so a slot can run until the end of the day.
+pub(crate) fn parse_time(value: &str) -> Option<u16> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    if hours.is_empty() || hours.len() > 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours: u16 = hours.parse().ok()?;
+    let minutes: u16 = minutes.parse().ok()?;
+    if minutes > 59 {
+        return None;
+    }
+    let total = hours * 60 + minutes;
+    if total > MINUTES_PER_DAY {
+        return None;
+    }
+    Some(total)
+}
+
+// Parse the formats found in free-text entries written before times were
+// validated: "9:30", "09.30", "9am", "2:15 PM", "14".
+pub(crate) fn parse_legacy_time(value: &str) -> Option<u16> {
+    let value = value.trim().to_lowercase().replace('.', ":");
+    let (clock, meridiem) = if let Some(clock) = value.strip_suffix("am") {
+        (clock.trim(), Some(false))
+    } else if let Some(clock) = value.strip_suffix("pm") {
+        (clock.trim(), Some(true))
+    } else {
+        (value.as_str(), None)
+    };
+    let (hours, minutes) = match clock.split_once(':') {
+        Some((hours, minutes)) => (hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?),
+        None => (clock.parse::<u16>().ok()?, 0),
+    };
+    let hours = match meridiem {
+        Some(_) if hours == 0 || hours > 12 => return None,
+        Some(false) => hours % 12,
+        Some(true) => hours % 12 + 12,
+        None => hours,
+    };
+    if minutes > 59 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+pub(crate) fn format_time(minutes: u16) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+// Parse and check the times of a slot, returning (start, end) in minutes
+pub(crate) fn parse_slot(start_time: &str, end_time: &str) -> Result<(u16, u16), Error> {
+    let start = parse_time(start_time).ok_or_else(|| Error::InvalidInput {
+        field: "start_time".to_string(),
+        reason: format!("{:?} is not a HH:MM time", start_time),
+    })?;
+    let end = parse_time(end_time).ok_or_else(|| Error::InvalidInput {
+        field: "end_time".to_string(),
+        reason: format!("{:?} is not a HH:MM time", end_time),
+    })?;
+    if start >= end {
+        return Err(Error::InvalidInput {
+            field: "end_time".to_string(),
+            reason: format!("must be after start_time {}", start_time),
+        });
+    }
+    Ok((start, end))
+}
+
+// Time zone the school's wall clock times are given in. Schedule times are
+// local to this zone.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SchoolSettings {
+    // IANA zone name, e.g. "Africa/Nairobi"
+    pub(crate) time_zone: String,
+    // offset from UTC in minutes, e.g. 180 for UTC+3
+    pub(crate) utc_offset_minutes: i16,
+}
+
+impl Default for SchoolSettings {
+    fn default() -> Self {
+        SchoolSettings {
+            time_zone: "UTC".to_string(),
+            utc_offset_minutes: 0,
+        }
+    }
+}
+
+impl Storable for SchoolSettings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    pub(crate) static SCHOOL_SETTINGS: RefCell<Cell<SchoolSettings, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), SchoolSettings::default())
+            .expect("Cannot create school settings")
+    );
+}
+
+pub(crate) fn school_settings() -> SchoolSettings {
+    SCHOOL_SETTINGS.with(|settings| settings.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_school_settings() -> SchoolSettings {
+    school_settings()
+}
+
+#[ic_cdk::update]
+fn update_school_settings(settings: SchoolSettings) -> Result<SchoolSettings, Error> {
+    audited("update_school_settings", &[AuditTarget::SchoolSettings], || {
+        require_admin("change school settings")?;
+        if settings.time_zone.trim().is_empty() {
+            return Err(Error::InvalidInput {
+                field: "time_zone".to_string(),
+                reason: "must not be empty".to_string(),
+            });
+        }
+        // real world offsets range from UTC-12:00 to UTC+14:00
+        if !(-12 * 60..=14 * 60).contains(&settings.utc_offset_minutes) {
+            return Err(Error::InvalidInput {
+                field: "utc_offset_minutes".to_string(),
+                reason: "must be between -720 and 840".to_string(),
+            });
+        }
+        SCHOOL_SETTINGS
+            .with(|cell| cell.borrow_mut().set(settings.clone()))
+            .expect("cannot store school settings");
+        Ok(settings)
+    })
+}
+
+impl ScheduleEntry {
+    // true when both slots share some time on the same day
+    pub(crate) fn overlaps(&self, other: &ScheduleEntry) -> bool {
+        self.day == other.day
+            && self.start_minute < other.end_minute
+            && other.start_minute < self.end_minute
+    }
+
+    // true when `other` lies entirely within this slot
+    pub(crate) fn contains(&self, other: &ScheduleEntry) -> bool {
+        self.day == other.day
+            && self.start_minute <= other.start_minute
+            && other.end_minute <= self.end_minute
+    }
+
+    // "Monday 09:00-10:30"
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "{} {}-{}",
+            self.day.name(),
+            format_time(self.start_minute),
+            format_time(self.end_minute)
+        )
+    }
+}
+
+// A reason a slot cannot be given to a lesson or teacher
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum ScheduleConflict {
+    // the teacher already teaches `lesson_id` during `slot`
+    TeacherDoubleBooked { teacher_id: u64, lesson_id: u64, slot: ScheduleEntry },
+    // the slot is not covered by any of the teacher's availability entries
+    OutsideTeacherAvailability { teacher_id: u64 },
+    // an enrolled student already attends `lesson_id` during `slot`
+    StudentDoubleBooked { student_id: u64, lesson_id: u64, slot: ScheduleEntry },
+    // the teacher's availability already contains an overlapping entry
+    AvailabilityOverlap { teacher_id: u64, slot: ScheduleEntry },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ConflictReport {
+    pub(crate) slot: ScheduleEntry,
+    pub(crate) conflicts: Vec<ScheduleConflict>,
+}
+
+pub(crate) fn get_schedule_entry(schedule_id: u64) -> Result<ScheduleEntry, Error> {
+    let schedule = SCHEDULE_ENTRY_MAP.with(|service| service.borrow().get(&schedule_id));
+    if let Some(schedule) = schedule {
+        Ok(schedule)
+    } else {
+        Err(Error::NotFound { entity: Entity::ScheduleEntry, id: schedule_id })
+    }
+}
+
+// Everything that clashes with giving `slot` to `lesson`
+pub(crate) fn lesson_slot_conflicts(lesson: &Lesson, slot: &ScheduleEntry) -> ConflictReport {
+    let mut conflicts = Vec::new();
+
+    // the teacher's other slots, including the lesson's own
+    let mut stored = false;
+    LESSON_MAP.with(|service| {
+        for (_, other) in service.borrow().iter() {
+            if other.teacher_id != lesson.teacher_id && other.id != lesson.id {
+                continue;
+            }
+            if other.id != lesson.id && !terms_overlap(lesson.term_id, other.term_id) {
+                continue;
+            }
+            let other_schedule = if other.id == lesson.id {
+                stored = true;
+                &lesson.schedule
+            } else {
+                &other.schedule
+            };
+            for booked in other_schedule.iter().filter(|booked| booked.overlaps(slot)) {
+                conflicts.push(ScheduleConflict::TeacherDoubleBooked {
+                    teacher_id: lesson.teacher_id,
+                    lesson_id: other.id,
+                    slot: booked.clone(),
+                });
+            }
+        }
+    });
+    // a lesson that is being created is not in the map yet
+    if !stored {
+        for booked in lesson.schedule.iter().filter(|booked| booked.overlaps(slot)) {
+            conflicts.push(ScheduleConflict::TeacherDoubleBooked {
+                teacher_id: lesson.teacher_id,
+                lesson_id: lesson.id,
+                slot: booked.clone(),
+            });
+        }
+    }
+
+    // availability is only enforced once the teacher has declared some
+    let teacher = TEACHER_MAP.with(|service| service.borrow().get(&lesson.teacher_id));
+    if let Some(teacher) = teacher {
+        if !teacher.availability.is_empty()
+            && !teacher.availability.iter().any(|available| available.contains(slot))
+        {
+            conflicts.push(ScheduleConflict::OutsideTeacherAvailability {
+                teacher_id: teacher.id,
+            });
+        }
+    }
+
+    for student_id in &lesson.students {
+        let student = STUDENT_MAP.with(|service| service.borrow().get(student_id));
+        let student = if let Some(student) = student {
+            student
+        } else {
+            continue;
+        };
+        for other_id in student.lessons.iter().filter(|id| **id != lesson.id) {
+            let other = LESSON_MAP.with(|service| service.borrow().get(other_id));
+            let other = if let Some(other) = other {
+                other
+            } else {
+                continue;
+            };
+            // already reported as the teacher's own clash
+            if other.teacher_id == lesson.teacher_id || !terms_overlap(lesson.term_id, other.term_id) {
+                continue;
+            }
+            for booked in other.schedule.iter().filter(|booked| booked.overlaps(slot)) {
+                conflicts.push(ScheduleConflict::StudentDoubleBooked {
+                    student_id: *student_id,
+                    lesson_id: other.id,
+                    slot: booked.clone(),
+                });
+            }
+        }
+    }
+
+    ConflictReport {
+        slot: slot.clone(),
+        conflicts,
+    }
+}
+
+// Check every slot of a lesson that moves to another teacher or term, one
+// after the other as clone_lesson does, so its own slots do not clash with
+// themselves
+pub(crate) fn check_lesson_slots(lesson: &Lesson, force: bool) -> Result<(), Error> {
+    let mut placed = Lesson {
+        schedule: Vec::new(),
+        ..lesson.clone()
+    };
+    for slot in &lesson.schedule {
+        check_conflicts(lesson_slot_conflicts(&placed, slot), force)?;
+        placed.schedule.push(slot.clone());
+    }
+    Ok(())
+}
+
+// Overlaps between `slot` and the teacher's existing availability
+pub(crate) fn availability_conflicts(teacher: &Teacher, slot: &ScheduleEntry) -> ConflictReport {
+    let conflicts = teacher
+        .availability
+        .iter()
+        .filter(|available| available.overlaps(slot))
+        .map(|available| ScheduleConflict::AvailabilityOverlap {
+            teacher_id: teacher.id,
+            slot: available.clone(),
+        })
+        .collect();
+    ConflictReport {
+        slot: slot.clone(),
+        conflicts,
+    }
+}
+
+// Conflicts block the change unless an admin passed `force`
+pub(crate) fn check_conflicts(report: ConflictReport, force: bool) -> Result<(), Error> {
+    if report.conflicts.is_empty() {
+        return Ok(());
+    }
+    if force {
+        return require_admin("override schedule conflicts");
+    }
+    Err(Error::ScheduleConflict { report })
+}
+
+// preview the conflicts insert_schedule_to_lesson would report
+#[ic_cdk::query]
+fn check_lesson_schedule(lesson_id: u64, schedule_id: u64) -> Result<ConflictReport, Error> {
+    require_lesson_teacher(lesson_id, "schedule this lesson")?;
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        let slot = get_schedule_entry(schedule_id)?;
+        Ok(lesson_slot_conflicts(&lesson, &slot))
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_field(result: Result<(u16, u16), Error>) -> String {
+        match result {
+            Err(Error::InvalidInput { field, .. }) => field,
+            _ => panic!("expected InvalidInput"),
+        }
+    }
+
+    #[test]
+    fn parse_time_reads_24_hour_times() {
+        assert_eq!(parse_time("00:00"), Some(0));
+        assert_eq!(parse_time("9:05"), Some(9 * 60 + 5));
+        assert_eq!(parse_time(" 12:00 "), Some(12 * 60));
+        assert_eq!(parse_time("23:59"), Some(23 * 60 + 59));
+        assert_eq!(parse_time("24:00"), Some(MINUTES_PER_DAY));
+    }
+
+    #[test]
+    fn parse_time_rejects_other_input() {
+        for value in ["", "9", "9:5", "09:300", ":30", "123:00", "12:60", "24:01", "25:00", "9am", "9.30", "-1:00"] {
+            assert_eq!(parse_time(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn parse_legacy_time_reads_12_and_24_hour_times() {
+        assert_eq!(parse_legacy_time("9:30"), Some(9 * 60 + 30));
+        assert_eq!(parse_legacy_time("09.30"), Some(9 * 60 + 30));
+        assert_eq!(parse_legacy_time("14"), Some(14 * 60));
+        assert_eq!(parse_legacy_time("9am"), Some(9 * 60));
+        assert_eq!(parse_legacy_time("2:15 PM"), Some(14 * 60 + 15));
+        assert_eq!(parse_legacy_time(" 11:45 pm "), Some(23 * 60 + 45));
+    }
+
+    #[test]
+    fn parse_legacy_time_handles_noon_and_midnight() {
+        assert_eq!(parse_legacy_time("12pm"), Some(12 * 60));
+        assert_eq!(parse_legacy_time("12:30 PM"), Some(12 * 60 + 30));
+        assert_eq!(parse_legacy_time("12am"), Some(0));
+        assert_eq!(parse_legacy_time("12:15 am"), Some(15));
+        assert_eq!(parse_legacy_time("0:00"), Some(0));
+        assert_eq!(parse_legacy_time("24:00"), Some(MINUTES_PER_DAY));
+    }
+
+    #[test]
+    fn parse_legacy_time_rejects_out_of_range_times() {
+        for value in ["0am", "13pm", "9:60", "24:01", "25", "noon", "", "pm"] {
+            assert_eq!(parse_legacy_time(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn format_time_pads_hours_and_minutes() {
+        assert_eq!(format_time(0), "00:00");
+        assert_eq!(format_time(9 * 60 + 5), "09:05");
+        assert_eq!(format_time(MINUTES_PER_DAY), "24:00");
+    }
+
+    #[test]
+    fn parse_slot_returns_start_and_end() {
+        assert_eq!(parse_slot("08:00", "09:30").ok(), Some((8 * 60, 9 * 60 + 30)));
+        assert_eq!(parse_slot("23:00", "24:00").ok(), Some((23 * 60, MINUTES_PER_DAY)));
+    }
+
+    #[test]
+    fn parse_slot_names_the_field_that_fails() {
+        assert_eq!(invalid_field(parse_slot("8am", "09:00")), "start_time");
+        assert_eq!(invalid_field(parse_slot("08:00", "24:30")), "end_time");
+        // the end must come after the start
+        assert_eq!(invalid_field(parse_slot("10:00", "09:00")), "end_time");
+        assert_eq!(invalid_field(parse_slot("10:00", "10:00")), "end_time");
+    }
+}
@@ -0,0 +1,266 @@
+use crate::pagination::{fill_page, page, page_size, Page};
+use crate::{eligibility, require_not_empty, Error, Lesson, Memory, LESSON_MAP, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::BTreeSet;
+use std::{borrow::Cow, cell::RefCell};
+
+// Longest term kept in an index key; longer values are cut at a char
+// boundary, which only makes the index match a little more than it should
+const MAX_TERM_SIZE: usize = 1024;
+
+// Text searches look up every three character window of the query, so
+// shorter queries cannot use the index
+const MIN_TEXT_QUERY_CHARS: usize = 3;
+
+// Key of the term indexes: the normalized term, a 0 byte and the big endian
+// lesson id. All lessons with the same term form one contiguous range.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct TermKey(Vec<u8>);
+
+impl TermKey {
+    fn new(term: &str, lesson_id: u64) -> Self {
+        let mut end = term.len().min(MAX_TERM_SIZE);
+        while !term.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut bytes = term.as_bytes()[..end].to_vec();
+        bytes.push(0);
+        bytes.extend_from_slice(&lesson_id.to_be_bytes());
+        TermKey(bytes)
+    }
+
+    fn lesson_id(&self) -> u64 {
+        let id_bytes: [u8; 8] = self.0[self.0.len() - 8..].try_into().unwrap();
+        u64::from_be_bytes(id_bytes)
+    }
+}
+
+impl Storable for TermKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TermKey(bytes.into_owned())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_TERM_SIZE as u32 + 9,
+        is_fixed_size: false,
+    };
+}
+
+type TermIndex = StableBTreeMap<TermKey, (), Memory>;
+
+thread_local! {
+    static SUBJECT_INDEX: RefCell<TermIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+    static GRADE_LEVEL_INDEX: RefCell<TermIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+    // (teacher_id, lesson_id)
+    static TEACHER_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+    // trigrams of the lowercased title and description
+    static TEXT_INDEX: RefCell<TermIndex> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))))
+    );
+}
+
+// Filters of search_lessons; every given filter has to match
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LessonQuery {
+    // exact match, ignoring case and surrounding whitespace
+    subject: Option<String>,
+    grade_level: Option<String>,
+    teacher_id: Option<u64>,
+    // case-insensitive substring of the title or the description
+    text: Option<String>,
+}
+
+fn normalize(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+fn trigrams(text: &str) -> BTreeSet<String> {
+    let chars: Vec<char> = text.to_lowercase().chars().collect();
+    chars.windows(3).map(|window| window.iter().collect()).collect()
+}
+
+fn lesson_trigrams(lesson: &Lesson) -> BTreeSet<String> {
+    let mut terms = trigrams(&lesson.title);
+    terms.extend(trigrams(&lesson.description));
+    terms
+}
+
+// update the entries of one index from the old to the new terms of a lesson
+fn replace_terms(index: &mut TermIndex, lesson_id: u64, old: &BTreeSet<String>, new: &BTreeSet<String>) {
+    for term in old.difference(new) {
+        index.remove(&TermKey::new(term, lesson_id));
+    }
+    for term in new.difference(old) {
+        index.insert(TermKey::new(term, lesson_id), ());
+    }
+}
+
+fn single_term(lesson: Option<&Lesson>, field: fn(&Lesson) -> &String) -> BTreeSet<String> {
+    lesson.map(|lesson| normalize(field(lesson))).into_iter().collect()
+}
+
+// Keep the indexes in step with LESSON_MAP. Called with the stored record
+// before and after every write; None when there was none or it was removed.
+pub(crate) fn index_lesson(old: Option<&Lesson>, new: Option<&Lesson>) {
+    let lesson_id = match new.or(old) {
+        Some(lesson) => lesson.id,
+        None => return,
+    };
+    SUBJECT_INDEX.with(|index| {
+        let old = single_term(old, |lesson| &lesson.subject);
+        let new = single_term(new, |lesson| &lesson.subject);
+        replace_terms(&mut index.borrow_mut(), lesson_id, &old, &new);
+    });
+    GRADE_LEVEL_INDEX.with(|index| {
+        let old = single_term(old, |lesson| &lesson.grade_level);
+        let new = single_term(new, |lesson| &lesson.grade_level);
+        replace_terms(&mut index.borrow_mut(), lesson_id, &old, &new);
+    });
+    TEACHER_INDEX.with(|index| {
+        let old_teacher = old.map(|lesson| lesson.teacher_id);
+        let new_teacher = new.map(|lesson| lesson.teacher_id);
+        if old_teacher != new_teacher {
+            let mut index = index.borrow_mut();
+            if let Some(teacher_id) = old_teacher {
+                index.remove(&(teacher_id, lesson_id));
+            }
+            if let Some(teacher_id) = new_teacher {
+                index.insert((teacher_id, lesson_id), ());
+            }
+        }
+    });
+    TEXT_INDEX.with(|index| {
+        let old = old.map(lesson_trigrams).unwrap_or_default();
+        let new = new.map(lesson_trigrams).unwrap_or_default();
+        replace_terms(&mut index.borrow_mut(), lesson_id, &old, &new);
+    });
+}
+
+// Drop and rebuild every index from LESSON_MAP
+pub(crate) fn rebuild_lesson_indexes() {
+    SUBJECT_INDEX.with(|index| index.borrow_mut().clear_new());
+    GRADE_LEVEL_INDEX.with(|index| index.borrow_mut().clear_new());
+    TEACHER_INDEX.with(|index| index.borrow_mut().clear_new());
+    TEXT_INDEX.with(|index| index.borrow_mut().clear_new());
+    let lessons: Vec<Lesson> = LESSON_MAP.with(|service| service.borrow().iter().map(|(_, lesson)| lesson).collect());
+    for lesson in &lessons {
+        index_lesson(None, Some(lesson));
+    }
+}
+
+fn lessons_with_term(index: &TermIndex, term: &str) -> BTreeSet<u64> {
+    index
+        .range(TermKey::new(term, 0)..=TermKey::new(term, u64::MAX))
+        .map(|(key, _)| key.lesson_id())
+        .collect()
+}
+
+pub(crate) fn lessons_of_teacher(teacher_id: u64) -> BTreeSet<u64> {
+    TEACHER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((teacher_id, 0)..=(teacher_id, u64::MAX))
+            .map(|((_, lesson_id), _)| lesson_id)
+            .collect()
+    })
+}
+
+// narrow the candidates down to the ids in `ids`
+fn intersect(candidates: Option<BTreeSet<u64>>, ids: BTreeSet<u64>) -> Option<BTreeSet<u64>> {
+    match candidates {
+        Some(candidates) => Some(candidates.intersection(&ids).copied().collect()),
+        None => Some(ids),
+    }
+}
+
+fn require_filter(field: &str, value: &Option<String>) -> Result<Option<String>, Error> {
+    match value {
+        Some(value) => {
+            require_not_empty(field, value)?;
+            Ok(Some(normalize(value)))
+        }
+        None => Ok(None),
+    }
+}
+
+// find lessons matching all given filters, in id order
+#[ic_cdk::query]
+fn search_lessons(query: LessonQuery, start_after: Option<u64>, limit: u32) -> Result<Page<Lesson>, Error> {
+    let subject = require_filter("subject", &query.subject)?;
+    let grade_level = query.grade_level.as_deref().map(eligibility::canonical_grade_level);
+    let grade_level = require_filter("grade_level", &grade_level)?;
+    let text = require_filter("text", &query.text)?;
+    if let Some(text) = &text {
+        if text.chars().count() < MIN_TEXT_QUERY_CHARS {
+            return Err(Error::InvalidInput {
+                field: "text".to_string(),
+                reason: format!("must be at least {} characters", MIN_TEXT_QUERY_CHARS),
+            });
+        }
+    }
+    if subject.is_none() && grade_level.is_none() && query.teacher_id.is_none() && text.is_none() {
+        return LESSON_MAP.with(|service| page(&service.borrow(), start_after, limit));
+    }
+    let limit = page_size(limit)?;
+
+    let mut candidates = None;
+    if let Some(subject) = &subject {
+        let ids = SUBJECT_INDEX.with(|index| lessons_with_term(&index.borrow(), subject));
+        candidates = intersect(candidates, ids);
+    }
+    if let Some(grade_level) = &grade_level {
+        let ids = GRADE_LEVEL_INDEX.with(|index| lessons_with_term(&index.borrow(), grade_level));
+        candidates = intersect(candidates, ids);
+    }
+    if let Some(teacher_id) = query.teacher_id {
+        candidates = intersect(candidates, lessons_of_teacher(teacher_id));
+    }
+    if let Some(text) = &text {
+        for term in trigrams(text) {
+            let ids = TEXT_INDEX.with(|index| lessons_with_term(&index.borrow(), &term));
+            candidates = intersect(candidates, ids);
+        }
+    }
+    let candidates = candidates.unwrap_or_default();
+
+    // the trigrams only narrow the candidates down, the substring itself is
+    // checked on the record
+    let matches: Vec<u64> = match &text {
+        Some(text) => LESSON_MAP.with(|service| {
+            let map = service.borrow();
+            candidates
+                .into_iter()
+                .filter(|id| {
+                    map.get(id).is_some_and(|lesson| {
+                        lesson.title.to_lowercase().contains(text.as_str())
+                            || lesson.description.to_lowercase().contains(text.as_str())
+                    })
+                })
+                .collect()
+        }),
+        None => candidates.into_iter().collect(),
+    };
+
+    let total = matches.len() as u64;
+    let (items, next_cursor) = LESSON_MAP.with(|service| {
+        let map = service.borrow();
+        let lessons = matches
+            .into_iter()
+            .filter(|id| start_after.is_none_or(|start_after| *id > start_after))
+            .filter_map(|id| map.get(&id).map(|lesson| (id, lesson)));
+        fill_page(lessons, limit)
+    });
+    Ok(Page { items, next_cursor, total })
+}
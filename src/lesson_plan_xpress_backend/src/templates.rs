@@ -0,0 +1,193 @@
+use crate::audit::{audited, record};
+use crate::auth::{require_lesson_teacher, require_teacher};
+use crate::links::require_teacher_exists;
+use crate::pagination::{page, Page};
+use crate::plan::LessonPlan;
+use crate::{calendar, do_insert_lesson, eligibility, migrations, next_lesson_id, revisions, schedule};
+use crate::{Entity, Error, IdCell, Lesson, Memory, LESSON_MAP, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Lesson content kept for reuse, without a teacher's classes or schedule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LessonTemplate {
+    pub(crate) id: u64,
+    title: String,
+    description: String,
+    grade_level: String,
+    subject: String,
+    plan: LessonPlan,
+    // teacher who saved the template
+    teacher_id: u64,
+    // lesson the template was saved from
+    source_lesson_id: u64,
+}
+
+impl LessonTemplate {
+    // "Fractions" (Math, grade 5), teacher 3
+    pub(crate) fn describe(&self) -> String {
+        format!("{:?} ({}, grade {}), teacher {}", self.title, self.subject, self.grade_level, self.teacher_id)
+    }
+}
+
+impl Storable for LessonTemplate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(migrations::encode_template(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        migrations::decode_template(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    pub(crate) static TEMPLATE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), 0)
+            .expect("Cannot create a counter")
+    );
+    pub(crate) static TEMPLATE_MAP: RefCell<StableBTreeMap<u64, LessonTemplate, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))))
+    );
+}
+
+// Where the content of a cloned lesson came from
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum LessonSource {
+    Lesson(u64),
+    Template(u64),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CloneLessonPayload {
+    source: LessonSource,
+    // teacher of the new lesson
+    teacher_id: u64,
+    // schedule entries to give the new lesson, checked like
+    // insert_schedule_to_lesson; admins may `force` conflicts through
+    schedule_ids: Vec<u64>,
+    force: bool,
+    // term of the new lesson, all year when None
+    term_id: Option<u64>,
+}
+
+fn get_template_record(template_id: u64) -> Result<LessonTemplate, Error> {
+    let template = TEMPLATE_MAP.with(|service| service.borrow().get(&template_id));
+    if let Some(template) = template {
+        Ok(template)
+    } else {
+        Err(Error::NotFound { entity: Entity::Template, id: template_id })
+    }
+}
+
+fn get_lesson_record(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+#[ic_cdk::query]
+fn get_template(id: u64) -> Result<LessonTemplate, Error> {
+    get_template_record(id)
+}
+
+#[ic_cdk::query]
+fn list_templates(start_after: Option<u64>, limit: u32) -> Result<Page<LessonTemplate>, Error> {
+    TEMPLATE_MAP.with(|service| page(&service.borrow(), start_after, limit))
+}
+
+// keep the content of a lesson as a template
+#[ic_cdk::update]
+fn save_lesson_as_template(lesson_id: u64) -> Result<LessonTemplate, Error> {
+    audited("save_lesson_as_template", &[record(Entity::Lesson, lesson_id)], || {
+        let lesson = get_lesson_record(lesson_id)?;
+        require_lesson_teacher(lesson_id, "save this lesson as a template")?;
+
+        let id = TEMPLATE_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment id counter");
+        let template = LessonTemplate {
+            id,
+            title: lesson.title,
+            description: lesson.description,
+            grade_level: lesson.grade_level,
+            subject: lesson.subject,
+            plan: lesson.plan,
+            teacher_id: lesson.teacher_id,
+            source_lesson_id: lesson.id,
+        };
+        TEMPLATE_MAP.with(|service| service.borrow_mut().insert(id, template.clone()));
+        Ok(template)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_template(id: u64) -> Result<LessonTemplate, Error> {
+    audited("delete_template", &[record(Entity::Template, id)], || {
+        let template = get_template_record(id)?;
+        require_teacher(template.teacher_id, "delete this template")?;
+        TEMPLATE_MAP.with(|service| service.borrow_mut().remove(&id));
+        Ok(template)
+    })
+}
+
+// Create a lesson for `teacher_id` with the content of a lesson or template.
+// The new lesson has no students and links back to its source.
+#[ic_cdk::update]
+fn clone_lesson(payload: CloneLessonPayload) -> Result<Lesson, Error> {
+    audited("clone_lesson", &[], || {
+        require_teacher(payload.teacher_id, "add lessons for this teacher")?;
+        require_teacher_exists(payload.teacher_id)?;
+        if let Some(term_id) = payload.term_id {
+            calendar::require_term_exists(term_id)?;
+        }
+        let (title, description, grade_level, subject, plan) = match payload.source {
+            LessonSource::Lesson(lesson_id) => {
+                let lesson = get_lesson_record(lesson_id)?;
+                (lesson.title, lesson.description, lesson.grade_level, lesson.subject, lesson.plan)
+            }
+            LessonSource::Template(template_id) => {
+                let template = get_template_record(template_id)?;
+                (template.title, template.description, template.grade_level, template.subject, template.plan)
+            }
+        };
+
+        let mut lesson = Lesson {
+            id: next_lesson_id(),
+            title,
+            description,
+            grade_level: eligibility::canonical_grade_level(&grade_level),
+            subject,
+            teacher_id: payload.teacher_id,
+            students: Vec::new(),
+            schedule: Vec::new(),
+            plan,
+            cloned_from: Some(payload.source),
+            term_id: payload.term_id,
+            capacity: None,
+            waitlist: Vec::new(),
+        };
+        for schedule_id in payload.schedule_ids {
+            let slot = schedule::get_schedule_entry(schedule_id)?;
+            if lesson.schedule.iter().any(|entry| entry.id == schedule_id) {
+                return Err(Error::Conflict {
+                    reason: format!("schedule id={} is given more than once", schedule_id),
+                });
+            }
+            schedule::check_conflicts(schedule::lesson_slot_conflicts(&lesson, &slot), payload.force)?;
+            lesson.schedule.push(slot);
+        }
+        do_insert_lesson(&lesson)?;
+        revisions::record_revision(&lesson);
+        Ok(lesson)
+    })
+}
@@ -0,0 +1,782 @@
+use crate::audit::{audited, record, AuditTarget};
+use crate::auth::require_admin;
+use crate::calendar::terms_overlap;
+use crate::rooms::{self, get_room_record};
+use crate::schedule::get_schedule_entry;
+use crate::{do_insert_lesson, revisions, Entity, Error, Lesson, ScheduleEntry, LESSON_MAP, TEACHER_MAP};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Most lessons one timetable job may place
+const MAX_LESSONS: usize = 200;
+
+// Most weekly periods a single lesson may ask for
+const MAX_PERIODS: u32 = 10;
+
+// Most slots a job may choose from
+const MAX_SLOTS: usize = 500;
+
+// Instructions a call may spend on the search before it returns and waits
+// to be continued, well below the limit of an update call
+const INSTRUCTION_BUDGET: u64 = 5_000_000_000;
+
+// Search steps after which a job settles for the best timetable found
+const MAX_SEARCH_STEPS: u64 = 2_000_000;
+
+// Weekly periods wanted for a lesson
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PeriodDemand {
+    lesson_id: u64,
+    periods: u32,
+    // room to hold every period in, the lesson keeps no room when None
+    room_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum TimetableMode {
+    // only report the timetable
+    Propose,
+    // write it into the lessons once the search is done
+    Apply,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TimetableRequest {
+    lessons: Vec<PeriodDemand>,
+    // schedule entries the periods are chosen from, e.g. the school's bell
+    // schedule; a lesson only gets slots within its teacher's availability
+    slot_ids: Vec<u64>,
+    mode: TimetableMode,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum TimetableStatus {
+    // call continue_timetable for more
+    Searching,
+    // the search is done, nothing was written
+    Proposed,
+    // the search is done and the lessons were updated
+    Applied,
+}
+
+// The periods one lesson got
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LessonTimetable {
+    lesson_id: u64,
+    slots: Vec<ScheduleEntry>,
+    room_id: Option<u64>,
+    // periods that could not be placed
+    unplaced: u32,
+    // slots that were open to the lesson before other lessons were placed
+    candidate_slots: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TimetableProgress {
+    status: TimetableStatus,
+    steps: u64,
+    periods: u32,
+    unplaced: u32,
+    // the best timetable found so far; empty until the first is complete
+    lessons: Vec<LessonTimetable>,
+}
+
+impl TimetableProgress {
+    // "proposed, 40 of 42 periods placed after 1200 steps"
+    pub(crate) fn describe(&self) -> String {
+        let status = match self.status {
+            TimetableStatus::Searching => "searching",
+            TimetableStatus::Proposed => "proposed",
+            TimetableStatus::Applied => "applied",
+        };
+        format!(
+            "{}, {} of {} periods placed after {} steps",
+            status,
+            self.periods - self.unplaced,
+            self.periods,
+            self.steps
+        )
+    }
+}
+
+struct JobLesson {
+    lesson_id: u64,
+    periods: u32,
+    room_id: Option<u64>,
+    // positions in Job.slots the lesson may use, in slot order
+    candidates: Vec<usize>,
+}
+
+// A running or finished timetable search. The search is a depth-first
+// branch and bound over the periods to place: each period takes one of
+// its lesson's candidate slots or is left unplaced, and branches that
+// leave at least as many periods unplaced as the best timetable so far are
+// cut. Periods of one lesson take candidates in increasing order, so the
+// same timetable is not visited once per ordering of its periods.
+struct Job {
+    mode: TimetableMode,
+    status: TimetableStatus,
+    slots: Vec<ScheduleEntry>,
+    lessons: Vec<JobLesson>,
+    // overlaps[a][b]: slots a and b share some time
+    overlaps: Vec<Vec<bool>>,
+    // linked[i][j]: lessons i and j may not run at the same time, because
+    // they share a teacher, a student or a room in overlapping terms
+    linked: Vec<Vec<bool>>,
+    // lesson of each period, in the order the search places them
+    periods: Vec<usize>,
+    // candidate position chosen for each placed period, None when unplaced
+    chosen: Vec<Option<usize>>,
+    // candidate position to try next for the period at chosen.len()
+    next: usize,
+    unplaced: u32,
+    best: Option<(u32, Vec<Option<usize>>)>,
+    steps: u64,
+}
+
+thread_local! {
+    // the last timetable job; lost on upgrade like any heap state
+    static TIMETABLE_JOB: RefCell<Option<Job>> = const { RefCell::new(None) };
+}
+
+fn get_lesson_record(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+fn no_job() -> Error {
+    Error::Conflict {
+        reason: "no timetable job, call start_timetable first".to_string(),
+    }
+}
+
+// A slot that is already taken and stays taken: a period of a lesson the
+// job does not place
+struct Booking {
+    slot: ScheduleEntry,
+    term_id: Option<u64>,
+}
+
+// Bookings of the teachers, students and rooms the job's lessons use
+#[derive(Default)]
+struct FixedBookings {
+    teachers: BTreeMap<u64, Vec<Booking>>,
+    students: BTreeMap<u64, Vec<Booking>>,
+    rooms: BTreeMap<u64, Vec<Booking>>,
+}
+
+fn fixed_bookings(lessons: &[Lesson], rooms: &BTreeSet<u64>) -> FixedBookings {
+    let placed: BTreeSet<u64> = lessons.iter().map(|lesson| lesson.id).collect();
+    let teachers: BTreeSet<u64> = lessons.iter().map(|lesson| lesson.teacher_id).collect();
+    let students: BTreeSet<u64> = lessons.iter().flat_map(|lesson| lesson.students.iter().copied()).collect();
+    let mut fixed = FixedBookings::default();
+    LESSON_MAP.with(|service| {
+        for (_, other) in service.borrow().iter() {
+            if placed.contains(&other.id) {
+                continue;
+            }
+            for slot in &other.schedule {
+                let booking = || Booking { slot: slot.clone(), term_id: other.term_id };
+                if teachers.contains(&other.teacher_id) {
+                    fixed.teachers.entry(other.teacher_id).or_default().push(booking());
+                }
+                for student_id in other.students.iter().filter(|id| students.contains(id)) {
+                    fixed.students.entry(*student_id).or_default().push(booking());
+                }
+            }
+        }
+    });
+    for (room_id, other, slot) in rooms::room_bookings(None) {
+        if rooms.contains(&room_id) && !placed.contains(&other.id) {
+            fixed.rooms.entry(room_id).or_default().push(Booking { slot, term_id: other.term_id });
+        }
+    }
+    fixed
+}
+
+// Slots in `slots` the lesson may take when no other lesson of the job is
+// placed yet: within its teacher's availability and clear of the fixed
+// bookings of its teacher, students and room
+fn candidate_slots(
+    lesson: &Lesson,
+    room_id: Option<u64>,
+    slots: &[ScheduleEntry],
+    fixed: &FixedBookings,
+) -> Vec<usize> {
+    let availability = TEACHER_MAP
+        .with(|service| service.borrow().get(&lesson.teacher_id))
+        .map(|teacher| teacher.availability)
+        .unwrap_or_default();
+    let mut bookings: Vec<&Booking> = Vec::new();
+    bookings.extend(fixed.teachers.get(&lesson.teacher_id).into_iter().flatten());
+    for student_id in &lesson.students {
+        bookings.extend(fixed.students.get(student_id).into_iter().flatten());
+    }
+    if let Some(room_id) = room_id {
+        bookings.extend(fixed.rooms.get(&room_id).into_iter().flatten());
+    }
+    bookings.retain(|booking| terms_overlap(lesson.term_id, booking.term_id));
+    (0..slots.len())
+        .filter(|&index| {
+            let slot = &slots[index];
+            availability.iter().any(|available| available.contains(slot))
+                && !bookings.iter().any(|booking| booking.slot.overlaps(slot))
+        })
+        .collect()
+}
+
+// Two lessons of a job may not run at the same time when they share a
+// teacher, a student or a room in overlapping terms
+fn share_resources(lesson: &Lesson, room_id: Option<u64>, other: &Lesson, other_room_id: Option<u64>) -> bool {
+    let shared = lesson.teacher_id == other.teacher_id
+        || lesson.students.iter().any(|id| other.students.contains(id))
+        || (room_id.is_some() && room_id == other_room_id);
+    shared && terms_overlap(lesson.term_id, other.term_id)
+}
+
+// the requested room must seat every enrolled student
+fn require_room_capacity(lesson: &Lesson, room_id: u64) -> Result<(), Error> {
+    let room = get_room_record(room_id)?;
+    if lesson.students.len() as u64 > room.capacity as u64 {
+        return Err(Error::CapacityExceeded {
+            entity: Entity::Room,
+            id: room_id,
+            capacity: room.capacity as u64,
+        });
+    }
+    Ok(())
+}
+
+fn validate_request(request: &TimetableRequest) -> Result<(), Error> {
+    if request.lessons.is_empty() || request.lessons.len() > MAX_LESSONS {
+        return Err(Error::InvalidInput {
+            field: "lessons".to_string(),
+            reason: format!("must list 1 to {} lessons", MAX_LESSONS),
+        });
+    }
+    if request.slot_ids.is_empty() || request.slot_ids.len() > MAX_SLOTS {
+        return Err(Error::InvalidInput {
+            field: "slot_ids".to_string(),
+            reason: format!("must list 1 to {} slots", MAX_SLOTS),
+        });
+    }
+    let mut seen = BTreeSet::new();
+    for demand in &request.lessons {
+        if !seen.insert(demand.lesson_id) {
+            return Err(Error::InvalidInput {
+                field: "lessons".to_string(),
+                reason: format!("lesson {} is given more than once", demand.lesson_id),
+            });
+        }
+        if demand.periods == 0 || demand.periods > MAX_PERIODS {
+            return Err(Error::InvalidInput {
+                field: "periods".to_string(),
+                reason: format!("must be 1 to {}", MAX_PERIODS),
+            });
+        }
+    }
+    Ok(())
+}
+
+impl Job {
+    fn new(request: TimetableRequest) -> Result<Job, Error> {
+        validate_request(&request)?;
+        let mut slots: Vec<ScheduleEntry> = Vec::new();
+        for schedule_id in &request.slot_ids {
+            let slot = get_schedule_entry(*schedule_id)?;
+            if !slots.iter().any(|other| other.id == slot.id) {
+                slots.push(slot);
+            }
+        }
+        slots.sort_by_key(|slot| (slot.day, slot.start_minute, slot.end_minute, slot.id));
+
+        let mut lessons = Vec::new();
+        let mut rooms = BTreeSet::new();
+        for demand in &request.lessons {
+            let lesson = get_lesson_record(demand.lesson_id)?;
+            if let Some(room_id) = demand.room_id {
+                require_room_capacity(&lesson, room_id)?;
+                rooms.insert(room_id);
+            }
+            lessons.push(lesson);
+        }
+
+        let fixed = fixed_bookings(&lessons, &rooms);
+        let job_lessons: Vec<JobLesson> = request
+            .lessons
+            .iter()
+            .zip(&lessons)
+            .map(|(demand, lesson)| JobLesson {
+                lesson_id: lesson.id,
+                periods: demand.periods,
+                room_id: demand.room_id,
+                candidates: candidate_slots(lesson, demand.room_id, &slots, &fixed),
+            })
+            .collect();
+        Ok(Job::plan(request.mode, slots, &lessons, job_lessons))
+    }
+
+    // Set up the search over `slots` for the lessons, given with their
+    // candidates in the same order
+    fn plan(mode: TimetableMode, slots: Vec<ScheduleEntry>, lessons: &[Lesson], job_lessons: Vec<JobLesson>) -> Job {
+        let overlaps = slots
+            .iter()
+            .map(|slot| slots.iter().map(|other| slot.overlaps(other)).collect())
+            .collect();
+        let linked = lessons
+            .iter()
+            .zip(&job_lessons)
+            .map(|(lesson, job_lesson)| {
+                lessons
+                    .iter()
+                    .zip(&job_lessons)
+                    .map(|(other, other_job)| share_resources(lesson, job_lesson.room_id, other, other_job.room_id))
+                    .collect()
+            })
+            .collect();
+
+        // lessons with the least room to spare are placed first
+        let mut order: Vec<usize> = (0..job_lessons.len()).collect();
+        order.sort_by_key(|&index| {
+            let lesson = &job_lessons[index];
+            (lesson.candidates.len() as i64 - lesson.periods as i64, lesson.lesson_id)
+        });
+        let periods = order
+            .into_iter()
+            .flat_map(|index| std::iter::repeat_n(index, job_lessons[index].periods as usize))
+            .collect();
+
+        Job {
+            mode,
+            status: TimetableStatus::Searching,
+            slots,
+            lessons: job_lessons,
+            overlaps,
+            linked,
+            periods,
+            chosen: Vec::new(),
+            next: 0,
+            unplaced: 0,
+            best: None,
+            steps: 0,
+        }
+    }
+
+    // true when `slot` for `lesson` clashes with no period placed so far
+    fn fits(&self, lesson: usize, slot: usize) -> bool {
+        self.chosen.iter().enumerate().all(|(depth, choice)| match choice {
+            Some(position) => {
+                let other = self.periods[depth];
+                let other_slot = self.lessons[other].candidates[*position];
+                !(self.linked[lesson][other] && self.overlaps[slot][other_slot])
+            }
+            None => true,
+        })
+    }
+
+    // Undo the last choice and move on to its next alternative; a job with
+    // no choice left to undo has searched everything
+    fn backtrack(&mut self) {
+        let depth = self.chosen.len();
+        match self.chosen.pop() {
+            None => self.status = TimetableStatus::Proposed,
+            Some(Some(position)) => self.next = position + 1,
+            Some(None) => {
+                self.unplaced -= 1;
+                // past the last candidate and the unplaced option
+                self.next = self.lessons[self.periods[depth - 1]].candidates.len() + 1;
+            }
+        }
+    }
+
+    // one move of the search: record a complete timetable, try one
+    // candidate, leave a period unplaced or backtrack
+    fn step(&mut self) {
+        self.steps += 1;
+        let depth = self.chosen.len();
+        if depth == self.periods.len() {
+            self.best = Some((self.unplaced, self.chosen.clone()));
+            self.backtrack();
+            return;
+        }
+        if self.best.as_ref().is_some_and(|(unplaced, _)| self.unplaced >= *unplaced) {
+            self.backtrack();
+            return;
+        }
+        let lesson = self.periods[depth];
+        let count = self.lessons[lesson].candidates.len();
+        // an earlier period of the same lesson bounds the candidates
+        let first = match depth.checked_sub(1) {
+            Some(previous) if self.periods[previous] == lesson => match self.chosen[previous] {
+                Some(position) => position + 1,
+                None => count,
+            },
+            _ => 0,
+        };
+        let position = self.next.max(first);
+        if position < count {
+            if self.fits(lesson, self.lessons[lesson].candidates[position]) {
+                self.chosen.push(Some(position));
+                self.next = 0;
+            } else {
+                self.next = position + 1;
+            }
+        } else if position == count {
+            self.chosen.push(None);
+            self.unplaced += 1;
+            self.next = 0;
+        } else {
+            self.backtrack();
+        }
+    }
+
+    // search until done or out of instructions for this call
+    fn run(&mut self) -> Result<(), Error> {
+        while self.status == TimetableStatus::Searching {
+            if self.best.as_ref().is_some_and(|(unplaced, _)| *unplaced == 0) || self.steps >= MAX_SEARCH_STEPS {
+                self.status = TimetableStatus::Proposed;
+                break;
+            }
+            if ic_cdk::api::instruction_counter() >= INSTRUCTION_BUDGET {
+                return Ok(());
+            }
+            self.step();
+        }
+        if self.mode == TimetableMode::Apply && self.status == TimetableStatus::Proposed {
+            self.apply()?;
+        }
+        Ok(())
+    }
+
+    // the slots each lesson got in the best timetable, in job order
+    fn timetable(&self) -> Vec<LessonTimetable> {
+        let choices = match &self.best {
+            Some((_, choices)) => choices,
+            None => return Vec::new(),
+        };
+        let mut result: Vec<LessonTimetable> = self
+            .lessons
+            .iter()
+            .map(|lesson| LessonTimetable {
+                lesson_id: lesson.lesson_id,
+                slots: Vec::new(),
+                room_id: lesson.room_id,
+                unplaced: 0,
+                candidate_slots: lesson.candidates.len() as u32,
+            })
+            .collect();
+        for (depth, choice) in choices.iter().enumerate() {
+            let lesson = self.periods[depth];
+            match choice {
+                Some(position) => {
+                    let slot = &self.slots[self.lessons[lesson].candidates[*position]];
+                    result[lesson].slots.push(slot.clone());
+                }
+                None => result[lesson].unplaced += 1,
+            }
+        }
+        result
+    }
+
+    // The search saw the lessons, slots and bookings as they were when the
+    // job started. Check the timetable against the current records and
+    // return the lessons to write, or a conflict when anything it relies on
+    // changed in between.
+    fn verify(&self, timetable: &[LessonTimetable]) -> Result<Vec<Lesson>, Error> {
+        let changed = |reason: String| Error::Conflict {
+            reason: format!("{} since the timetable was searched; start a new job", reason),
+        };
+        let mut lessons = Vec::new();
+        let mut rooms = BTreeSet::new();
+        for entry in timetable {
+            let lesson = get_lesson_record(entry.lesson_id)?;
+            if let Some(room_id) = entry.room_id {
+                require_room_capacity(&lesson, room_id)?;
+                rooms.insert(room_id);
+            }
+            for slot in &entry.slots {
+                let current = get_schedule_entry(slot.id)?;
+                if (current.day, current.start_minute, current.end_minute) != (slot.day, slot.start_minute, slot.end_minute) {
+                    return Err(changed(format!("schedule entry {} changed", slot.id)));
+                }
+            }
+            lessons.push(lesson);
+        }
+
+        // lessons outside the job may have taken slots, and teachers may
+        // have changed their availability
+        let fixed = fixed_bookings(&lessons, &rooms);
+        for (lesson, entry) in lessons.iter().zip(timetable) {
+            let open = candidate_slots(lesson, entry.room_id, &self.slots, &fixed);
+            for slot in &entry.slots {
+                if !open.iter().any(|&index| self.slots[index].id == slot.id) {
+                    return Err(changed(format!("slot {} is no longer free for lesson {}", slot.id, lesson.id)));
+                }
+            }
+        }
+        // the job's lessons may have changed teachers, students or terms
+        for (index, (lesson, entry)) in lessons.iter().zip(timetable).enumerate() {
+            for (other, other_entry) in lessons.iter().zip(timetable).skip(index + 1) {
+                let clash = entry
+                    .slots
+                    .iter()
+                    .any(|slot| other_entry.slots.iter().any(|other_slot| slot.overlaps(other_slot)));
+                if clash && share_resources(lesson, entry.room_id, other, other_entry.room_id) {
+                    return Err(changed(format!("lessons {} and {} clash", lesson.id, other.id)));
+                }
+            }
+        }
+        Ok(lessons)
+    }
+
+    // Replace the schedule of every lesson of the job with its slots. The
+    // lessons' room bookings are replaced by the requested room. Nothing is
+    // written when verify finds a change; the job then stays Proposed.
+    fn apply(&mut self) -> Result<(), Error> {
+        let timetable = self.timetable();
+        let lessons = self.verify(&timetable)?;
+        for (mut lesson, entry) in lessons.into_iter().zip(timetable) {
+            let schedule_ids: Vec<u64> = entry.slots.iter().map(|slot| slot.id).collect();
+            lesson.schedule = entry.slots;
+            do_insert_lesson(&lesson)?;
+            revisions::record_revision(&lesson);
+            rooms::remove_lesson_rooms(lesson.id);
+            if let Some(room_id) = entry.room_id {
+                rooms::book_lesson_rooms(lesson.id, room_id, &schedule_ids);
+            }
+        }
+        self.status = TimetableStatus::Applied;
+        Ok(())
+    }
+
+    fn progress(&self) -> TimetableProgress {
+        let periods = self.periods.len() as u32;
+        TimetableProgress {
+            status: self.status,
+            steps: self.steps,
+            periods,
+            unplaced: self.best.as_ref().map_or(periods, |(unplaced, _)| *unplaced),
+            lessons: self.timetable(),
+        }
+    }
+}
+
+// the lessons an Apply job writes, for the audit log
+fn apply_targets(lesson_ids: impl Iterator<Item = u64>) -> Vec<AuditTarget> {
+    lesson_ids.map(|lesson_id| record(Entity::Lesson, lesson_id)).collect()
+}
+
+// Start a search for weekly slots for the given lessons. Each period gets
+// a slot from `slot_ids` within its teacher's availability that clashes
+// with no other period of the same teacher, student or room, including
+// lessons outside the job. The call searches as long as its instruction
+// budget allows; continue_timetable resumes it.
+#[ic_cdk::update]
+fn start_timetable(request: TimetableRequest) -> Result<TimetableProgress, Error> {
+    let targets = if request.mode == TimetableMode::Apply {
+        apply_targets(request.lessons.iter().map(|demand| demand.lesson_id))
+    } else {
+        Vec::new()
+    };
+    audited("start_timetable", &targets, || {
+        require_admin("generate timetables")?;
+        let running = TIMETABLE_JOB.with(|job| {
+            job.borrow().as_ref().is_some_and(|job| job.status == TimetableStatus::Searching)
+        });
+        if running {
+            return Err(Error::Conflict {
+                reason: "a timetable job is still searching, continue or cancel it first".to_string(),
+            });
+        }
+        let mut job = Job::new(request)?;
+        let result = job.run();
+        let progress = job.progress();
+        TIMETABLE_JOB.with(|slot| *slot.borrow_mut() = Some(job));
+        result?;
+        Ok(progress)
+    })
+}
+
+// search on where the last call stopped; a finished job reports its result
+#[ic_cdk::update]
+fn continue_timetable() -> Result<TimetableProgress, Error> {
+    let targets = TIMETABLE_JOB.with(|job| match job.borrow().as_ref() {
+        Some(job) if job.mode == TimetableMode::Apply && job.status == TimetableStatus::Searching => {
+            apply_targets(job.lessons.iter().map(|lesson| lesson.lesson_id))
+        }
+        _ => Vec::new(),
+    });
+    audited("continue_timetable", &targets, || {
+        require_admin("generate timetables")?;
+        TIMETABLE_JOB.with(|job| {
+            let mut job = job.borrow_mut();
+            let job = job.as_mut().ok_or_else(no_job)?;
+            if job.status == TimetableStatus::Searching {
+                job.run()?;
+            }
+            Ok(job.progress())
+        })
+    })
+}
+
+// progress of the last timetable job
+#[ic_cdk::query]
+fn get_timetable() -> Result<TimetableProgress, Error> {
+    require_admin("generate timetables")?;
+    TIMETABLE_JOB.with(|job| job.borrow().as_ref().map(Job::progress).ok_or_else(no_job))
+}
+
+// drop the last timetable job without applying it
+#[ic_cdk::update]
+fn cancel_timetable() -> Result<(), Error> {
+    audited("cancel_timetable", &[], || {
+        require_admin("generate timetables")?;
+        TIMETABLE_JOB.with(|job| job.borrow_mut().take()).ok_or_else(no_job)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::Weekday;
+
+    fn slot(id: u64, day: Weekday, start_minute: u16, end_minute: u16) -> ScheduleEntry {
+        ScheduleEntry {
+            id,
+            day,
+            start_minute,
+            end_minute,
+        }
+    }
+
+    fn lesson(id: u64, teacher_id: u64, students: Vec<u64>) -> Lesson {
+        Lesson {
+            id,
+            title: format!("Lesson {}", id),
+            description: "Weekly lesson".to_string(),
+            grade_level: "3".to_string(),
+            subject: "Math".to_string(),
+            teacher_id,
+            students,
+            schedule: Vec::new(),
+            plan: Default::default(),
+            cloned_from: None,
+            term_id: None,
+            capacity: None,
+            waitlist: Vec::new(),
+        }
+    }
+
+    // a job over `slots` where each lesson asks for `periods` and may use
+    // the slots at the given positions
+    fn job(slots: Vec<ScheduleEntry>, lessons: &[(Lesson, u32, Vec<usize>)]) -> Job {
+        let records: Vec<Lesson> = lessons.iter().map(|(lesson, _, _)| lesson.clone()).collect();
+        let job_lessons = lessons
+            .iter()
+            .map(|(lesson, periods, candidates)| JobLesson {
+                lesson_id: lesson.id,
+                periods: *periods,
+                room_id: None,
+                candidates: candidates.clone(),
+            })
+            .collect();
+        Job::plan(TimetableMode::Propose, slots, &records, job_lessons)
+    }
+
+    // search the whole tree, as run does without an instruction budget
+    fn search(mut job: Job) -> Job {
+        while job.status == TimetableStatus::Searching {
+            job.step();
+        }
+        job
+    }
+
+    fn slot_ids(timetable: &LessonTimetable) -> Vec<u64> {
+        timetable.slots.iter().map(|slot| slot.id).collect()
+    }
+
+    #[test]
+    fn every_period_fits() {
+        let slots = vec![
+            slot(1, Weekday::Monday, 9 * 60, 10 * 60),
+            slot(2, Weekday::Monday, 10 * 60, 11 * 60),
+            slot(3, Weekday::Tuesday, 9 * 60, 10 * 60),
+        ];
+        let job = search(job(
+            slots,
+            &[
+                (lesson(10, 1, vec![7]), 3, vec![0, 1, 2]),
+                (lesson(11, 2, vec![8]), 1, vec![0, 1, 2]),
+            ],
+        ));
+        let progress = job.progress();
+        assert_eq!((progress.periods, progress.unplaced), (4, 0));
+        assert!(progress.status == TimetableStatus::Proposed);
+        // the periods of one lesson take their slots in order
+        assert_eq!(slot_ids(&progress.lessons[0]), vec![1, 2, 3]);
+        assert_eq!(progress.lessons[0].unplaced, 0);
+        assert_eq!(progress.lessons[1].slots.len(), 1);
+    }
+
+    #[test]
+    fn the_best_timetable_leaves_one_period_unplaced() {
+        // one teacher, three periods and two slots that do not overlap
+        let slots = vec![
+            slot(1, Weekday::Monday, 9 * 60, 10 * 60),
+            slot(2, Weekday::Monday, 10 * 60, 11 * 60),
+        ];
+        let job = search(job(
+            slots,
+            &[
+                (lesson(10, 1, Vec::new()), 2, vec![0, 1]),
+                (lesson(11, 1, Vec::new()), 1, vec![0, 1]),
+            ],
+        ));
+        let progress = job.progress();
+        assert!(progress.status == TimetableStatus::Proposed);
+        assert_eq!((progress.periods, progress.unplaced), (3, 1));
+        let unplaced: u32 = progress.lessons.iter().map(|lesson| lesson.unplaced).sum();
+        assert_eq!(unplaced, 1);
+        let mut used: Vec<u64> = progress.lessons.iter().flat_map(slot_ids).collect();
+        used.sort();
+        assert_eq!(used, vec![1, 2]);
+        // the unplaced option is tried for every period, and undone again
+        assert!(job.chosen.is_empty());
+        assert_eq!(job.unplaced, 0);
+    }
+
+    #[test]
+    fn lessons_sharing_a_student_get_slots_that_do_not_overlap() {
+        let slots = vec![
+            slot(1, Weekday::Monday, 9 * 60, 10 * 60),
+            slot(2, Weekday::Monday, 9 * 60 + 30, 10 * 60 + 30),
+            slot(3, Weekday::Monday, 11 * 60, 12 * 60),
+        ];
+        let linked = search(job(
+            slots.clone(),
+            &[
+                (lesson(10, 1, vec![7]), 1, vec![0]),
+                (lesson(11, 2, vec![7, 8]), 1, vec![0, 1, 2]),
+            ],
+        ));
+        let progress = linked.progress();
+        assert_eq!(progress.unplaced, 0);
+        assert_eq!(slot_ids(&progress.lessons[0]), vec![1]);
+        assert_eq!(slot_ids(&progress.lessons[1]), vec![3]);
+
+        // without the shared student the first candidate is taken
+        let unlinked = search(job(
+            slots,
+            &[
+                (lesson(10, 1, vec![7]), 1, vec![0]),
+                (lesson(11, 2, vec![8]), 1, vec![0, 1, 2]),
+            ],
+        ));
+        assert_eq!(slot_ids(&unlinked.progress().lessons[1]), vec![1]);
+    }
+}
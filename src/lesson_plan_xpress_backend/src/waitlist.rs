@@ -0,0 +1,130 @@
+use crate::audit::{audited, record};
+use crate::auth::require_teacher;
+use crate::{do_insert_lesson, do_insert_student, revisions, rooms, Entity, Error, Lesson};
+use crate::{LESSON_MAP, STUDENT_MAP};
+
+// Where a student ended up after asking for a place in a lesson
+#[derive(PartialEq)]
+pub(crate) enum Placement {
+    Enrolled,
+    Waitlisted,
+}
+
+fn get_lesson_record(lesson_id: u64) -> Result<Lesson, Error> {
+    let lesson = LESSON_MAP.with(|service| service.borrow().get(&lesson_id));
+    if let Some(lesson) = lesson {
+        Ok(lesson)
+    } else {
+        Err(Error::NotFound { entity: Entity::Lesson, id: lesson_id })
+    }
+}
+
+// a free place below the lesson's capacity and within the seats of the
+// smallest room it is booked in
+fn has_room(lesson: &Lesson) -> bool {
+    [lesson.capacity, rooms::lesson_seats(lesson.id)]
+        .into_iter()
+        .flatten()
+        .min()
+        .is_none_or(|capacity| lesson.students.len() < capacity as usize)
+}
+
+// Give the student a place in the lesson, or a place at the end of the
+// waitlist when the lesson is full. The caller stores the lesson.
+pub(crate) fn place_student(lesson: &mut Lesson, student_id: u64) -> Result<Placement, Error> {
+    if lesson.students.contains(&student_id) {
+        return Err(Error::Conflict {
+            reason: format!(
+                "Student with id={} is already enrolled in lesson with id={}",
+                student_id, lesson.id
+            ),
+        });
+    }
+    if lesson.waitlist.contains(&student_id) {
+        return Err(Error::Conflict {
+            reason: format!(
+                "Student with id={} is already on the waitlist of lesson with id={}",
+                student_id, lesson.id
+            ),
+        });
+    }
+    if has_room(lesson) {
+        lesson.students.push(student_id);
+        Ok(Placement::Enrolled)
+    } else {
+        lesson.waitlist.push(student_id);
+        Ok(Placement::Waitlisted)
+    }
+}
+
+// Fill free places from the front of the waitlist. Promoted students are
+// linked on both sides, as enroll does. The caller stores the lesson.
+pub(crate) fn promote_waitlisted(lesson: &mut Lesson) {
+    while has_room(lesson) && !lesson.waitlist.is_empty() {
+        let student_id = lesson.waitlist.remove(0);
+        lesson.students.push(student_id);
+        let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
+        if let Some(mut student) = student {
+            if !student.lessons.contains(&lesson.id) {
+                student.lessons.push(lesson.id);
+                do_insert_student(&student);
+            }
+        }
+    }
+}
+
+// Take the student out of the lesson and off its waitlist, unlink the
+// lesson from the student record, then hand the freed place to the next
+// student in line. The caller stores the lesson.
+pub(crate) fn release_student(lesson: &mut Lesson, student_id: u64) {
+    lesson.students.retain(|id| id != &student_id);
+    lesson.waitlist.retain(|id| id != &student_id);
+    let student = STUDENT_MAP.with(|service| service.borrow().get(&student_id));
+    if let Some(mut student) = student {
+        if student.lessons.contains(&lesson.id) {
+            student.lessons.retain(|id| id != &lesson.id);
+            do_insert_student(&student);
+        }
+    }
+    promote_waitlisted(lesson);
+}
+
+// Limit how many students the lesson takes, or lift the limit with None.
+// Students already enrolled keep their place when the limit is lowered;
+// raising it promotes students from the waitlist.
+#[ic_cdk::update]
+fn set_lesson_capacity(lesson_id: u64, capacity: Option<u32>) -> Result<Lesson, Error> {
+    audited("set_lesson_capacity", &[record(Entity::Lesson, lesson_id)], || {
+        let mut lesson = get_lesson_record(lesson_id)?;
+        require_teacher(lesson.teacher_id, "change the capacity of this lesson")?;
+        if capacity == Some(0) {
+            return Err(Error::InvalidInput {
+                field: "capacity".to_string(),
+                reason: "must be at least 1".to_string(),
+            });
+        }
+        lesson.capacity = capacity;
+        promote_waitlisted(&mut lesson);
+        do_insert_lesson(&lesson)?;
+        revisions::record_revision(&lesson);
+        Ok(lesson)
+    })
+}
+
+// students waiting for a place in the lesson, first in line first
+#[ic_cdk::query]
+fn get_waitlist(lesson_id: u64) -> Result<Vec<u64>, Error> {
+    Ok(get_lesson_record(lesson_id)?.waitlist)
+}
+
+// 1-based position of the student on the lesson's waitlist, None when the
+// student is not waiting
+#[ic_cdk::query]
+fn get_waitlist_position(lesson_id: u64, student_id: u64) -> Result<Option<u64>, Error> {
+    let lesson = get_lesson_record(lesson_id)?;
+    Ok(lesson
+        .waitlist
+        .iter()
+        .position(|id| id == &student_id)
+        .map(|index| index as u64 + 1))
+}